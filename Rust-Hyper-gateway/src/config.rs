@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Read, sync::Arc};
+use std::{collections::HashMap, fs::File, io::Read, sync::Arc};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct GatewayConfig {
@@ -8,9 +8,29 @@ pub struct GatewayConfig {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Route {
+    #[serde(default)]
+    pub id: String,
     pub scheme: String,
     pub authority: Authority,
     pub path: String,
+    // Optional conditions, all of which must hold for the route to match
+    pub host: Option<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+    // Path changes applied before forwarding, in this order
+    #[serde(default)]
+    pub strip_prefix: bool,
+    pub rewrite: Option<Rewrite>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Rewrite {
+    pub pattern: String,
+    pub replacement: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -27,6 +47,140 @@ pub fn load_config(path: &str) -> GatewayConfig {
     serde_yaml::from_str(&contents).expect("Failed to parse the configuration file!")
 }
 
-pub fn get_route<'a>(path: &str, route: &'a [Route]) -> Option<&'a Route> {
-    route.iter().find(|c: &&Route| path.starts_with(&c.path))
+pub struct RouteTable {
+    entries: Vec<RouteEntry>,
+}
+
+pub struct RouteEntry {
+    pub route: Route,
+    rewrite: Option<regex::Regex>,
+}
+
+impl RouteTable {
+    pub fn new(routes: &[Route]) -> RouteTable {
+        let mut entries: Vec<RouteEntry> = routes
+            .iter()
+            .map(|route: &Route| RouteEntry {
+                route: route.clone(),
+                rewrite: route.rewrite.as_ref().map(|rewrite: &Rewrite| {
+                    regex::Regex::new(&rewrite.pattern)
+                        .expect("Failed to compile a rewrite pattern!")
+                }),
+            })
+            .collect();
+        // Longest prefix first, then the route with more conditions; the sort is stable so
+        // the file order only breaks the remaining ties
+        entries.sort_by_key(|entry: &RouteEntry| {
+            std::cmp::Reverse((entry.route.path.len(), entry.conditions()))
+        });
+        RouteTable { entries }
+    }
+
+    pub fn find<B>(&self, request: &http::Request<B>) -> Option<&RouteEntry> {
+        self.entries
+            .iter()
+            .find(|entry: &&RouteEntry| entry.matches(request))
+    }
+}
+
+impl RouteEntry {
+    fn conditions(&self) -> usize {
+        let route: &Route = &self.route;
+        route.host.is_some() as usize
+            + !route.methods.is_empty() as usize
+            + route.headers.len()
+            + route.query.len()
+    }
+
+    fn matches<B>(&self, request: &http::Request<B>) -> bool {
+        let route: &Route = &self.route;
+        if !has_prefix(request.uri().path(), &route.path) {
+            return false;
+        }
+
+        if let Some(host) = &route.host {
+            // The Host header may carry a port, which is not part of the match
+            let request_host: Option<&str> = request
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|value: &http::HeaderValue| value.to_str().ok())
+                .or_else(|| request.uri().host());
+            match request_host {
+                Some(request_host) => {
+                    let request_host: &str = request_host
+                        .rsplit_once(':')
+                        .filter(|(_, port): &(&str, &str)| port.parse::<u16>().is_ok())
+                        .map_or(request_host, |(name, _): (&str, &str)| name);
+                    if !request_host.eq_ignore_ascii_case(host) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+
+        if !route.methods.is_empty()
+            && !route
+                .methods
+                .iter()
+                .any(|method: &String| method.eq_ignore_ascii_case(request.method().as_str()))
+        {
+            return false;
+        }
+
+        let headers_match: bool = route.headers.iter().all(|(name, value)| {
+            request
+                .headers()
+                .get_all(name.as_str())
+                .iter()
+                .any(|header: &http::HeaderValue| header.as_bytes() == value.as_bytes())
+        });
+        if !headers_match {
+            return false;
+        }
+
+        let query: Vec<(&str, &str)> = request
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair: &&str| !pair.is_empty())
+            .map(|pair: &str| pair.split_once('=').unwrap_or((pair, "")))
+            .collect();
+        route.query.iter().all(|(name, value)| {
+            query
+                .iter()
+                .any(|(key, val): &(&str, &str)| key == name && val == value)
+        })
+    }
+
+    // Path sent to the upstream: without the route's prefix if strip_prefix is set, then
+    // with the first match of the rewrite pattern replaced
+    pub fn forward_path(&self, path: &str) -> String {
+        let mut forward_path: String = path.to_string();
+        if self.route.strip_prefix {
+            let rest: &str = path
+                .strip_prefix(self.route.path.trim_end_matches('/'))
+                .unwrap_or(path);
+            forward_path = if rest.starts_with('/') {
+                rest.to_string()
+            } else {
+                format!("/{}", rest)
+            };
+        }
+        if let (Some(regex), Some(rewrite)) = (&self.rewrite, &self.route.rewrite) {
+            forward_path = regex
+                .replace(&forward_path, rewrite.replacement.as_str())
+                .into_owned();
+        }
+        forward_path
+    }
+}
+
+// "/product" matches "/product" and "/product/1" but not "/products"
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
 }
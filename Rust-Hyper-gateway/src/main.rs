@@ -17,7 +17,8 @@ async fn main() {
 
     log::info!("Load the configuation");
     let gateway_config: config::GatewayConfig = config::load_config("config.yaml");
-    let route_config_arr: Arc<[config::Route]> = gateway_config.route;
+    let route_table: Arc<config::RouteTable> =
+        Arc::new(config::RouteTable::new(&gateway_config.route));
 
     log::info!("Initialize the throttle");
     let throttle_pool: Arc<Mutex<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>> =
@@ -36,11 +37,7 @@ async fn main() {
                 .accept()
                 .await
                 .expect("Failed to accepts a connection from this listener!");
-            route::run(
-                gateway_stream,
-                route_config_arr.clone(),
-                throttle_pool.clone(),
-            )
+            route::run(gateway_stream, route_table.clone(), throttle_pool.clone())
         });
     }
 }
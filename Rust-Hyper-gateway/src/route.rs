@@ -7,7 +7,7 @@ use crate::{config, entity, throttle, trace};
 
 pub async fn run(
     gateway_stream: tokio::net::TcpStream,
-    route_config_arr_clone: Arc<[config::Route]>,
+    route_table_clone: Arc<config::RouteTable>,
     throttle_pool_clone: Arc<Mutex<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>>,
 ) -> Result<(), entity::GatewayError> {
     let service_fn = hyper::service::service_fn(
@@ -21,7 +21,7 @@ pub async fn run(
             .unwrap();
             log::info!("throttle_status = {:?}", throttle_status);
 
-            let (outgoing_request, addr) = route_request(incoming_request, &route_config_arr_clone);
+            let (outgoing_request, addr) = route_request(incoming_request, &route_table_clone);
 
             async move {
                 if !throttle_status.is_success() {
@@ -70,36 +70,47 @@ pub async fn run(
 
 pub fn route_request(
     incoming_request: http::Request<hyper::body::Incoming>,
-    route_config_arr: &[config::Route],
+    route_table: &config::RouteTable,
 ) -> (http::Request<hyper::body::Incoming>, SocketAddr) {
     log::info!("incoming_request = {:?}", &incoming_request);
 
-    let route_config: config::Route =
-        config::get_route(incoming_request.uri().path(), route_config_arr)
-            .expect("Failed to get the routing configuration for the request!")
-            .clone();
+    let route_entry: &config::RouteEntry = route_table
+        .find(&incoming_request)
+        .expect("Failed to get the routing configuration for the request!");
+    let route_config: &config::Route = &route_entry.route;
 
     let route_addr: SocketAddr = SocketAddr::from((
         route_config.authority.host.parse::<IpAddr>().unwrap(),
         route_config.authority.port.parse::<u16>().unwrap(),
     ));
-    log::info!("Routed to {}://{:?}", route_config.scheme, &route_addr);
+    log::info!(
+        "Routed by {:?} to {}://{:?}",
+        route_config.id,
+        route_config.scheme,
+        &route_addr
+    );
 
     (
-        build_request(incoming_request, route_config).expect("Failed to create a routing request!"),
+        build_request(incoming_request, route_entry).expect("Failed to create a routing request!"),
         route_addr,
     )
 }
 
 pub fn build_request(
     request: hyper::Request<hyper::body::Incoming>,
-    route_config: config::Route,
+    route_entry: &config::RouteEntry,
 ) -> Result<hyper::Request<hyper::body::Incoming>, http::Error> {
+    let route_config: &config::Route = &route_entry.route;
+    let query: String = request
+        .uri()
+        .query()
+        .map_or(String::new(), |query: &str| format!("?{}", query));
     let uri: String = format!(
-        "http://{}:{}{}",
+        "http://{}:{}{}{}",
         route_config.authority.host,
         route_config.authority.port,
-        request.uri().path()
+        route_entry.forward_path(request.uri().path()),
+        query
     );
 
     let traceparent: trace::Traceparent =
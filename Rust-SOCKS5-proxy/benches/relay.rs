@@ -0,0 +1,148 @@
+// Throughput of relaying one loopback TCP connection into another: tokio::io::copy with
+// various buffer sizes against splice(2) through a pipe.
+//
+//     cargo bench --bench relay
+//
+// RELAY_BENCH_MB sets the amount of data sent per run (default 1024).
+#[cfg(target_os = "linux")]
+#[path = "../src/splice.rs"]
+mod splice;
+
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+// Size of the chunks the source writes and the sink reads
+const IO_SIZE: usize = 256 * 1024;
+
+// Runs per method; the fastest one counts
+const RUNS: usize = 3;
+
+#[derive(Clone, Copy)]
+enum Method {
+    // tokio::io::copy with its built-in 8 KiB buffer
+    Copy,
+    // tokio::io::copy_buf through a read buffer of this size
+    Buffered(usize),
+    // splice(2) through a pipe of this size
+    #[cfg(target_os = "linux")]
+    Splice(usize),
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Method::Copy => write!(f, "tokio::io::copy"),
+            Method::Buffered(size) => write!(f, "copy_buf, {} KiB buffer", size / 1024),
+            #[cfg(target_os = "linux")]
+            Method::Splice(size) => write!(f, "splice, {} KiB pipe", size / 1024),
+        }
+    }
+}
+
+// Two ends of a loopback TCP connection
+async fn connected_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+// Move bytes from one socket to the other until EOF, then pass the half-close on
+async fn relay(mut from: TcpStream, mut to: TcpStream, method: Method) -> u64 {
+    let copied = match method {
+        Method::Copy => tokio::io::copy(&mut from, &mut to).await.unwrap(),
+        Method::Buffered(size) => {
+            let mut from = BufReader::with_capacity(size, from);
+            tokio::io::copy_buf(&mut from, &mut to).await.unwrap()
+        }
+        #[cfg(target_os = "linux")]
+        Method::Splice(size) => {
+            let pipe = splice::Pipe::new(size).unwrap();
+            let mut copied = 0;
+            loop {
+                let n = pipe.fill(&from, pipe.size()).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                pipe.drain(&to, n).await.unwrap();
+                copied += n as u64;
+            }
+            copied
+        }
+    };
+    to.shutdown().await.unwrap();
+    copied
+}
+
+// Time to push total bytes from a source through the relay into a sink
+async fn run(method: Method, total: u64) -> Duration {
+    let (mut source, relay_in) = connected_pair().await;
+    let (relay_out, mut sink) = connected_pair().await;
+
+    let start = Instant::now();
+    let relay = tokio::spawn(relay(relay_in, relay_out, method));
+    let source = tokio::spawn(async move {
+        let buf = vec![0xa5u8; IO_SIZE];
+        let mut left = total;
+        while left > 0 {
+            let n = left.min(IO_SIZE as u64) as usize;
+            source.write_all(&buf[..n]).await.unwrap();
+            left -= n as u64;
+        }
+        source.shutdown().await.unwrap();
+    });
+
+    let mut buf = vec![0u8; IO_SIZE];
+    let mut received = 0u64;
+    loop {
+        let n = sink.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        received += n as u64;
+    }
+    let elapsed = start.elapsed();
+
+    source.await.unwrap();
+    assert_eq!(relay.await.unwrap(), total);
+    assert_eq!(received, total);
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    let megabytes: u64 = std::env::var("RELAY_BENCH_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1024);
+    let total = megabytes * 1024 * 1024;
+
+    let methods = [
+        Method::Copy,
+        Method::Buffered(16 * 1024),
+        Method::Buffered(64 * 1024),
+        #[cfg(target_os = "linux")]
+        Method::Splice(64 * 1024),
+        #[cfg(target_os = "linux")]
+        Method::Splice(1024 * 1024),
+    ];
+
+    println!(
+        "Relaying {} MiB over loopback, best of {} runs",
+        megabytes, RUNS
+    );
+    for method in methods {
+        let mut best = Duration::MAX;
+        for _ in 0..RUNS {
+            best = best.min(run(method, total).await);
+        }
+        let throughput = total as f64 / best.as_secs_f64() / (1024.0 * 1024.0);
+        println!(
+            "{:<28} {:>8.1} ms {:>10.1} MiB/s",
+            method.to_string(),
+            best.as_secs_f64() * 1000.0,
+            throughput
+        );
+    }
+}
@@ -0,0 +1,301 @@
+// Structured access log: one JSON record per session, with daily or size-based rotation
+use crate::limits::{self, civil_from_days};
+use crate::request::Address;
+use crate::sessions::Session;
+use anyhow::{Context, Result};
+use log::error;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// When the access log file is rotated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Never,
+    // At midnight UTC; the previous file is renamed to <file>.YYYY-MM-DD
+    Daily,
+    // Before the file grows past this many bytes; previous files are numbered <file>.1, .2, ...
+    Size(u64),
+}
+
+// "daily", "never" or a size such as "100M"
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "daily" => Ok(Rotation::Daily),
+            "never" => Ok(Rotation::Never),
+            size => match limits::parse_size(size)? {
+                0 => Err("Rotation size must be greater than 0".to_string()),
+                bytes => Ok(Rotation::Size(bytes)),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Rotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rotation::Never => write!(f, "never"),
+            Rotation::Daily => write!(f, "daily"),
+            Rotation::Size(bytes) => write!(f, "every {} bytes", bytes),
+        }
+    }
+}
+
+// Why a session ended
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CloseReason {
+    ClientClosed,
+    RemoteClosed,
+    IdleTimeout,
+    QuotaExhausted,
+    Shutdown,
+    Killed,
+    Error,
+}
+
+// What a session did, filled in by the protocol handlers as it progresses
+#[derive(Default)]
+pub(crate) struct SessionDetails {
+    pub(crate) protocol: Option<&'static str>,
+    pub(crate) command: Option<&'static str>,
+    pub(crate) destination: Option<Address>,
+    pub(crate) resolved_ip: Option<IpAddr>,
+    pub(crate) route: Option<String>,
+    pub(crate) reply_code: Option<u16>,
+    pub(crate) connect_latency: Option<Duration>,
+    pub(crate) close_reason: Option<CloseReason>,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    start: String,
+    end: String,
+    listener: &'a str,
+    client: SocketAddr,
+    user: Option<&'a str>,
+    protocol: Option<&'static str>,
+    command: Option<&'static str>,
+    destination: Option<String>,
+    domain: Option<&'a str>,
+    resolved_ip: Option<IpAddr>,
+    route: Option<&'a str>,
+    reply_code: Option<u16>,
+    bytes_up: u64,
+    bytes_down: u64,
+    handshake_ms: Option<f64>,
+    connect_ms: Option<f64>,
+    duration_ms: f64,
+    close_reason: CloseReason,
+    error: Option<String>,
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+    // Days since 1970-01-01 (UTC) of the records in the file
+    day: u64,
+}
+
+pub(crate) struct AccessLog {
+    path: PathBuf,
+    rotation: Rotation,
+    // Number of rotated files kept next to the current one
+    keep: usize,
+    // Records are short single writes, so a blocking file behind a mutex is good enough
+    file: Mutex<OpenFile>,
+}
+
+impl AccessLog {
+    pub(crate) fn open(path: PathBuf, rotation: Rotation, keep: usize) -> Result<Self> {
+        let file =
+            open_file(&path).context(format!("Failed to open access log file: {:?}", path))?;
+        Ok(AccessLog {
+            path,
+            rotation,
+            keep,
+            file: Mutex::new(file),
+        })
+    }
+
+    // Append the record of a finished session; failures are logged, never passed on
+    pub(crate) fn write(&self, session: &Session, result: &Result<()>) {
+        let end = SystemTime::now();
+        let details = session.details();
+        let close_reason = details.close_reason.unwrap_or(match result {
+            Ok(()) => CloseReason::ClientClosed,
+            Err(_) => CloseReason::Error,
+        });
+        let record = Record {
+            start: format_time(session.started),
+            end: format_time(end),
+            listener: &session.listener,
+            client: session.client,
+            user: session.user(),
+            protocol: details.protocol,
+            command: details.command,
+            destination: details.destination.as_ref().map(Address::to_string),
+            domain: details.destination.as_ref().and_then(Address::domain),
+            resolved_ip: details.resolved_ip,
+            route: details.route.as_deref(),
+            reply_code: details.reply_code,
+            bytes_up: session.bytes_up(),
+            bytes_down: session.bytes_down(),
+            handshake_ms: session.handshake_latency().map(milliseconds),
+            connect_ms: details.connect_latency.map(milliseconds),
+            duration_ms: milliseconds(session.start.elapsed()),
+            close_reason,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to encode access log record: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        if let Err(e) = self.append(line.as_bytes(), days(end)) {
+            error!("Failed to write access log {:?}: {}", self.path, e);
+        }
+    }
+
+    fn append(&self, line: &[u8], today: u64) -> std::io::Result<()> {
+        let mut open = self.file.lock().unwrap();
+        let due = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Daily => today != open.day,
+            Rotation::Size(max) => open.size > 0 && open.size + line.len() as u64 > max,
+        };
+        if due {
+            self.rotate(&open)?;
+            *open = open_file(&self.path)?;
+        }
+
+        open.file.write_all(line)?;
+        open.size += line.len() as u64;
+        Ok(())
+    }
+
+    // Move the current file aside and delete rotated files beyond the number kept
+    fn rotate(&self, open: &OpenFile) -> std::io::Result<()> {
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+
+        match self.rotation {
+            Rotation::Daily => {
+                let (year, month, day) = civil_from_days(open.day);
+                let date = format!("{:04}-{:02}-{:02}", year, month, day);
+                std::fs::rename(&self.path, suffixed(&self.path, &date))?;
+
+                // Dates sort chronologically, the oldest files come first
+                let mut dated = self.dated_files()?;
+                dated.sort();
+                let excess = dated.len().saturating_sub(self.keep);
+                for path in &dated[..excess] {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(())
+            }
+            _ => {
+                for index in (1..self.keep).rev() {
+                    let from = suffixed(&self.path, &index.to_string());
+                    if from.exists() {
+                        std::fs::rename(from, suffixed(&self.path, &(index + 1).to_string()))?;
+                    }
+                }
+                std::fs::rename(&self.path, suffixed(&self.path, "1"))
+            }
+        }
+    }
+
+    // Files rotated by date, <file>.YYYY-MM-DD
+    fn dated_files(&self) -> std::io::Result<Vec<PathBuf>> {
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(suffix) = name.strip_prefix(&prefix)
+                && is_date(suffix)
+            {
+                files.push(directory.join(name.as_ref()));
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn open_file(path: &Path) -> std::io::Result<OpenFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    // An existing file keeps the day it was last written on
+    let day = metadata
+        .modified()
+        .map_or_else(|_| days(SystemTime::now()), days);
+    Ok(OpenFile {
+        file,
+        size: metadata.len(),
+        day,
+    })
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn is_date(value: &str) -> bool {
+    value.len() == 10
+        && value.char_indices().all(|(index, c)| match index {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+fn days(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / 86400)
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+// RFC 3339 in UTC with milliseconds, e.g. "2025-03-14T09:26:53.589Z"
+pub(crate) fn format_time(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = elapsed.as_secs();
+    let (year, month, day) = civil_from_days(seconds / 86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        elapsed.subsec_millis()
+    )
+}
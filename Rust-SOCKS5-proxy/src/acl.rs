@@ -0,0 +1,90 @@
+// Destination access control lists
+use crate::ProxyError;
+use crate::rules::{Destination, Matcher, parse_rule_lines};
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use std::path::Path;
+
+struct AclRule {
+    matchers: Vec<Matcher>,
+    allow: bool,
+}
+
+impl AclRule {
+    // Rules that name a domain without constraining the IP cannot vouch for the
+    // resolved address, otherwise DNS rebinding would get around IP rules
+    fn constrains_ip(&self) -> bool {
+        let has_domain = self
+            .matchers
+            .iter()
+            .any(|matcher| matches!(matcher, Matcher::Domain(_)));
+        !has_domain || self.matchers.iter().any(Matcher::needs_ip)
+    }
+}
+
+// Ordered allow/deny rules, the first matching rule decides and destinations that
+// match no rule are allowed
+pub(crate) struct DestinationAcl {
+    rules: Vec<AclRule>,
+}
+
+impl DestinationAcl {
+    pub(crate) async fn load(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .context(format!("Failed to read access list: {:?}", path))?;
+        Self::parse(&content).context(format!("Failed to parse access list: {:?}", path))
+    }
+
+    // Same line format as the routing rules, with "allow" or "deny" as the action
+    fn parse(content: &str) -> Result<Self, ProxyError> {
+        let rules = parse_rule_lines(content)?
+            .into_iter()
+            .map(|line| {
+                let allow = match line.action {
+                    "allow" => true,
+                    "deny" => false,
+                    _ => {
+                        return Err(ProxyError::ConfigError(format!(
+                            "Line {}: action must be allow or deny, found '{}'",
+                            line.line_number, line.action
+                        )));
+                    }
+                };
+                Ok(AclRule {
+                    matchers: line.matchers,
+                    allow,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(DestinationAcl { rules })
+    }
+
+    pub(crate) fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub(crate) fn needs_ip(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.matchers.iter().any(Matcher::needs_ip))
+    }
+
+    // A destination must pass both with its domain and on its resolved IP alone
+    pub(crate) fn is_allowed(&self, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> bool {
+        let dest = Destination { domain, ip, port };
+        if !self.evaluate(&dest, |_| true) {
+            return false;
+        }
+        domain.is_none() || self.evaluate(&dest, AclRule::constrains_ip)
+    }
+
+    fn evaluate(&self, dest: &Destination, filter: impl Fn(&AclRule) -> bool) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| filter(rule))
+            .find(|rule| rule.matchers.iter().all(|matcher| matcher.matches(dest)))
+            .is_none_or(|rule| rule.allow)
+    }
+}
@@ -0,0 +1,287 @@
+// Admin HTTP API: list and kill live sessions, drain listeners
+use crate::access_log::format_time;
+use crate::reload::SharedContext;
+use crate::sessions::{Session, SessionRegistry};
+use anyhow::Result;
+use log::{debug, info};
+use serde::Serialize;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+// Upper bound for an admin request header
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// What the API shows of a live session
+#[derive(Serialize)]
+struct SessionInfo<'a> {
+    id: u64,
+    listener: &'a str,
+    client: SocketAddr,
+    user: Option<&'a str>,
+    protocol: Option<&'static str>,
+    command: Option<&'static str>,
+    target: Option<String>,
+    resolved_ip: Option<IpAddr>,
+    start: String,
+    established: bool,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+#[derive(Serialize)]
+struct ListenerInfo<'a> {
+    name: &'a str,
+    bind: SocketAddr,
+    draining: bool,
+    sessions: usize,
+}
+
+struct Api {
+    sessions: Arc<SessionRegistry>,
+    listeners: Vec<Arc<SharedContext>>,
+    token: String,
+}
+
+// Serve the admin API until shutdown
+pub(crate) async fn serve(
+    listener: TcpListener,
+    sessions: Arc<SessionRegistry>,
+    listeners: Vec<Arc<SharedContext>>,
+    token: String,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let api = Arc::new(Api {
+        sessions,
+        listeners,
+        token,
+    });
+
+    loop {
+        tokio::select! {
+            accept_result = listener.accept() => {
+                let (stream, addr) = match accept_result {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        debug!("Error accepting admin connection: {}", e);
+                        continue;
+                    }
+                };
+                let api = Arc::clone(&api);
+                tokio::spawn(async move {
+                    let result =
+                        timeout(Duration::from_secs(10), api.handle_request(stream, addr)).await;
+                    if let Ok(Err(e)) = result {
+                        debug!("Admin request from {} failed: {}", addr, e);
+                    }
+                });
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Admin API received shutdown signal");
+                break;
+            }
+        }
+    }
+}
+
+impl Api {
+    async fn handle_request(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        let mut buf = Vec::new();
+        while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+            if buf.len() >= MAX_REQUEST_SIZE {
+                return Err(anyhow::anyhow!("Admin request header too large"));
+            }
+            let mut chunk = [0u8; 1024];
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        let (status, body) = if request.parse(&buf).is_err() {
+            ("400 Bad Request", error("malformed request"))
+        } else if !self.authorized(request.headers) {
+            ("401 Unauthorized", error("missing or invalid bearer token"))
+        } else {
+            let method = request.method.unwrap_or_default();
+            let target = request.path.unwrap_or_default();
+            let response = self.route(method, target);
+            info!(
+                "Admin request from {}: {} {} ({})",
+                addr, method, target, response.0
+            );
+            response
+        };
+
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            if status.starts_with("401") {
+                "WWW-Authenticate: Bearer\r\n"
+            } else {
+                ""
+            },
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    // Requests carry "Authorization: Bearer <token>"
+    fn authorized(&self, headers: &[httparse::Header]) -> bool {
+        headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("authorization"))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| bool::from(token.trim().as_bytes().ct_eq(self.token.as_bytes())))
+    }
+
+    fn route(&self, method: &str, target: &str) -> (&'static str, serde_json::Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let segments: Option<Vec<String>> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(percent_decode)
+            .collect();
+        let Some(segments) = segments else {
+            return ("400 Bad Request", error("invalid percent-encoding in path"));
+        };
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+        match (method, segments.as_slice()) {
+            ("GET", ["sessions"]) => {
+                let user = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("user="))
+                    .and_then(percent_decode);
+                let sessions = self.sessions.list(user.as_deref());
+                let sessions: Vec<serde_json::Value> = sessions
+                    .iter()
+                    .map(|session| session_info(session))
+                    .collect();
+                ("200 OK", json!(sessions))
+            }
+            ("DELETE", ["sessions", id]) => match id.parse() {
+                Ok(id) if self.sessions.kill(id) => ("200 OK", json!({ "killed": 1 })),
+                _ => ("404 Not Found", error("no such session")),
+            },
+            ("DELETE", ["users", user, "sessions"]) => {
+                let killed = self.sessions.kill_user(user);
+                ("200 OK", json!({ "killed": killed }))
+            }
+            ("GET", ["listeners"]) => {
+                let listeners: Vec<serde_json::Value> = self
+                    .listeners
+                    .iter()
+                    .map(|listener| self.listener_info(listener))
+                    .collect();
+                ("200 OK", json!(listeners))
+            }
+            ("POST", ["listeners", name, action @ ("drain" | "resume")]) => {
+                match self
+                    .listeners
+                    .iter()
+                    .find(|listener| listener.settings().name == *name)
+                {
+                    Some(listener) => {
+                        set_draining(listener, *action == "drain");
+                        ("200 OK", self.listener_info(listener))
+                    }
+                    None => ("404 Not Found", error("no such listener")),
+                }
+            }
+            ("POST", [action @ ("drain" | "resume")]) => {
+                for listener in &self.listeners {
+                    set_draining(listener, *action == "drain");
+                }
+                let listeners: Vec<serde_json::Value> = self
+                    .listeners
+                    .iter()
+                    .map(|listener| self.listener_info(listener))
+                    .collect();
+                ("200 OK", json!(listeners))
+            }
+            _ => ("404 Not Found", error("not found")),
+        }
+    }
+
+    fn listener_info(&self, listener: &SharedContext) -> serde_json::Value {
+        let name = &listener.settings().name;
+        let sessions = self
+            .sessions
+            .list(None)
+            .iter()
+            .filter(|session| session.listener == *name)
+            .count();
+        json!(ListenerInfo {
+            name,
+            bind: listener.settings().bind,
+            draining: listener.is_draining(),
+            sessions,
+        })
+    }
+}
+
+fn set_draining(listener: &SharedContext, draining: bool) {
+    if listener.is_draining() != draining {
+        let name = &listener.settings().name;
+        match draining {
+            true => info!("Listener {} is draining, new connections are refused", name),
+            false => info!("Listener {} accepts new connections again", name),
+        }
+    }
+    listener.set_draining(draining);
+}
+
+fn session_info(session: &Session) -> serde_json::Value {
+    let details = session.details();
+    json!(SessionInfo {
+        id: session.id,
+        listener: &session.listener,
+        client: session.client,
+        user: session.user(),
+        protocol: details.protocol,
+        command: details.command,
+        target: details.destination.as_ref().map(ToString::to_string),
+        resolved_ip: details.resolved_ip,
+        start: format_time(session.started),
+        established: session.is_established(),
+        bytes_up: session.bytes_up(),
+        bytes_down: session.bytes_down(),
+    })
+}
+
+fn error(message: &str) -> serde_json::Value {
+    json!({ "error": message })
+}
+
+// Decode %XX escapes in a path segment or query value
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(index + 1..index + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
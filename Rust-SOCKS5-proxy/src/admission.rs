@@ -0,0 +1,159 @@
+// Admission control: a session takes one of max_connections slots once its request is
+// known, waiting in a bounded queue when all are taken, and users have their own caps
+use crate::server::Stats;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AdmissionSettings {
+    // Sessions served at once
+    pub(crate) max_sessions: usize,
+    // Sessions that may wait for a slot, 0 turns them away at once
+    pub(crate) queue_size: usize,
+    pub(crate) queue_timeout: Duration,
+    // Sessions of one user at once unless the user has a limit of their own, 0 for unlimited
+    pub(crate) max_per_user: usize,
+}
+
+// Why a session was turned away
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Rejection {
+    QueueFull,
+    QueueTimeout(Duration),
+    UserLimit(usize),
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::QueueFull => write!(f, "all session slots and the wait queue are taken"),
+            Rejection::QueueTimeout(waited) => {
+                write!(f, "no session slot freed up within {}s", waited.as_secs())
+            }
+            Rejection::UserLimit(limit) => write!(f, "user has {} sessions already", limit),
+        }
+    }
+}
+
+pub(crate) struct Admission {
+    settings: AdmissionSettings,
+    slots: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    users: Mutex<HashMap<String, usize>>,
+    stats: Arc<Stats>,
+}
+
+impl Admission {
+    pub(crate) fn new(settings: AdmissionSettings, stats: Arc<Stats>) -> Self {
+        Admission {
+            settings,
+            slots: Arc::new(Semaphore::new(settings.max_sessions)),
+            waiting: AtomicUsize::new(0),
+            users: Mutex::new(HashMap::new()),
+            stats,
+        }
+    }
+
+    // The session holds its slot until the permit is dropped. user_limit overrides the
+    // per-user limit of the settings
+    pub(crate) async fn admit(
+        self: &Arc<Self>,
+        user: Option<&str>,
+        user_limit: Option<usize>,
+    ) -> Result<AdmissionPermit, Rejection> {
+        let result = self.acquire(user, user_limit).await;
+        if result.is_err() {
+            self.stats
+                .rejected_connections
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn acquire(
+        self: &Arc<Self>,
+        user: Option<&str>,
+        user_limit: Option<usize>,
+    ) -> Result<AdmissionPermit, Rejection> {
+        // A user at their limit is not queued, waiting would not free one of their slots
+        let user = match user {
+            Some(user) => {
+                let limit = user_limit.unwrap_or(self.settings.max_per_user);
+                let mut users = self.users.lock().unwrap();
+                let active = users.entry(user.to_string()).or_default();
+                if limit > 0 && *active >= limit {
+                    return Err(Rejection::UserLimit(limit));
+                }
+                *active += 1;
+                Some(UserSlot {
+                    admission: Arc::clone(self),
+                    user: user.to_string(),
+                })
+            }
+            None => None,
+        };
+
+        let slot = match Arc::clone(&self.slots).try_acquire_owned() {
+            Ok(slot) => slot,
+            Err(_) => {
+                let Some(_waiting) = self.wait() else {
+                    return Err(Rejection::QueueFull);
+                };
+                let queue_timeout = self.settings.queue_timeout;
+                match timeout(queue_timeout, Arc::clone(&self.slots).acquire_owned()).await {
+                    Ok(Ok(slot)) => slot,
+                    // The semaphore is never closed
+                    Ok(Err(_)) | Err(_) => return Err(Rejection::QueueTimeout(queue_timeout)),
+                }
+            }
+        };
+        Ok(AdmissionPermit {
+            _slot: slot,
+            _user: user,
+        })
+    }
+
+    // A place in the queue, given up when the session stops waiting for whatever reason
+    fn wait(&self) -> Option<Waiting<'_>> {
+        self.waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |waiting| {
+                (waiting < self.settings.queue_size).then_some(waiting + 1)
+            })
+            .ok()
+            .map(|_| Waiting(&self.waiting))
+    }
+}
+
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct UserSlot {
+    admission: Arc<Admission>,
+    user: String,
+}
+
+impl Drop for UserSlot {
+    fn drop(&mut self) {
+        let mut users = self.admission.users.lock().unwrap();
+        if let Some(active) = users.get_mut(&self.user) {
+            *active -= 1;
+            if *active == 0 {
+                users.remove(&self.user);
+            }
+        }
+    }
+}
+
+pub(crate) struct AdmissionPermit {
+    _slot: OwnedSemaphorePermit,
+    _user: Option<UserSlot>,
+}
@@ -0,0 +1,633 @@
+// Password verification and pluggable authentication backends
+use crate::ProxyError;
+use crate::acl::DestinationAcl;
+use crate::connect::Egress;
+use crate::limits::{self, LimitSettings};
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use argon2::password_hash::phc::PasswordHash;
+use argon2::password_hash::{CustomizedPasswordHasher, PasswordHasher, PasswordVerifier};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::warn;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+// Upper bound for the response of an authentication callback
+const MAX_CALLBACK_RESPONSE_SIZE: usize = 16384;
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<bool>> + Send + 'a>>;
+
+// Source of truth for proxy credentials
+pub trait Authenticator: Send + Sync {
+    // Check a username and password presented by the client
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        client: SocketAddr,
+    ) -> AuthFuture<'a>;
+
+    // Whether a user exists, for SOCKS4 which sends a USERID but no password
+    fn has_user(&self, _username: &str) -> bool {
+        false
+    }
+}
+
+// Hash formats accepted in the auth file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Bcrypt,
+    Argon2,
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bcrypt" => Ok(HashAlgorithm::Bcrypt),
+            "argon2" => Ok(HashAlgorithm::Argon2),
+            _ => Err(format!(
+                "Unknown hash algorithm: {} (bcrypt or argon2)",
+                value
+            )),
+        }
+    }
+}
+
+// Produce a hash for the auth file
+pub fn hash_password(password: &str, algorithm: HashAlgorithm) -> Result<String> {
+    match algorithm {
+        HashAlgorithm::Bcrypt => {
+            bcrypt::hash(password, bcrypt::DEFAULT_COST).context("Failed to hash password")
+        }
+        HashAlgorithm::Argon2 => Argon2::default()
+            .hash_password(password.as_bytes())
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e)),
+    }
+}
+
+fn is_bcrypt(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| stored.starts_with(prefix))
+}
+
+// Compare a password with a stored bcrypt, argon2, apr1, {SHA} or plaintext value
+pub(crate) fn verify_password(stored: &str, password: &str) -> bool {
+    if is_bcrypt(stored) {
+        bcrypt::verify(password, stored).unwrap_or(false)
+    } else if stored.starts_with("$argon2") {
+        Argon2::default()
+            .verify_password(password.as_bytes(), stored)
+            .is_ok()
+    } else if let Some(rest) = stored.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or_default();
+        constant_time_eq(&apr1_md5(password, salt), stored)
+    } else if let Some(digest) = stored.strip_prefix("{SHA}") {
+        constant_time_eq(&BASE64.encode(Sha1::digest(password.as_bytes())), digest)
+    } else {
+        constant_time_eq(stored, password)
+    }
+}
+
+// Hashes that cost real CPU time, verified off the async workers
+fn is_expensive(stored: &str) -> bool {
+    is_bcrypt(stored) || stored.starts_with("$argon2")
+}
+
+async fn verify_password_async(stored: &str, password: &str) -> Result<bool> {
+    if !is_expensive(stored) {
+        return Ok(verify_password(stored, password));
+    }
+    let stored = stored.to_string();
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || verify_password(&stored, &password))
+        .await
+        .context("Password verification task failed")
+}
+
+// Stand-in for the entry of an unknown username: a hash of the same format and cost as a
+// bcrypt or argon2 entry (or any entry if there is none), so that verifying against it
+// takes as long as verifying a known user's password
+async fn dummy_hash(stored: &HashMap<String, String>) -> Option<String> {
+    let template = stored
+        .values()
+        .find(|stored| is_expensive(stored))
+        .or_else(|| stored.values().next())?
+        .clone();
+    let hashed = tokio::task::spawn_blocking(move || hash_like(&template, "unknown user"))
+        .await
+        .context("Password hashing task failed")
+        .and_then(|hashed| hashed);
+    match hashed {
+        Ok(hashed) => Some(hashed),
+        Err(e) => {
+            warn!(
+                "Unknown usernames are rejected faster than known ones: {:#}",
+                e
+            );
+            None
+        }
+    }
+}
+
+// Hash password with the format and parameters of template
+fn hash_like(template: &str, password: &str) -> Result<String> {
+    if is_bcrypt(template) {
+        let cost = template
+            .get(4..6)
+            .and_then(|cost| cost.parse().ok())
+            .unwrap_or(bcrypt::DEFAULT_COST);
+        bcrypt::hash(password, cost).context("Failed to hash password")
+    } else if template.starts_with("$argon2") {
+        let hash = PasswordHash::new(template)
+            .map_err(|e| anyhow::anyhow!("Invalid argon2 hash: {}", e))?;
+        let params = argon2::Params::try_from(&hash)
+            .map_err(|e| anyhow::anyhow!("Invalid argon2 hash: {}", e))?;
+        Argon2::default()
+            .hash_password_customized(
+                password.as_bytes(),
+                b"unknown-user-salt",
+                Some(hash.algorithm.as_str()),
+                hash.version,
+                params,
+            )
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))
+    } else if let Some(rest) = template.strip_prefix("$apr1$") {
+        Ok(apr1_md5(
+            password,
+            rest.split('$').next().unwrap_or_default(),
+        ))
+    } else if template.starts_with("{SHA}") {
+        Ok(format!(
+            "{{SHA}}{}",
+            BASE64.encode(Sha1::digest(password.as_bytes()))
+        ))
+    } else {
+        Ok(password.to_string())
+    }
+}
+
+// Unknown usernames are rejected only after the same work as a known one
+async fn reject_unknown(dummy_hash: Option<&str>, password: &str) -> Result<bool> {
+    if let Some(dummy_hash) = dummy_hash {
+        verify_password_async(dummy_hash, password).await?;
+    }
+    Ok(false)
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+// Apache's MD5-based crypt variant used by "htpasswd -m"
+fn apr1_md5(password: &str, salt: &str) -> String {
+    const MAGIC: &str = "$apr1$";
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut context = Md5::new()
+        .chain_update(password)
+        .chain_update(MAGIC)
+        .chain_update(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        context.update(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            context.update([0u8]);
+        } else {
+            context.update(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 == 1 {
+            context.update(password);
+        } else {
+            context.update(digest);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 == 1 {
+            context.update(digest);
+        } else {
+            context.update(password);
+        }
+        digest = context.finalize();
+    }
+
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut encoded = format!("{}{}$", MAGIC, String::from_utf8_lossy(salt));
+    let mut push = |value: u32, count: usize| {
+        let mut value = value;
+        for _ in 0..count {
+            encoded.push(ALPHABET[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        let value =
+            (u32::from(digest[a]) << 16) | (u32::from(digest[b]) << 8) | u32::from(digest[c]);
+        push(value, 4);
+    }
+    push(u32::from(digest[11]), 2);
+    encoded
+}
+
+// User authentication information storage
+pub(crate) struct Users {
+    pub(crate) credentials: HashMap<String, String>,
+    pub(crate) settings: HashMap<String, UserSettings>,
+    dummy_hash: Option<String>,
+}
+
+// Per-user options that follow the credentials in the auth file
+#[derive(Default)]
+pub(crate) struct UserSettings {
+    pub(crate) destination_acl: Option<Arc<DestinationAcl>>,
+    pub(crate) limits: LimitSettings,
+    pub(crate) egress: Egress,
+    // Concurrent sessions, overriding the global per-user limit
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl Users {
+    // Lines are "username:password", the password being everything after the first colon,
+    // optionally followed by a tab and whitespace-separated key=value options, e.g.
+    // "alice:secret\tacl=alice.acl"
+    pub(crate) async fn load(auth_file: &str) -> Result<Self> {
+        let content = tokio::fs::read_to_string(auth_file)
+            .await
+            .context(format!("Failed to read auth file: {}", auth_file))?;
+
+        let mut credentials = HashMap::new();
+        let mut settings = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+
+            // Passwords may contain spaces but not tabs
+            let (credential, options) = line.split_once('\t').unwrap_or((line, ""));
+            let Some((username, password)) = credential.split_once(':') else {
+                continue;
+            };
+            // The quota state file separates usernames by whitespace
+            let username = username.trim();
+            if username.is_empty() || username.contains(|c: char| c.is_whitespace() || c == ':') {
+                return Err(ProxyError::ConfigError(format!(
+                    "{} line {}: invalid username '{}'",
+                    auth_file,
+                    index + 1,
+                    username
+                ))
+                .into());
+            }
+
+            let mut user_settings = UserSettings::default();
+            for option in options.split_whitespace() {
+                let invalid = |reason: &str| {
+                    ProxyError::ConfigError(format!(
+                        "{} line {}: {} '{}'",
+                        auth_file,
+                        index + 1,
+                        reason,
+                        option
+                    ))
+                };
+                let (key, value) = option
+                    .split_once('=')
+                    .ok_or_else(|| invalid("invalid user option"))?;
+                match key {
+                    "acl" => {
+                        let acl = DestinationAcl::load(Path::new(value)).await?;
+                        user_settings.destination_acl = Some(Arc::new(acl));
+                    }
+                    "upload" => {
+                        let rate = limits::parse_size(value).map_err(|e| invalid(&e))?;
+                        user_settings.limits.upload = Some(rate);
+                    }
+                    "download" => {
+                        let rate = limits::parse_size(value).map_err(|e| invalid(&e))?;
+                        user_settings.limits.download = Some(rate);
+                    }
+                    "quota" => {
+                        let quota = value.parse().map_err(|e: String| invalid(&e))?;
+                        user_settings.limits.quota = Some(quota);
+                    }
+                    "egress" => {
+                        user_settings.egress = value.parse().map_err(|e: String| invalid(&e))?;
+                    }
+                    "connections" => {
+                        let limit = value
+                            .parse()
+                            .map_err(|_| invalid("invalid session limit"))?;
+                        user_settings.max_connections = Some(limit);
+                    }
+                    _ => return Err(invalid("unknown user option").into()),
+                }
+            }
+
+            credentials.insert(username.to_string(), password.to_string());
+            settings.insert(username.to_string(), user_settings);
+        }
+
+        Ok(Users {
+            dummy_hash: dummy_hash(&credentials).await,
+            credentials,
+            settings,
+        })
+    }
+
+    pub(crate) fn contains(&self, username: &str) -> bool {
+        self.credentials.contains_key(username)
+    }
+}
+
+// Auth file backend, passwords may be plaintext, bcrypt or argon2
+impl Authenticator for Users {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        _client: SocketAddr,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            match self.credentials.get(username) {
+                Some(stored) => verify_password_async(stored, password).await,
+                None => reject_unknown(self.dummy_hash.as_deref(), password).await,
+            }
+        })
+    }
+
+    fn has_user(&self, username: &str) -> bool {
+        self.contains(username)
+    }
+}
+
+// Apache htpasswd file with bcrypt, apr1 (MD5) or {SHA} entries
+pub(crate) struct HtpasswdAuthenticator {
+    entries: HashMap<String, String>,
+    dummy_hash: Option<String>,
+}
+
+impl HtpasswdAuthenticator {
+    pub(crate) async fn load(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .context(format!("Failed to read htpasswd file: {:?}", path))?;
+
+        let mut entries = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((username, hash)) = line.split_once(':') else {
+                return Err(ProxyError::ConfigError(format!(
+                    "{:?} line {}: expected username:hash",
+                    path,
+                    index + 1
+                ))
+                .into());
+            };
+            if !(is_bcrypt(hash) || hash.starts_with("$apr1$") || hash.starts_with("{SHA}")) {
+                return Err(ProxyError::ConfigError(format!(
+                    "{:?} line {}: unsupported hash format for {} (use bcrypt, MD5 or SHA1)",
+                    path,
+                    index + 1,
+                    username
+                ))
+                .into());
+            }
+            entries.insert(username.to_string(), hash.to_string());
+        }
+
+        Ok(HtpasswdAuthenticator {
+            dummy_hash: dummy_hash(&entries).await,
+            entries,
+        })
+    }
+}
+
+impl Authenticator for HtpasswdAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        _client: SocketAddr,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            match self.entries.get(username) {
+                Some(stored) => verify_password_async(stored, password).await,
+                None => reject_unknown(self.dummy_hash.as_deref(), password).await,
+            }
+        })
+    }
+
+    fn has_user(&self, username: &str) -> bool {
+        self.entries.contains_key(username)
+    }
+}
+
+// Asks an external service: POSTs {"username", "password", "client"} as JSON,
+// a 2xx answer accepts the credentials and 401 or 403 rejects them
+pub(crate) struct HttpCallbackAuthenticator {
+    url: String,
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+    timeout_duration: Duration,
+    connector: TlsConnector,
+}
+
+impl HttpCallbackAuthenticator {
+    pub(crate) fn new(url: &str, timeout_duration: Duration) -> Result<Self> {
+        let invalid = |reason: &str| {
+            ProxyError::ConfigError(format!("Invalid auth callback URL '{}': {}", url, reason))
+        };
+
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| invalid("missing scheme"))?;
+        let tls = match scheme.to_ascii_lowercase().as_str() {
+            "http" => false,
+            "https" => true,
+            _ => return Err(invalid("scheme must be http or https").into()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => rest.split_at(pos),
+            None => (rest, "/"),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (host, port) = bracketed
+                .split_once(']')
+                .ok_or_else(|| invalid("unterminated IPv6 address"))?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("invalid port"))?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host").into());
+        }
+
+        let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(HttpCallbackAuthenticator {
+            url: url.to_string(),
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            timeout_duration,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    async fn call(&self, username: &str, password: &str, client: SocketAddr) -> Result<bool> {
+        let body = serde_json::json!({
+            "username": username,
+            "password": password,
+            "client": client.to_string(),
+        })
+        .to_string();
+        let host_header = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            host_header,
+            body.len(),
+            body
+        );
+
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .context(format!("Failed to connect to auth callback {}", self.url))?;
+        let status = if self.tls {
+            let server_name = ServerName::try_from(self.host.clone())
+                .context(format!("Invalid TLS server name: {}", self.host))?;
+            let stream = self
+                .connector
+                .connect(server_name, stream)
+                .await
+                .context(format!(
+                    "TLS handshake with auth callback {} failed",
+                    self.url
+                ))?;
+            exchange(stream, request.as_bytes()).await?
+        } else {
+            exchange(stream, request.as_bytes()).await?
+        };
+
+        match status {
+            200..=299 => Ok(true),
+            401 | 403 => Ok(false),
+            _ => Err(anyhow::anyhow!(
+                "Auth callback {} replied with HTTP {}",
+                self.url,
+                status
+            )),
+        }
+    }
+}
+
+// Send a request and return the status code of the response
+async fn exchange<S>(mut stream: S, request: &[u8]) -> Result<u16>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(request)
+        .await
+        .context("Failed to send auth callback request")?;
+
+    let mut response = Vec::new();
+    while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+        if response.len() >= MAX_CALLBACK_RESPONSE_SIZE {
+            return Err(anyhow::anyhow!("Auth callback response header too large"));
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream
+            .read(&mut chunk)
+            .await
+            .context("Failed to read auth callback response")?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&chunk[..n]);
+    }
+
+    String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid auth callback response"))
+}
+
+impl Authenticator for HttpCallbackAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        client: SocketAddr,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            match timeout(self.timeout_duration, self.call(username, password, client)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!("Auth callback {} timed out", self.url);
+                    Err(ProxyError::Timeout(format!("Auth callback {}", self.url)).into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,155 @@
+// BIND command for inbound connections (RFC 1928, section 4)
+use crate::connect::TargetAddress;
+use crate::context::ServerContext;
+use crate::relay::relay;
+use crate::sessions::Session;
+use crate::socks5::send_reply_with_addr;
+use crate::{ProxyError, REPLY_GENERAL_FAILURE, REPLY_HOST_UNREACHABLE, REPLY_SUCCEEDED, socks4};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::{Instant, timeout_at};
+
+// Protocol of the client that issued the BIND request, which decides the reply format
+#[derive(Clone, Copy)]
+pub(crate) enum BindProtocol {
+    Socks4,
+    Socks5,
+}
+
+pub(crate) async fn handle_bind<T>(
+    mut socket: T,
+    protocol: BindProtocol,
+    session: &Session,
+    local_addr: SocketAddr,
+    expected_peer: TargetAddress,
+    context: &ServerContext,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let addr = session.client;
+
+    // Open the listener on the interface the client reached us on
+    let listener = match TcpListener::bind(SocketAddr::new(local_addr.ip(), 0)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            send_bind_reply(&mut socket, session, protocol, REPLY_GENERAL_FAILURE, None)
+                .await
+                .context("Failed to send general failure reply")?;
+            return Err(
+                ProxyError::NetworkError(format!("Failed to bind BIND listener: {}", e)).into(),
+            );
+        }
+    };
+    let listen_addr = listener
+        .local_addr()
+        .context("Failed to get BIND listener address")?;
+
+    // First reply: the address the application server should connect to
+    send_bind_reply(
+        &mut socket,
+        session,
+        protocol,
+        REPLY_SUCCEEDED,
+        Some(listen_addr),
+    )
+    .await
+    .context("Failed to send first BIND reply")?;
+    info!(
+        "{} waiting for inbound connection from {} on {}",
+        addr, expected_peer, listen_addr
+    );
+
+    // Accept exactly one connection from the expected host within the timeout;
+    // an unspecified DST.ADDR accepts any host
+    let deadline = Instant::now() + context.timeout_duration;
+    let (mut remote, peer_addr) = loop {
+        match timeout_at(deadline, listener.accept()).await {
+            Ok(Ok((stream, peer_addr))) => {
+                let peer_ip = peer_addr.ip().to_canonical();
+                if expected_peer
+                    .ips
+                    .iter()
+                    .any(|ip| ip.is_unspecified() || ip.to_canonical() == peer_ip)
+                {
+                    break (stream, peer_addr);
+                }
+                warn!(
+                    "{} rejected inbound connection from {} (expected {})",
+                    addr, peer_addr, expected_peer
+                );
+            }
+            Ok(Err(e)) => {
+                send_bind_reply(&mut socket, session, protocol, REPLY_GENERAL_FAILURE, None)
+                    .await
+                    .context("Failed to send general failure reply")?;
+                return Err(ProxyError::NetworkError(format!(
+                    "Failed to accept inbound connection: {}",
+                    e
+                ))
+                .into());
+            }
+            Err(_) => {
+                send_bind_reply(&mut socket, session, protocol, REPLY_HOST_UNREACHABLE, None)
+                    .await
+                    .context("Failed to send timeout reply")?;
+                return Err(ProxyError::Timeout(format!(
+                    "No inbound connection on {} from {}",
+                    listen_addr, expected_peer
+                ))
+                .into());
+            }
+        }
+    };
+    drop(listener);
+
+    // Second reply: the address of the connecting host
+    send_bind_reply(
+        &mut socket,
+        session,
+        protocol,
+        REPLY_SUCCEEDED,
+        Some(peer_addr),
+    )
+    .await
+    .context("Failed to send second BIND reply")?;
+    info!("{} accepted inbound connection from {}", addr, peer_addr);
+
+    match relay(&mut socket, &mut remote, session, context).await {
+        Ok(transferred) => {
+            info!(
+                "{} closed inbound connection from {} ({})",
+                addr, peer_addr, transferred
+            );
+            Ok(())
+        }
+        Err(e) => {
+            warn!("{} error with inbound {} - {}", addr, peer_addr, e);
+            Err(ProxyError::NetworkError(format!("Data transfer error: {}", e)).into())
+        }
+    }
+}
+
+// Send a BIND reply given as a SOCKS5 reply code in the format of the client protocol
+async fn send_bind_reply<T>(
+    socket: &mut T,
+    session: &Session,
+    protocol: BindProtocol,
+    reply_code: u8,
+    bind_addr: Option<SocketAddr>,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let bind_addr = bind_addr.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    match protocol {
+        BindProtocol::Socks5 => send_reply_with_addr(socket, session, reply_code, bind_addr).await,
+        BindProtocol::Socks4 => {
+            let granted = reply_code == REPLY_SUCCEEDED;
+            socks4::send_reply(socket, session, granted, bind_addr).await
+        }
+    }
+}
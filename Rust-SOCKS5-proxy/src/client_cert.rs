@@ -0,0 +1,89 @@
+// Client certificate authentication (mutual TLS) and the users certificates stand for
+use crate::ProxyError;
+use crate::config::ClientUser;
+use crate::sessions::Session;
+use anyhow::{Context, Result};
+use log::debug;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, CertificateRevocationListDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::server::TlsStream;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+// Require a client certificate that chains to the CA bundle and, given CRLs, is not revoked
+pub(crate) fn verifier(ca: &Path, crl: Option<&Path>) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca)
+        .context(format!("Failed to open client CA file: {:?}", ca))?
+    {
+        let cert = cert.context(format!("Failed to parse client CA file: {:?}", ca))?;
+        roots
+            .add(cert)
+            .context(format!("Invalid certificate in client CA file: {:?}", ca))?;
+    }
+    if roots.is_empty() {
+        return Err(ProxyError::ConfigError(format!(
+            "No certificates found in client CA file: {:?}",
+            ca
+        ))
+        .into());
+    }
+
+    let mut builder = WebPkiClientVerifier::builder(Arc::new(roots));
+    if let Some(crl) = crl {
+        let crls = CertificateRevocationListDer::pem_file_iter(crl)
+            .context(format!("Failed to open CRL file: {:?}", crl))?
+            .collect::<Result<Vec<_>, _>>()
+            .context(format!("Failed to parse CRL file: {:?}", crl))?;
+        builder = builder.with_crls(crls);
+    }
+
+    builder
+        .build()
+        .context("Failed to build client certificate verifier")
+}
+
+// Take the session's user from the verified client certificate
+pub(crate) fn identify(
+    tls_stream: &TlsStream<TcpStream>,
+    source: ClientUser,
+    session: &Session,
+) -> Result<(), ProxyError> {
+    let user = tls_stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(<[_]>::first)
+        .and_then(|cert| certificate_user(cert, source))
+        .ok_or(ProxyError::AuthenticationFailed)?;
+
+    debug!(
+        "{} authenticated as {} by client certificate",
+        session.client, user
+    );
+    session.set_user(&user);
+    Ok(())
+}
+
+// The subject CN, or the first email, DNS or URI entry of the subject alternative names
+fn certificate_user(cert: &CertificateDer, source: ClientUser) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let user = match source {
+        ClientUser::Cn => cert.subject().iter_common_name().next()?.as_str().ok()?,
+        ClientUser::San => {
+            let san = cert.subject_alternative_name().ok()??;
+            san.value.general_names.iter().find_map(|name| match name {
+                GeneralName::RFC822Name(name)
+                | GeneralName::DNSName(name)
+                | GeneralName::URI(name) => Some(*name),
+                _ => None,
+            })?
+        }
+    };
+    (!user.is_empty()).then(|| user.to_string())
+}
@@ -0,0 +1,251 @@
+// Listener settings, from the command line, a TOML configuration file or an embedder
+use crate::ProxyError;
+use crate::context::AllowedIPs;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Credential source of a listener
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+    File,
+    Htpasswd,
+    Http,
+}
+
+impl std::fmt::Display for AuthBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthBackend::File => write!(f, "file"),
+            AuthBackend::Htpasswd => write!(f, "htpasswd"),
+            AuthBackend::Http => write!(f, "http"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthSettings {
+    #[serde(default = "default_auth_backend")]
+    pub backend: AuthBackend,
+    // Auth file or htpasswd file
+    #[serde(default = "default_auth_file")]
+    pub file: String,
+    // Callback URL of the http backend
+    pub url: Option<String>,
+}
+
+// Certificate field a client certificate's user is taken from
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientUser {
+    Cn,
+    San,
+}
+
+impl std::fmt::Display for ClientUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientUser::Cn => write!(f, "subject CN"),
+            ClientUser::San => write!(f, "SAN"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // CA bundle client certificates must chain to, enables mutual TLS
+    pub client_ca: Option<PathBuf>,
+    // Revocation lists checked for client certificates
+    pub client_crl: Option<PathBuf>,
+    #[serde(default = "default_client_user")]
+    pub client_user: ClientUser,
+}
+
+impl TlsSettings {
+    // Where the user comes from when client certificates are required
+    pub fn client_user(&self) -> Option<ClientUser> {
+        self.client_ca.as_ref().map(|_| self.client_user)
+    }
+}
+
+// Everything that may differ between listeners; the rest is shared by all of them
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSettings {
+    #[serde(default)]
+    pub name: String,
+    pub bind: SocketAddr,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    // Established tunnels without traffic in either direction for this long are closed, 0 never
+    #[serde(default = "default_idle_timeout_seconds")]
+    pub idle_timeout_seconds: u64,
+    pub auth: Option<AuthSettings>,
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    pub allowed_ips_file: Option<PathBuf>,
+    pub tls: Option<TlsSettings>,
+    // Read a PROXY protocol header from trusted_proxies and use the client address it carries
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // Accept a SOCKS4 USERID that names a known user as its login; it has no password,
+    // so anyone who knows a username gets in
+    #[serde(default)]
+    pub allow_socks4_userid: bool,
+}
+
+impl ListenerSettings {
+    // A listener on addr with the defaults of a configuration file
+    pub fn new(bind: SocketAddr) -> Self {
+        ListenerSettings {
+            name: String::new(),
+            bind,
+            timeout_seconds: default_timeout_seconds(),
+            idle_timeout_seconds: default_idle_timeout_seconds(),
+            auth: None,
+            allowed_ips: Vec::new(),
+            allowed_ips_file: None,
+            tls: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            allow_socks4_userid: false,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_seconds > 0).then(|| Duration::from_secs(self.idle_timeout_seconds))
+    }
+
+    // Reason the settings cannot be used, if any
+    pub(crate) fn check(&self) -> Option<String> {
+        if self.timeout_seconds == 0 {
+            return Some("timeout_seconds must be greater than 0".to_string());
+        }
+        if let Some(auth) = &self.auth {
+            match (auth.backend, &auth.url) {
+                (AuthBackend::Http, None) => {
+                    return Some("the http auth backend requires a url".to_string());
+                }
+                (AuthBackend::File | AuthBackend::Htpasswd, Some(_)) => {
+                    return Some(format!(
+                        "url is only used by the http auth backend, not {}",
+                        auth.backend
+                    ));
+                }
+                _ => {}
+            }
+        }
+        if self.allow_socks4_userid && self.auth.is_none() {
+            return Some("allow_socks4_userid requires auth".to_string());
+        }
+        if let Some(tls) = &self.tls
+            && tls.client_crl.is_some()
+            && tls.client_ca.is_none()
+        {
+            return Some("client_crl requires client_ca".to_string());
+        }
+        // An empty list would trust every peer
+        match (self.proxy_protocol, self.trusted_proxies.is_empty()) {
+            (true, true) => return Some("proxy_protocol requires trusted_proxies".to_string()),
+            (false, false) => return Some("trusted_proxies requires proxy_protocol".to_string()),
+            _ => {}
+        }
+        if let Some(entry) = self
+            .trusted_proxies
+            .iter()
+            .find(|entry| AllowedIPs::new(entry).is_err())
+        {
+            return Some(format!(
+                "invalid trusted proxy address or CIDR range '{}'",
+                entry
+            ));
+        }
+        self.allowed_ips
+            .iter()
+            .find(|entry| AllowedIPs::new(entry).is_err())
+            .map(|entry| format!("invalid allowed IP address or CIDR range '{}'", entry))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default, rename = "listener")]
+    listeners: Vec<ListenerSettings>,
+}
+
+fn default_auth_backend() -> AuthBackend {
+    AuthBackend::File
+}
+
+fn default_auth_file() -> String {
+    "auth.txt".to_string()
+}
+
+fn default_client_user() -> ClientUser {
+    ClientUser::Cn
+}
+
+fn default_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_idle_timeout_seconds() -> u64 {
+    300
+}
+
+// Listeners of a TOML file with one [[listener]] table per listener
+pub async fn load(path: &Path) -> Result<Vec<ListenerSettings>> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .context(format!("Failed to read config file: {:?}", path))?;
+    let invalid = |reason: String| ProxyError::ConfigError(format!("{:?}: {}", path, reason));
+
+    let config: ConfigFile = toml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+    let mut listeners = config.listeners;
+    if listeners.is_empty() {
+        return Err(invalid(
+            "no listeners defined, add at least one [[listener]] table".to_string(),
+        )
+        .into());
+    }
+
+    let mut names = HashSet::new();
+    let mut binds = HashSet::new();
+    for (index, listener) in listeners.iter_mut().enumerate() {
+        if listener.name.is_empty() {
+            listener.name = listener.bind.to_string();
+        }
+        let describe = format!("listener {} ('{}')", index + 1, listener.name);
+
+        if let Some(reason) = listener.check() {
+            return Err(invalid(format!("{}: {}", describe, reason)).into());
+        }
+        if !binds.insert(listener.bind) {
+            return Err(invalid(format!(
+                "{}: {} is already used by another listener",
+                describe, listener.bind
+            ))
+            .into());
+        }
+        if !names.insert(listener.name.clone()) {
+            return Err(invalid(format!("{}: duplicate listener name", describe)).into());
+        }
+    }
+
+    Ok(listeners)
+}
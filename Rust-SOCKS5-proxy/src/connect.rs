@@ -0,0 +1,303 @@
+// Outbound connections: name resolution, egress route and access lists
+use crate::context::ServerContext;
+use crate::dns::Resolver;
+use crate::request::Address;
+use crate::rules::Route;
+use crate::sessions::Session;
+use crate::{
+    ProxyError, REPLY_CONNECTION_NOT_ALLOWED, REPLY_CONNECTION_REFUSED, REPLY_GENERAL_FAILURE,
+    REPLY_HOST_UNREACHABLE, REPLY_NETWORK_UNREACHABLE, happy_eyeballs,
+};
+use log::{debug, info};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+pub type ConnectFuture<'a> = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send + 'a>>;
+
+// Source addresses outbound connections are bound to, one per address family; without
+// one the host's default route picks the source
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Egress {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl Egress {
+    // Source address for connections to ip
+    pub fn source_for(&self, ip: IpAddr) -> Option<IpAddr> {
+        match ip {
+            IpAddr::V4(_) => self.ipv4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.ipv6.map(IpAddr::V6),
+        }
+    }
+
+    // Fill the families not set here from other
+    pub(crate) fn or(self, other: Egress) -> Egress {
+        Egress {
+            ipv4: self.ipv4.or(other.ipv4),
+            ipv6: self.ipv6.or(other.ipv6),
+        }
+    }
+}
+
+impl std::fmt::Display for Egress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ips: Vec<String> = [self.ipv4.map(IpAddr::V4), self.ipv6.map(IpAddr::V6)]
+            .into_iter()
+            .flatten()
+            .map(|ip| ip.to_string())
+            .collect();
+        write!(f, "{}", ips.join(", "))
+    }
+}
+
+// Comma-separated addresses, at most one IPv4 and one IPv6 address
+impl FromStr for Egress {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut egress = Egress::default();
+        for entry in value.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let duplicate = match entry.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => egress.ipv4.replace(ip).is_some(),
+                Ok(IpAddr::V6(ip)) => egress.ipv6.replace(ip).is_some(),
+                Err(_) => return Err(format!("Invalid egress address: {}", entry)),
+            };
+            if duplicate {
+                return Err(format!(
+                    "More than one egress address of the same family: {}",
+                    value
+                ));
+            }
+        }
+        if egress == Egress::default() {
+            return Err(format!("No egress address: {}", value));
+        }
+        Ok(egress)
+    }
+}
+
+// Opens the TCP connections of direct routes; upstream proxies connect on their own
+pub trait Connector: Send + Sync {
+    // Connect to one of the addresses of a destination, which are in preference order,
+    // from the egress address of its family
+    fn connect<'a>(
+        &'a self,
+        addresses: &'a [IpAddr],
+        port: u16,
+        egress: Egress,
+    ) -> ConnectFuture<'a>;
+}
+
+// Default connector, tries the addresses with Happy Eyeballs
+pub struct DirectConnector;
+
+impl Connector for DirectConnector {
+    fn connect<'a>(
+        &'a self,
+        addresses: &'a [IpAddr],
+        port: u16,
+        egress: Egress,
+    ) -> ConnectFuture<'a> {
+        Box::pin(happy_eyeballs::connect(addresses.to_vec(), port, egress))
+    }
+}
+
+// Target address information; a domain may resolve to several addresses
+pub(crate) struct TargetAddress {
+    pub(crate) ips: Vec<IpAddr>,
+    pub(crate) domain: Option<String>,
+    pub(crate) port: u16,
+}
+
+impl std::fmt::Display for TargetAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ips: Vec<String> = self.ips.iter().map(IpAddr::to_string).collect();
+        match &self.domain {
+            Some(domain) => write!(f, "{}:{} ({})", domain, self.port, ips.join(", ")),
+            None => write!(f, "{}:{}", ips.join(", "), self.port),
+        }
+    }
+}
+
+// Resolve a requested address to a target, asking the resolver for domain names
+pub(crate) async fn resolve_address(
+    address: Address,
+    resolver: &dyn Resolver,
+) -> Result<TargetAddress, ProxyError> {
+    let (domain_str, port) = match address {
+        Address::Ip(socket_addr) => {
+            return Ok(TargetAddress {
+                ips: vec![socket_addr.ip()],
+                domain: None,
+                port: socket_addr.port(),
+            });
+        }
+        Address::Domain(domain_str, port) => (domain_str, port),
+    };
+    debug!("Resolving domain: {}", domain_str);
+
+    let ips = resolver.resolve(&domain_str).await?;
+    Ok(TargetAddress {
+        ips,
+        domain: Some(domain_str),
+        port,
+    })
+}
+
+// Open the outbound connection for a CONNECT request along its egress route; failures
+// carry the SOCKS5 reply code that describes them
+pub(crate) async fn connect_outbound(
+    session: &Session,
+    address: Address,
+    context: &ServerContext,
+) -> Result<(TcpStream, String), (u8, ProxyError)> {
+    let addr = session.client;
+    let user = session.user();
+
+    // A user whose quota ran out may not open new connections
+    if context.quota_exhausted(user) {
+        return Err((
+            REPLY_CONNECTION_NOT_ALLOWED,
+            ProxyError::ConnectionFailed(format!(
+                "Transfer quota of user {} exhausted",
+                user.unwrap_or_default()
+            )),
+        ));
+    }
+
+    // Select the egress route for the destination
+    let route = match &context.router {
+        Some(router) => router.route(&address, context.resolver.as_ref()).await,
+        None => Route::Direct(Egress::default()),
+    };
+    let acl = context.destination_acl(user);
+
+    let route_name = match &route {
+        Route::Direct(_) => "direct".to_string(),
+        Route::Upstream(upstream) => upstream.name.clone(),
+        Route::Reject => "reject".to_string(),
+    };
+    session.details().route = Some(route_name.clone());
+    let direct = matches!(route, Route::Direct(_));
+
+    let (target_info, connect): (String, Pin<Box<dyn Future<Output = _> + Send + '_>>) = match route
+    {
+        Route::Direct(rule_egress) => {
+            // Resolve target address
+            let mut target_addr = resolve_address(address.clone(), context.resolver.as_ref())
+                .await
+                .map_err(|e| (REPLY_HOST_UNREACHABLE, e))?;
+
+            // The ACL sees the IPs that are actually connected to
+            if let Some(acl) = acl {
+                let domain = target_addr.domain.clone();
+                target_addr
+                    .ips
+                    .retain(|ip| acl.is_allowed(domain.as_deref(), Some(*ip), target_addr.port));
+                if target_addr.ips.is_empty() {
+                    return Err(access_denied(&address));
+                }
+            }
+
+            // A rule's egress address takes precedence over the user's and the global one
+            let egress = rule_egress.or(context.egress(user));
+            let target_info = target_addr.to_string();
+            let connector = Arc::clone(&context.connector);
+            let connect = async move {
+                connector
+                    .connect(&target_addr.ips, target_addr.port, egress)
+                    .await
+            };
+            (target_info, Box::pin(connect))
+        }
+        Route::Upstream(upstream) => {
+            // The upstream resolves domains itself, the local resolution only serves the ACL.
+            // Which address the upstream picks is unknown, so every one must be allowed,
+            // and a domain that does not resolve here is denied
+            if let Some(acl) = acl {
+                let ips = match &address {
+                    Address::Ip(socket_addr) => vec![Some(socket_addr.ip())],
+                    Address::Domain(..) if acl.needs_ip() => {
+                        match resolve_address(address.clone(), context.resolver.as_ref()).await {
+                            Ok(target_addr) if !target_addr.ips.is_empty() => {
+                                target_addr.ips.into_iter().map(Some).collect()
+                            }
+                            _ => return Err(access_denied(&address)),
+                        }
+                    }
+                    Address::Domain(..) => vec![None],
+                };
+                if !ips
+                    .into_iter()
+                    .all(|ip| acl.is_allowed(address.domain(), ip, address.port()))
+                {
+                    return Err(access_denied(&address));
+                }
+            }
+
+            let target_info = format!("{} via upstream {}", address, upstream.name);
+            let connect = async move { upstream.connect(&address).await };
+            (target_info, Box::pin(connect))
+        }
+        Route::Reject => {
+            return Err((
+                REPLY_CONNECTION_NOT_ALLOWED,
+                ProxyError::ConnectionFailed(format!("Connection to {} rejected by rule", address)),
+            ));
+        }
+    };
+
+    // Log remote server connection information
+    info!("{} connecting to {}", addr, target_info);
+
+    // Connect to remote server (with timeout)
+    let connect_start = Instant::now();
+    match timeout(context.timeout_duration, connect).await {
+        Ok(Ok(stream)) => {
+            let latency = connect_start.elapsed();
+            context.metrics.record_connect_latency(&route_name, latency);
+            // Through an upstream the address it connected to stays unknown
+            let mut details = session.details();
+            details.connect_latency = Some(latency);
+            if direct {
+                details.resolved_ip = stream.peer_addr().ok().map(|peer| peer.ip());
+            }
+            drop(details);
+            info!("{} connected to {}", addr, target_info);
+            Ok((stream, target_info))
+        }
+        Ok(Err(e)) => {
+            let reply_code = match e.kind() {
+                io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+                io::ErrorKind::PermissionDenied => REPLY_CONNECTION_NOT_ALLOWED,
+                io::ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+                io::ErrorKind::HostUnreachable => REPLY_HOST_UNREACHABLE,
+                io::ErrorKind::TimedOut => REPLY_HOST_UNREACHABLE,
+                // Includes egress addresses missing on the host
+                _ => REPLY_GENERAL_FAILURE,
+            };
+            Err((
+                reply_code,
+                ProxyError::ConnectionFailed(format!("Failed to connect to remote: {}", e)),
+            ))
+        }
+        Err(_) => Err((
+            REPLY_HOST_UNREACHABLE,
+            ProxyError::Timeout(format!("Connection timeout to {}", target_info)),
+        )),
+    }
+}
+
+fn access_denied(target: &dyn std::fmt::Display) -> (u8, ProxyError) {
+    (
+        REPLY_CONNECTION_NOT_ALLOWED,
+        ProxyError::ConnectionFailed(format!("Connection to {} denied by access list", target)),
+    )
+}
@@ -0,0 +1,180 @@
+// Per-listener state shared by the connection handlers
+use crate::ProxyError;
+use crate::access_log::AccessLog;
+use crate::acl::DestinationAcl;
+use crate::admission::{Admission, AdmissionPermit};
+use crate::auth::{Authenticator, Users};
+use crate::connect::{Connector, Egress};
+use crate::dns::Resolver;
+use crate::limits::{Limits, UserLimiter};
+use crate::metrics::Metrics;
+use crate::relay::RelaySettings;
+use crate::rules::Router;
+use crate::sessions::Session;
+use crate::throttle::{AuthThrottle, IpLimiter};
+use ipnet::IpNet;
+use log::{error, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::TlsAcceptor;
+
+// Shared state handed to every client handler
+#[derive(Clone)]
+pub(crate) struct ServerContext {
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) users: Option<Arc<Users>>,
+    pub(crate) allowed_ips: Option<Arc<AllowedIPs>>,
+    pub(crate) tls_acceptor: Option<TlsAcceptor>,
+    // Peers whose PROXY protocol headers are read, when enabled on the listener
+    pub(crate) trusted_proxies: Option<Arc<AllowedIPs>>,
+    // SOCKS4 clients may log in with a bare USERID
+    pub(crate) allow_socks4_userid: bool,
+    pub(crate) timeout_duration: Duration,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) relay: RelaySettings,
+    pub(crate) resolver: Arc<dyn Resolver>,
+    pub(crate) connector: Arc<dyn Connector>,
+    pub(crate) egress: Egress,
+    pub(crate) router: Option<Arc<Router>>,
+    pub(crate) destination_acl: Option<Arc<DestinationAcl>>,
+    pub(crate) limits: Option<Arc<Limits>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) access_log: Option<Arc<AccessLog>>,
+    pub(crate) auth_throttle: Option<Arc<AuthThrottle>>,
+    pub(crate) ip_limiter: Option<Arc<IpLimiter>>,
+    pub(crate) admission: Arc<Admission>,
+}
+
+impl ServerContext {
+    // Verify credentials with the configured backend; backend errors reject the client
+    pub(crate) async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        client: SocketAddr,
+    ) -> bool {
+        let Some(authenticator) = &self.authenticator else {
+            return false;
+        };
+        if self.auth_blocked(username, client) {
+            return false;
+        }
+        let authenticated = match authenticator.authenticate(username, password, client).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
+                error!("Authentication backend error for {}: {:#}", client, e);
+                false
+            }
+        };
+        self.record_auth(username, client, authenticated);
+        authenticated
+    }
+
+    // Blocked clients and usernames are rejected without asking the backend
+    pub(crate) fn auth_blocked(&self, username: &str, client: SocketAddr) -> bool {
+        let Some(remaining) = self
+            .auth_throttle
+            .as_ref()
+            .and_then(|throttle| throttle.blocked(client.ip(), username))
+        else {
+            return false;
+        };
+        warn!(
+            "Authentication of {} as {} refused, blocked for another {}s",
+            client,
+            username,
+            remaining.as_secs() + 1
+        );
+        self.metrics.record_auth(false);
+        true
+    }
+
+    pub(crate) fn record_auth(&self, username: &str, client: SocketAddr, success: bool) {
+        self.metrics.record_auth(success);
+        if let Some(throttle) = &self.auth_throttle {
+            match success {
+                true => throttle.record_success(client.ip(), username),
+                false => throttle.record_failure(client.ip(), username),
+            }
+        }
+    }
+
+    // The user's own access list takes precedence over the global one
+    pub(crate) fn destination_acl(&self, user: Option<&str>) -> Option<&DestinationAcl> {
+        user.and_then(|user| self.users.as_ref()?.settings.get(user))
+            .and_then(|settings| settings.destination_acl.as_deref())
+            .or(self.destination_acl.as_deref())
+    }
+
+    // Egress addresses of direct connections; a user's addresses override the global ones
+    // of the same family
+    pub(crate) fn egress(&self, user: Option<&str>) -> Egress {
+        user.and_then(|user| self.users.as_ref()?.settings.get(user))
+            .map_or(Egress::default(), |settings| settings.egress)
+            .or(self.egress)
+    }
+
+    // Session slot for a client whose request has been read, the caller replies with a
+    // failure when it is turned away
+    pub(crate) async fn admit(&self, session: &Session) -> Result<AdmissionPermit, ProxyError> {
+        let user = session.user();
+        let user_limit = user
+            .and_then(|user| self.users.as_ref()?.settings.get(user))
+            .and_then(|settings| settings.max_connections);
+        self.admission
+            .admit(user, user_limit)
+            .await
+            .map_err(|rejection| {
+                warn!("Session from {} turned away: {}", session.client, rejection);
+                ProxyError::Overloaded(rejection.to_string())
+            })
+    }
+
+    // Bandwidth and quota limiter for a session, None when it is unlimited
+    pub(crate) fn limiter(&self, user: Option<&str>) -> Option<Arc<UserLimiter>> {
+        let settings = user
+            .and_then(|user| self.users.as_ref()?.settings.get(user))
+            .map(|settings| &settings.limits);
+        self.limits.as_ref()?.limiter(user, settings)
+    }
+
+    pub(crate) fn quota_exhausted(&self, user: Option<&str>) -> bool {
+        user.is_some()
+            && self
+                .limiter(user)
+                .is_some_and(|limiter| limiter.is_exhausted())
+    }
+}
+
+// IP allow list
+pub(crate) struct AllowedIPs {
+    pub(crate) networks: Vec<IpNet>,
+}
+
+impl AllowedIPs {
+    pub(crate) fn new(allowed_ips: &str) -> Result<Self, ipnet::AddrParseError> {
+        let mut networks = Vec::new();
+
+        for ip_str in allowed_ips.split(',') {
+            let ip_str = ip_str.trim();
+            if !ip_str.is_empty() {
+                // Bare addresses are taken as single-host networks
+                let network = IpNet::from_str(ip_str)
+                    .or_else(|e| IpAddr::from_str(ip_str).map(IpNet::from).map_err(|_| e))?;
+                networks.push(network);
+            }
+        }
+
+        Ok(AllowedIPs { networks })
+    }
+
+    pub(crate) fn is_allowed(&self, addr: &IpAddr) -> bool {
+        if self.networks.is_empty() {
+            return true; // Allow all IPs if the list is empty
+        }
+
+        self.networks.iter().any(|network| network.contains(addr))
+    }
+}
@@ -0,0 +1,155 @@
+// DNS resolution with a cache of all A/AAAA records and of negative answers
+use crate::ProxyError;
+use anyhow::{Context, Result};
+use hickory_resolver::TokioResolver;
+use hickory_resolver::net::{DnsError, NetError};
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+// Upper bound for a single lookup
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a negative answer without an SOA record is cached
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+// Expired entries are swept once the cache holds this many domains
+const SWEEP_THRESHOLD: usize = 4096;
+
+pub type ResolveFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, ProxyError>> + Send + 'a>>;
+
+// Turns the domain names clients request into addresses
+pub trait Resolver: Send + Sync {
+    // All addresses of a domain, in the order they should be tried
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a>;
+}
+
+// Cached answer for a domain, no addresses means the domain does not resolve
+struct Entry {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+pub(crate) struct DnsCache {
+    resolver: TokioResolver,
+    cache: Mutex<HashMap<String, Entry>>,
+    max_ttl: Duration,
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+}
+
+impl DnsCache {
+    // Answers are kept for their record TTL, at most max_ttl_seconds (0 disables the cache)
+    pub(crate) fn new(max_ttl_seconds: u64) -> Result<Self> {
+        let mut builder =
+            TokioResolver::builder_tokio().context("Failed to read system DNS configuration")?;
+        // Answers are cached here, the resolver's own cache would only duplicate them
+        builder.options_mut().cache_size = 0;
+        let resolver = builder.build().context("Failed to create DNS resolver")?;
+
+        Ok(DnsCache {
+            resolver,
+            cache: Mutex::new(HashMap::new()),
+            max_ttl: Duration::from_secs(max_ttl_seconds),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    // All addresses of a domain, AAAA records before A records
+    pub(crate) async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, ProxyError> {
+        if let Some(addresses) = self.get(domain) {
+            debug!("DNS cache hit for {}: {:?}", domain, addresses);
+            return if addresses.is_empty() {
+                Err(not_found(domain))
+            } else {
+                Ok(addresses)
+            };
+        }
+
+        match timeout(LOOKUP_TIMEOUT, self.resolver.lookup_ip(domain)).await {
+            Ok(Ok(lookup)) => {
+                let addresses: Vec<IpAddr> = lookup.iter().collect();
+                debug!("Resolved {} to {:?}", domain, addresses);
+                let ttl = lookup
+                    .valid_until()
+                    .saturating_duration_since(Instant::now());
+                self.set(domain, addresses.clone(), ttl);
+                if addresses.is_empty() {
+                    Err(not_found(domain))
+                } else {
+                    Ok(addresses)
+                }
+            }
+            Ok(Err(NetError::Dns(DnsError::NoRecordsFound(no_records)))) => {
+                let ttl = no_records
+                    .negative_ttl
+                    .map_or(DEFAULT_NEGATIVE_TTL, |ttl| Duration::from_secs(ttl.into()));
+                self.set(domain, Vec::new(), ttl);
+                Err(not_found(domain))
+            }
+            Ok(Err(e)) => Err(ProxyError::ConnectionFailed(format!(
+                "Domain resolution error: {}",
+                e
+            ))),
+            Err(_) => Err(ProxyError::Timeout(format!(
+                "Domain resolution timeout: {}",
+                domain
+            ))),
+        }
+    }
+
+    fn get(&self, domain: &str) -> Option<Vec<IpAddr>> {
+        if self.max_ttl.is_zero() {
+            return None; // Cache disabled
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(entry) = cache.get(domain) {
+            if entry.expires > Instant::now() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.addresses.clone());
+            }
+            // TTL expired, remove entry
+            cache.remove(domain);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    fn set(&self, domain: &str, addresses: Vec<IpAddr>, ttl: Duration) {
+        let ttl = ttl.min(self.max_ttl);
+        if ttl.is_zero() {
+            return; // Cache disabled or nothing worth caching
+        }
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= SWEEP_THRESHOLD {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        cache.insert(
+            domain.to_string(),
+            Entry {
+                addresses,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+impl Resolver for DnsCache {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+        Box::pin(DnsCache::resolve(self, domain))
+    }
+}
+
+fn not_found(domain: &str) -> ProxyError {
+    ProxyError::ConnectionFailed(format!("Domain resolution failed: {}", domain))
+}
@@ -0,0 +1,355 @@
+// Listening sockets passed in by systemd socket activation or by the process being upgraded,
+// and the SIGUSR2 re-exec that hands them over to a new process
+#[cfg(target_os = "linux")]
+use crate::ProxyError;
+use anyhow::{Context, Result};
+use log::{info, warn};
+#[cfg(target_os = "linux")]
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+#[cfg(target_os = "linux")]
+use tokio::sync::broadcast;
+#[cfg(target_os = "linux")]
+use tokio::time::timeout;
+
+// First descriptor passed by systemd (SD_LISTEN_FDS_START)
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: RawFd = 3;
+
+// Descriptors of the sockets handed over by an upgrading process
+#[cfg(target_os = "linux")]
+const HANDOFF_FDS: &str = "RUST_SOCKS5_PROXY_FDS";
+
+// Pipe the new process writes to once its listeners are set up
+#[cfg(target_os = "linux")]
+const READY_FD: &str = "RUST_SOCKS5_PROXY_READY_FD";
+
+// Longest time a new process may take to get ready
+#[cfg(target_os = "linux")]
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Sockets inherited at startup, taken by address as the listeners are set up
+#[derive(Default)]
+pub(crate) struct Inherited {
+    sockets: Vec<std::net::TcpListener>,
+}
+
+impl Inherited {
+    // Sockets of systemd socket activation (LISTEN_FDS) or of an upgrade
+    #[cfg(target_os = "linux")]
+    pub(crate) fn from_env() -> Result<Self> {
+        Self::from_vars(|name| std::env::var(name).ok(), std::process::id())
+    }
+
+    // The same from the variables var returns, for the process pid
+    #[cfg(target_os = "linux")]
+    fn from_vars(var: impl Fn(&str) -> Option<String>, pid: u32) -> Result<Self> {
+        let fds: Vec<RawFd> = match (var(HANDOFF_FDS), var("LISTEN_PID"), var("LISTEN_FDS")) {
+            (Some(fds), _, _) => fds
+                .split(',')
+                .map(|fd| fd.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid(&format!("{}={}", HANDOFF_FDS, fds)))?,
+            // The variables may have been meant for a parent process
+            (None, Some(listen_pid), Some(count)) if listen_pid.parse() == Ok(pid) => {
+                let count: RawFd = count
+                    .parse()
+                    .map_err(|_| invalid(&format!("LISTEN_FDS={}", count)))?;
+                (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+            }
+            _ => Vec::new(),
+        };
+
+        let sockets = fds
+            .into_iter()
+            .map(listener_from_fd)
+            .collect::<Result<_>>()?;
+        Ok(Inherited { sockets })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn from_env() -> Result<Self> {
+        Ok(Inherited::default())
+    }
+
+    // The inherited socket bound to addr, if any
+    pub(crate) fn take(&mut self, addr: SocketAddr) -> Result<Option<TcpListener>> {
+        let Some(index) = self
+            .sockets
+            .iter()
+            .position(|socket| socket.local_addr().is_ok_and(|local| local == addr))
+        else {
+            return Ok(None);
+        };
+        let socket = self.sockets.swap_remove(index);
+        info!("Using inherited socket for {}", addr);
+        Ok(Some(
+            TcpListener::from_std(socket).context("Failed to register inherited socket")?,
+        ))
+    }
+
+    // Close the sockets no listener asked for
+    pub(crate) fn close_unused(self) {
+        for socket in self.sockets {
+            match socket.local_addr() {
+                Ok(addr) => warn!(
+                    "Closing inherited socket {}, no listener is bound to it",
+                    addr
+                ),
+                Err(_) => warn!("Closing unused inherited socket"),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn invalid(what: &str) -> anyhow::Error {
+    ProxyError::ConfigError(format!("Invalid inherited sockets: {}", what)).into()
+}
+
+// Take ownership of fd once it is known to be a listening TCP socket
+#[cfg(target_os = "linux")]
+fn listener_from_fd(fd: RawFd) -> Result<std::net::TcpListener> {
+    let option = |name| {
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len are valid for the duration of the call
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                (&raw mut value).cast(),
+                &mut len,
+            )
+        };
+        (result == 0).then_some(value)
+    };
+    if option(libc::SO_TYPE) != Some(libc::SOCK_STREAM) || option(libc::SO_ACCEPTCONN) != Some(1) {
+        return Err(invalid(&format!(
+            "descriptor {} is not a listening TCP socket",
+            fd
+        )));
+    }
+
+    // SAFETY: the descriptor is an open socket that nothing else in this process owns
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // Not passed on to processes started later, except by an upgrade
+    set_inheritable(fd, false).context("Failed to set close-on-exec")?;
+    listener
+        .set_nonblocking(true)
+        .context("Failed to make inherited socket non-blocking")?;
+    Ok(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn set_inheritable(fd: RawFd, inheritable: bool) -> io::Result<()> {
+    let flags = if inheritable { 0 } else { libc::FD_CLOEXEC };
+    // SAFETY: F_SETFD only changes the descriptor flags
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Tell the process that started this one that the listeners are set up
+#[cfg(target_os = "linux")]
+pub(crate) fn notify_ready() {
+    let Some(fd) = std::env::var(READY_FD)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    // SAFETY: F_GETFD only checks that the descriptor is open
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return;
+    }
+    // SAFETY: the descriptor is the write end of the pipe the parent passed in
+    let mut pipe = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    if let Err(e) = std::io::Write::write_all(&mut pipe, b"1") {
+        warn!("Failed to notify the previous process: {}", e);
+    }
+}
+
+// Stop accepting and let the sessions finish until the drain deadline, after which the
+// remaining ones are closed
+#[cfg(target_os = "linux")]
+pub(crate) async fn drain(
+    drain_timeout: Duration,
+    stop_tx: &broadcast::Sender<()>,
+    shutdown_tx: &broadcast::Sender<()>,
+) {
+    let mut shutdown_rx = shutdown_tx.subscribe();
+    let _ = stop_tx.send(());
+    tokio::select! {
+        _ = tokio::time::sleep(drain_timeout) => {
+            info!("Drain deadline reached, closing the remaining sessions");
+            let _ = shutdown_tx.send(());
+        }
+        _ = shutdown_rx.recv() => {}
+    }
+}
+
+// Start the current executable with the same arguments and the listening sockets, and
+// wait until it is ready to serve; returns its process ID. On error the sockets stay
+// with this process only
+#[cfg(target_os = "linux")]
+pub(crate) async fn spawn_successor(fds: &[RawFd]) -> Result<u32> {
+    // The binary now installed at the path this one was started from, which Linux marks
+    // as deleted once it has been replaced
+    let exe = std::env::current_exe().context("Failed to locate the executable")?;
+    let exe = match exe
+        .to_str()
+        .and_then(|path| path.strip_suffix(" (deleted)"))
+    {
+        Some(path) => std::path::PathBuf::from(path),
+        None => exe,
+    };
+
+    let mut pipe = [0 as RawFd; 2];
+    // SAFETY: pipe points to two descriptors
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error()).context("Failed to create readiness pipe");
+    }
+    // SAFETY: both descriptors were just created and are owned here
+    let (ready, notify) = unsafe { (OwnedFd::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])) };
+
+    let list: Vec<String> = fds.iter().map(RawFd::to_string).collect();
+    let mut inherit = fds.to_vec();
+    inherit.push(pipe[1]);
+    let mut command = tokio::process::Command::new(&exe);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(HANDOFF_FDS, list.join(","))
+        .env(READY_FD, pipe[1].to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES");
+    // SAFETY: only async-signal-safe fcntl calls run between fork and exec
+    unsafe {
+        command.pre_exec(move || inherit.iter().try_for_each(|fd| set_inheritable(*fd, true)));
+    }
+    let mut child = command
+        .spawn()
+        .context(format!("Failed to start {:?}", exe))?;
+    drop(notify);
+
+    let mut ready = tokio::fs::File::from_std(std::fs::File::from(ready));
+    let mut byte = [0u8; 1];
+    match timeout(START_TIMEOUT, ready.read(&mut byte)).await {
+        Ok(Ok(1)) => Ok(child.id().unwrap_or_default()),
+        Ok(_) => {
+            let status = child.wait().await.ok();
+            Err(anyhow::anyhow!(
+                "New process exited before it was ready ({})",
+                status.map_or("unknown status".to_string(), |status| status.to_string())
+            ))
+        }
+        Err(_) => {
+            let _ = child.start_kill();
+            Err(anyhow::anyhow!(
+                "New process was not ready within {} seconds",
+                START_TIMEOUT.as_secs()
+            ))
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::fd::{AsRawFd, IntoRawFd};
+
+    fn vars<'a>(pairs: &'a [(&'a str, String)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |name| {
+            pairs
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn handed_over_socket_is_taken_by_address() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let fd = socket.into_raw_fd();
+
+        let pairs = [(HANDOFF_FDS, fd.to_string())];
+        let mut inherited = Inherited::from_vars(vars(&pairs), std::process::id()).unwrap();
+        let other = SocketAddr::from(([127, 0, 0, 1], addr.port().wrapping_add(1)));
+        assert!(inherited.take(other).unwrap().is_none());
+        let listener = inherited.take(addr).unwrap().unwrap();
+        assert!(inherited.take(addr).unwrap().is_none());
+
+        let client = tokio::net::TcpStream::connect(addr);
+        let (accepted, client) = tokio::join!(listener.accept(), client);
+        client.unwrap();
+        accepted.unwrap();
+    }
+
+    #[test]
+    fn descriptors_that_are_not_listening_sockets_are_refused() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let pairs = [(HANDOFF_FDS, socket.as_raw_fd().to_string())];
+        assert!(Inherited::from_vars(vars(&pairs), std::process::id()).is_err());
+
+        let pairs = [(HANDOFF_FDS, "3,x".to_string())];
+        assert!(Inherited::from_vars(vars(&pairs), std::process::id()).is_err());
+    }
+
+    #[test]
+    fn listen_fds_of_another_process_are_ignored() {
+        // Descriptor 3 is not touched, it belongs to whatever the test harness opened
+        let pid = std::process::id();
+        let pairs = [
+            ("LISTEN_PID", (pid + 1).to_string()),
+            ("LISTEN_FDS", "1".to_string()),
+        ];
+        let inherited = Inherited::from_vars(vars(&pairs), pid).unwrap();
+        assert!(inherited.sockets.is_empty());
+
+        let pairs = [("LISTEN_FDS", "1".to_string())];
+        let inherited = Inherited::from_vars(vars(&pairs), pid).unwrap();
+        assert!(inherited.sockets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drain_closes_sessions_at_the_deadline() {
+        let (stop_tx, mut stop_rx) = broadcast::channel(1);
+        let (shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+
+        let started = std::time::Instant::now();
+        drain(Duration::from_millis(200), &stop_tx, &shutdown_tx).await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(stop_rx.try_recv().is_ok());
+        assert!(shutdown_rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn drain_ends_early_on_shutdown() {
+        let (stop_tx, mut stop_rx) = broadcast::channel(1);
+        let (shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+
+        let shutdown = shutdown_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = shutdown.send(());
+        });
+        let started = std::time::Instant::now();
+        drain(Duration::from_secs(300), &stop_tx, &shutdown_tx).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(stop_rx.try_recv().is_ok());
+        // Only the shutdown that ended the drain was sent
+        assert!(shutdown_rx.try_recv().is_ok());
+        assert!(shutdown_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+// SOCKS5 method negotiation and username/password sub-negotiation (RFC 1928, RFC 1929)
+use crate::{NO_AUTH_METHOD, ProxyError, SOCKS_VERSION, USER_PASS_AUTH_METHOD};
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Method selected when no offered method is acceptable
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+// Sub-negotiation version and status codes (RFC 1929)
+const USER_PASS_VERSION: u8 = 1;
+const USER_PASS_SUCCESS: u8 = 0;
+const USER_PASS_FAILURE: u8 = 1;
+
+// Which methods the server accepts from a client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthMode {
+    // No authentication
+    Disabled,
+    // Username and password
+    Required,
+    // No authentication if offered, otherwise username and password; for clients
+    // already identified by a certificate
+    Optional,
+}
+
+// Negotiate the method and authenticate the client. The version byte has already been
+// read by the caller. verify checks a username and password; the username is returned
+// when the client authenticated with one
+pub async fn negotiate<S, V, F>(stream: &mut S, mode: AuthMode, verify: V) -> Result<Option<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    V: FnOnce(String, String) -> F,
+    F: Future<Output = bool>,
+{
+    // NMETHODS, METHODS
+    let mut buf = [0u8; 1];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .context("Failed to read authentication header")?;
+
+    let method_count = buf[0] as usize;
+    let mut methods = vec![0u8; method_count];
+    stream
+        .read_exact(&mut methods)
+        .await
+        .context("Failed to read authentication methods")?;
+
+    let use_password = match mode {
+        AuthMode::Disabled => false,
+        AuthMode::Required => true,
+        AuthMode::Optional => !methods.contains(&NO_AUTH_METHOD),
+    };
+
+    if !use_password {
+        if !methods.contains(&NO_AUTH_METHOD) {
+            stream
+                .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])
+                .await
+                .context("Failed to send no-auth rejection")?;
+            return Err(anyhow::anyhow!("No supported authentication methods"));
+        }
+
+        stream
+            .write_all(&[SOCKS_VERSION, NO_AUTH_METHOD])
+            .await
+            .context("Failed to send no-auth acceptance")?;
+        return Ok(None);
+    }
+
+    if !methods.contains(&USER_PASS_AUTH_METHOD) {
+        stream
+            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])
+            .await
+            .context("Failed to send auth rejection")?;
+        return Err(ProxyError::AuthenticationRequired.into());
+    }
+
+    // Select user/password authentication method
+    stream
+        .write_all(&[SOCKS_VERSION, USER_PASS_AUTH_METHOD])
+        .await
+        .context("Failed to send auth method")?;
+
+    // VER, ULEN
+    let mut auth_header = [0u8; 2];
+    stream
+        .read_exact(&mut auth_header)
+        .await
+        .context("Failed to read auth header")?;
+
+    if auth_header[0] != USER_PASS_VERSION {
+        return Err(anyhow::anyhow!("Unsupported auth version"));
+    }
+
+    // Read username
+    let mut username = vec![0u8; auth_header[1] as usize];
+    stream
+        .read_exact(&mut username)
+        .await
+        .context("Failed to read username")?;
+    let username = String::from_utf8(username).context("Username is not valid UTF-8")?;
+
+    // Read password
+    let mut plen = [0u8; 1];
+    stream
+        .read_exact(&mut plen)
+        .await
+        .context("Failed to read password length")?;
+    let mut password = vec![0u8; plen[0] as usize];
+    stream
+        .read_exact(&mut password)
+        .await
+        .context("Failed to read password")?;
+    let password = String::from_utf8(password).context("Password is not valid UTF-8")?;
+
+    if verify(username.clone(), password).await {
+        stream
+            .write_all(&[USER_PASS_VERSION, USER_PASS_SUCCESS])
+            .await
+            .context("Failed to send auth success")?;
+        Ok(Some(username))
+    } else {
+        stream
+            .write_all(&[USER_PASS_VERSION, USER_PASS_FAILURE])
+            .await
+            .context("Failed to send auth failure")?;
+        Err(ProxyError::AuthenticationFailed.into())
+    }
+}
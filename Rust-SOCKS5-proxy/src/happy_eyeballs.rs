@@ -0,0 +1,101 @@
+// Outbound connections to hosts with several addresses (RFC 8305 Happy Eyeballs)
+use crate::connect::Egress;
+use log::{debug, error};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+
+// Time to wait for an attempt before starting the next one (RFC 8305, section 5)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Alternate between the address families, starting with the family of the first address
+fn interleave(addresses: &[IpAddr]) -> Vec<IpAddr> {
+    let Some(first) = addresses.first() else {
+        return Vec::new();
+    };
+    let (mut preferred, mut other): (Vec<IpAddr>, Vec<IpAddr>) = addresses
+        .iter()
+        .partition(|ip| ip.is_ipv6() == first.is_ipv6());
+    preferred.reverse();
+    other.reverse();
+
+    let mut ordered = Vec::with_capacity(addresses.len());
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// Connect to target, from source when given
+async fn connect_from(source: Option<IpAddr>, target: SocketAddr) -> io::Result<TcpStream> {
+    let Some(source) = source else {
+        return TcpStream::connect(target).await;
+    };
+    let socket = match source {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Err(e) = socket.bind(SocketAddr::new(source, 0)) {
+        error!("Failed to bind egress address {}: {}", source, e);
+        let message = if e.kind() == io::ErrorKind::AddrNotAvailable {
+            format!("egress address {} is not present on this host", source)
+        } else {
+            format!("failed to bind egress address {}: {}", source, e)
+        };
+        return Err(io::Error::new(e.kind(), message));
+    }
+    socket.connect(target).await
+}
+
+// Start an attempt, then another one every 250ms or as soon as one fails; the first
+// established connection wins and the other attempts are dropped
+pub(crate) async fn connect(
+    addresses: Vec<IpAddr>,
+    port: u16,
+    egress: Egress,
+) -> io::Result<TcpStream> {
+    let mut pending = interleave(&addresses).into_iter();
+    let mut attempts = JoinSet::new();
+    let start_next = |attempts: &mut JoinSet<_>, pending: &mut std::vec::IntoIter<IpAddr>| {
+        pending.next().map(|ip| {
+            let target = SocketAddr::new(ip, port);
+            let source = egress.source_for(ip);
+            attempts.spawn(async move { (target, connect_from(source, target).await) })
+        })
+    };
+
+    if start_next(&mut attempts, &mut pending).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No addresses to connect to",
+        ));
+    }
+
+    loop {
+        tokio::select! {
+            Some(finished) = attempts.join_next() => {
+                let (target, result) = finished.map_err(io::Error::other)?;
+                let e = match result {
+                    Ok(stream) => {
+                        debug!("Connection attempt to {} succeeded", target);
+                        return Ok(stream);
+                    }
+                    Err(e) => e,
+                };
+                debug!("Connection attempt to {} failed: {}", target, e);
+                // The last failure is reported once no attempt is left
+                if start_next(&mut attempts, &mut pending).is_none() && attempts.is_empty() {
+                    return Err(e);
+                }
+            }
+            _ = sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {
+                start_next(&mut attempts, &mut pending);
+            }
+        }
+    }
+}
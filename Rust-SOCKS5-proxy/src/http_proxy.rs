@@ -0,0 +1,350 @@
+// HTTP proxy (CONNECT tunnels and absolute-URI forwarding) sharing the SOCKS5 listener
+use crate::connect::connect_outbound;
+use crate::context::ServerContext;
+use crate::relay::relay;
+use crate::request::Address;
+use crate::sessions::Session;
+use crate::{ProxyError, REPLY_CONNECTION_NOT_ALLOWED};
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Upper bound for a request header and the number of header fields
+const MAX_HEADER_SIZE: usize = 16384;
+const MAX_HEADERS: usize = 64;
+
+// Hop-by-hop headers that are not forwarded to the origin server
+const HOP_BY_HOP_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+];
+
+pub(crate) async fn handle_http<T>(
+    mut socket: T,
+    first_byte: u8,
+    session: &Session,
+    context: &ServerContext,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let addr = session.client;
+
+    // Read the request header; bytes past it belong to the tunnel or the request body
+    let mut buf = vec![first_byte];
+    let header_len = loop {
+        if let Some(pos) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() >= MAX_HEADER_SIZE {
+            send_response(
+                &mut socket,
+                session,
+                431,
+                "Request Header Fields Too Large",
+                "",
+            )
+            .await?;
+            return Err(
+                ProxyError::NetworkError("HTTP request header too large".to_string()).into(),
+            );
+        }
+
+        let mut chunk = [0u8; 4096];
+        let n = socket
+            .read(&mut chunk)
+            .await
+            .context("Failed to read HTTP request header")?;
+        if n == 0 {
+            return Err(ProxyError::NetworkError(
+                "Connection closed during HTTP request header".to_string(),
+            )
+            .into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    if !matches!(
+        request.parse(&buf[..header_len]),
+        Ok(httparse::Status::Complete(_))
+    ) {
+        send_response(&mut socket, session, 400, "Bad Request", "").await?;
+        return Err(ProxyError::NetworkError("Malformed HTTP request".to_string()).into());
+    }
+
+    let method = request.method.unwrap_or_default().to_string();
+    let target = request.path.unwrap_or_default().to_string();
+    let version = request.version.unwrap_or(1);
+    let headers: Vec<(String, Vec<u8>)> = request
+        .headers
+        .iter()
+        .map(|header| (header.name.to_string(), header.value.to_vec()))
+        .collect();
+    let body = &buf[header_len..];
+
+    // Proxy authentication with the same credentials as SOCKS5; with a client certificate
+    // the header is optional but must name the certificate's user
+    let credentials = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|(_, value)| parse_basic_credentials(value));
+    let certified = session.user().is_some();
+    if context.authenticator.is_some() && !(certified && credentials.is_none()) {
+        let authenticated = match &credentials {
+            Some((username, password)) => {
+                context.authenticate(username, password, addr).await
+                    && session.user().is_none_or(|user| user == username)
+            }
+            None => false,
+        };
+
+        match credentials {
+            Some((username, _)) if authenticated => {
+                debug!("{} authenticated as {}", addr, username);
+                session.set_user(&username);
+            }
+            credentials => {
+                send_response(
+                    &mut socket,
+                    session,
+                    407,
+                    "Proxy Authentication Required",
+                    "Proxy-Authenticate: Basic realm=\"proxy\"\r\n",
+                )
+                .await?;
+                return Err(if credentials.is_some() {
+                    ProxyError::AuthenticationFailed
+                } else {
+                    ProxyError::AuthenticationRequired
+                }
+                .into());
+            }
+        }
+    }
+
+    let is_connect = method.eq_ignore_ascii_case("CONNECT");
+    let (address, forward_header) = if is_connect {
+        match parse_authority(&target, None) {
+            Some(address) => (address, None),
+            None => {
+                send_response(&mut socket, session, 400, "Bad Request", "").await?;
+                return Err(ProxyError::NetworkError(format!(
+                    "Invalid CONNECT target: {}",
+                    target
+                ))
+                .into());
+            }
+        }
+    } else {
+        // Only absolute-form requests are proxied, e.g. "GET http://host/path HTTP/1.1"
+        let Some((authority, path)) = split_absolute_uri(&target) else {
+            send_response(&mut socket, session, 400, "Bad Request", "").await?;
+            return Err(ProxyError::NetworkError(format!(
+                "Unsupported request target: {}",
+                target
+            ))
+            .into());
+        };
+        let Some(address) = parse_authority(authority, Some(80)) else {
+            send_response(&mut socket, session, 400, "Bad Request", "").await?;
+            return Err(
+                ProxyError::NetworkError(format!("Invalid request host: {}", authority)).into(),
+            );
+        };
+        let header = build_forward_header(&method, path, version, authority, &headers);
+        (address, Some(header))
+    };
+
+    // The session slot is held until the tunnel or the forwarded request is done
+    let _permit = match context.admit(session).await {
+        Ok(permit) => permit,
+        Err(e) => {
+            send_response(&mut socket, session, 503, "Service Unavailable", "").await?;
+            return Err(e.into());
+        }
+    };
+
+    session.record_request(if is_connect { "connect" } else { "forward" }, &address);
+    let (mut remote, target_info) = match connect_outbound(session, address, context).await {
+        Ok(connected) => connected,
+        Err((reply_code, e)) => {
+            let (status, reason) = match (&e, reply_code) {
+                (ProxyError::Timeout(_), _) => (504, "Gateway Timeout"),
+                (_, REPLY_CONNECTION_NOT_ALLOWED) => (403, "Forbidden"),
+                _ => (502, "Bad Gateway"),
+            };
+            send_response(&mut socket, session, status, reason, "").await?;
+            return Err(e.into());
+        }
+    };
+
+    let traffic = context.metrics.traffic(session.user());
+    match forward_header {
+        None => {
+            socket
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .context("Failed to send CONNECT response")?;
+            session.record_reply(200u16);
+        }
+        Some(header) => {
+            remote
+                .write_all(&header)
+                .await
+                .context("Failed to forward HTTP request header")?;
+            traffic.up.fetch_add(header.len() as u64, Ordering::Relaxed);
+            session
+                .bytes_up
+                .fetch_add(header.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    // Bytes the client sent right after the header (request body or early tunnel data)
+    if !body.is_empty() {
+        remote
+            .write_all(body)
+            .await
+            .context("Failed to forward buffered request data")?;
+        traffic.up.fetch_add(body.len() as u64, Ordering::Relaxed);
+        session
+            .bytes_up
+            .fetch_add(body.len() as u64, Ordering::Relaxed);
+    }
+
+    match relay(&mut socket, &mut remote, session, context).await {
+        Ok(transferred) => {
+            info!(
+                "{} closed connection to {} ({})",
+                addr, target_info, transferred
+            );
+            Ok(())
+        }
+        Err(e) => {
+            warn!("{} error with {} - {}", addr, target_info, e);
+            Err(ProxyError::NetworkError(format!("Data transfer error: {}", e)).into())
+        }
+    }
+}
+
+async fn send_response<T>(
+    socket: &mut T,
+    session: &Session,
+    status: u16,
+    reason: &str,
+    headers: &str,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    session.record_reply(status);
+    let response = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason, headers
+    );
+    socket
+        .write_all(response.as_bytes())
+        .await
+        .context("Failed to send HTTP response")?;
+    Ok(())
+}
+
+// Decode "Basic base64(user:pass)" from a Proxy-Authorization header
+fn parse_basic_credentials(value: &[u8]) -> Option<(String, String)> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(token.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+// Split "http://authority/path?query" into the authority and the origin-form target
+fn split_absolute_uri(target: &str) -> Option<(&str, &str)> {
+    let scheme_len = "http://".len();
+    if target.len() < scheme_len || !target[..scheme_len].eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let rest = &target[scheme_len..];
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, "/"),
+    };
+    // Drop userinfo, it is never sent to the origin server
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    Some((authority, path))
+}
+
+// Parse "host:port", "[v6]:port" or, with a default port, a bare host
+fn parse_authority(authority: &str, default_port: Option<u16>) -> Option<Address> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port.parse().ok()?,
+            None if rest.is_empty() => default_port?,
+            None => return None,
+        };
+        (host, port)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, default_port?),
+        }
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+    Some(match host.parse::<IpAddr>() {
+        Ok(ip) => Address::Ip(SocketAddr::new(ip, port)),
+        Err(_) if host.len() <= 255 => Address::Domain(host.to_string(), port),
+        Err(_) => return None,
+    })
+}
+
+// Rewrite the request line to origin-form and drop hop-by-hop headers; the upstream
+// connection carries a single request
+fn build_forward_header(
+    method: &str,
+    path: &str,
+    version: u8,
+    authority: &str,
+    headers: &[(String, Vec<u8>)],
+) -> Vec<u8> {
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+
+    let mut header = format!("{} {} HTTP/1.{}\r\n", method, path, version).into_bytes();
+    let mut has_host = false;
+    for (name, value) in headers {
+        let lower = name.to_ascii_lowercase();
+        if HOP_BY_HOP_HEADERS.contains(&lower.as_str()) {
+            continue;
+        }
+        has_host |= lower == "host";
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(b": ");
+        header.extend_from_slice(value);
+        header.extend_from_slice(b"\r\n");
+    }
+    if !has_host {
+        header.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+    }
+    header.extend_from_slice(b"Connection: close\r\n\r\n");
+    header
+}
@@ -0,0 +1,113 @@
+// SOCKS5/HTTP proxy server as a library; the binary in main.rs is a command-line front end
+mod access_log;
+mod acl;
+mod admin;
+mod admission;
+mod auth;
+mod bind;
+mod client_cert;
+pub mod config;
+mod connect;
+mod context;
+mod dns;
+mod handoff;
+pub mod handshake;
+mod happy_eyeballs;
+mod http_proxy;
+mod limits;
+mod metrics;
+mod proxy_protocol;
+mod relay;
+mod reload;
+pub mod request;
+mod rules;
+mod server;
+mod sessions;
+mod socks4;
+mod socks5;
+#[cfg(target_os = "linux")]
+mod splice;
+mod throttle;
+mod udp;
+mod upstream;
+
+pub use access_log::Rotation;
+pub use auth::{AuthFuture, Authenticator, HashAlgorithm, hash_password};
+pub use connect::{ConnectFuture, Connector, DirectConnector, Egress};
+pub use dns::{ResolveFuture, Resolver};
+pub use limits::{Quota, parse_size};
+pub use server::{Socks5Server, Socks5ServerBuilder};
+
+// SOCKS5 protocol constants
+pub(crate) const SOCKS_VERSION: u8 = 5;
+pub(crate) const CONNECT_COMMAND: u8 = 1;
+pub(crate) const BIND_COMMAND: u8 = 2;
+pub(crate) const UDP_ASSOCIATE_COMMAND: u8 = 3;
+pub(crate) const NO_AUTH_METHOD: u8 = 0;
+pub(crate) const USER_PASS_AUTH_METHOD: u8 = 2;
+pub(crate) const ADDR_TYPE_IPV4: u8 = 1;
+pub(crate) const ADDR_TYPE_DOMAIN: u8 = 3;
+pub(crate) const ADDR_TYPE_IPV6: u8 = 4;
+pub(crate) const REPLY_SUCCEEDED: u8 = 0;
+
+// SOCKS5 error reply codes (refer to RFC 1928)
+pub(crate) const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub(crate) const REPLY_CONNECTION_NOT_ALLOWED: u8 = 0x02;
+pub(crate) const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub(crate) const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub(crate) const REPLY_TTL_EXPIRED: u8 = 0x06; // TTL expired
+pub(crate) const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub(crate) const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+pub(crate) const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+
+// Errors of the proxy; anyhow errors carry one where the cause matters to the caller
+#[derive(Debug)]
+pub enum ProxyError {
+    InvalidVersion,
+    AuthenticationRequired,
+    AuthenticationFailed,
+    UnsupportedCommand,
+    UnsupportedAddressType,
+    ConnectionFailed(String),
+    NetworkError(String),
+    Timeout(String),
+    ConfigError(String),
+    Overloaded(String),
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::InvalidVersion => write!(f, "Unsupported SOCKS version"),
+            ProxyError::AuthenticationRequired => write!(f, "Authentication required"),
+            ProxyError::AuthenticationFailed => write!(f, "Authentication failed"),
+            ProxyError::UnsupportedCommand => write!(f, "Unsupported command"),
+            ProxyError::UnsupportedAddressType => write!(f, "Unsupported address type"),
+            ProxyError::ConnectionFailed(details) => write!(f, "Connection failed: {}", details),
+            ProxyError::NetworkError(details) => write!(f, "Network error: {}", details),
+            ProxyError::Timeout(details) => write!(f, "Timeout: {}", details),
+            ProxyError::ConfigError(details) => write!(f, "Configuration error: {}", details),
+            ProxyError::Overloaded(details) => write!(f, "Session not admitted: {}", details),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl ProxyError {
+    // Variant name used as a metrics label
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            ProxyError::InvalidVersion => "InvalidVersion",
+            ProxyError::AuthenticationRequired => "AuthenticationRequired",
+            ProxyError::AuthenticationFailed => "AuthenticationFailed",
+            ProxyError::UnsupportedCommand => "UnsupportedCommand",
+            ProxyError::UnsupportedAddressType => "UnsupportedAddressType",
+            ProxyError::ConnectionFailed(_) => "ConnectionFailed",
+            ProxyError::NetworkError(_) => "NetworkError",
+            ProxyError::Timeout(_) => "Timeout",
+            ProxyError::ConfigError(_) => "ConfigError",
+            ProxyError::Overloaded(_) => "Overloaded",
+        }
+    }
+}
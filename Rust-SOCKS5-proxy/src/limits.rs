@@ -0,0 +1,466 @@
+// Per-user bandwidth shaping and transfer quotas
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+// Parse a byte count with an optional K, M, G or T suffix (1024-based)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid size: {}", value);
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((pos, suffix)) if suffix.is_ascii_alphabetic() => {
+            let multiplier: u64 = match suffix.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(invalid()),
+            };
+            (&value[..pos], multiplier)
+        }
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    // Identifier of the current period in UTC, e.g. "2025-03-14" or "2025-03"
+    fn current(self) -> String {
+        let days = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() / 86400);
+        let (year, month, day) = civil_from_days(days);
+        match self {
+            QuotaPeriod::Daily => format!("{:04}-{:02}-{:02}", year, month, day),
+            QuotaPeriod::Monthly => format!("{:04}-{:02}", year, month),
+        }
+    }
+}
+
+// Bytes a user may transfer per period, 0 for unlimited
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    bytes: u64,
+    period: QuotaPeriod,
+}
+
+// Parse "<size>/day" or "<size>/month"; a bare "0" lifts the quota
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.trim() == "0" {
+            return Ok(Quota {
+                bytes: 0,
+                period: QuotaPeriod::Daily,
+            });
+        }
+
+        let (size, period) = value.split_once('/').ok_or_else(|| {
+            format!(
+                "Invalid quota: {} (expected <size>/day or <size>/month)",
+                value
+            )
+        })?;
+        let period = match period.trim().to_ascii_lowercase().as_str() {
+            "day" | "daily" => QuotaPeriod::Daily,
+            "month" | "monthly" => QuotaPeriod::Monthly,
+            _ => return Err(format!("Invalid quota period: {}", period)),
+        };
+        Ok(Quota {
+            bytes: parse_size(size)?,
+            period,
+        })
+    }
+}
+
+// Rates in bytes per second and the quota; unset fields fall back to the defaults,
+// and 0 means unlimited
+#[derive(Clone, Copy, Default)]
+pub(crate) struct LimitSettings {
+    pub(crate) upload: Option<u64>,
+    pub(crate) download: Option<u64>,
+    pub(crate) quota: Option<Quota>,
+}
+
+impl LimitSettings {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.upload.is_none() && self.download.is_none() && self.quota.is_none()
+    }
+
+    fn or(self, defaults: LimitSettings) -> LimitSettings {
+        LimitSettings {
+            upload: self.upload.or(defaults.upload).filter(|rate| *rate > 0),
+            download: self.download.or(defaults.download).filter(|rate| *rate > 0),
+            quota: self
+                .quota
+                .or(defaults.quota)
+                .filter(|quota| quota.bytes > 0),
+        }
+    }
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket holding up to one second worth of traffic
+struct TokenBucket {
+    rate: f64,
+    state: tokio::sync::Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            state: tokio::sync::Mutex::new(BucketState {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    // Take tokens, sleeping off any deficit; the lock keeps waiting sessions in order
+    async fn take(&self, amount: usize) {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.rate) - amount as f64;
+        state.updated = now;
+        if state.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-state.tokens / self.rate)).await;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    Upload,
+    Download,
+}
+
+// Limits shared by all sessions of one user
+pub(crate) struct UserLimiter {
+    user: Option<String>,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+    quota: Option<Quota>,
+    store: Arc<QuotaStore>,
+    exhausted: watch::Sender<bool>,
+}
+
+impl UserLimiter {
+    fn bucket(&self, direction: Direction) -> Option<&TokenBucket> {
+        match direction {
+            Direction::Upload => self.upload.as_ref(),
+            Direction::Download => self.download.as_ref(),
+        }
+    }
+
+    // Quota state for the current period; a new period lifts an exhausted quota
+    pub(crate) fn is_exhausted(&self) -> bool {
+        let (Some(user), Some(quota)) = (&self.user, self.quota) else {
+            return false;
+        };
+        let exhausted = self.store.used(user, quota.period) >= quota.bytes;
+        self.exhausted
+            .send_if_modified(|current| std::mem::replace(current, exhausted) != exhausted);
+        exhausted
+    }
+
+    // Resolves once the quota runs out, so sessions in progress can be closed
+    pub(crate) async fn wait_exhausted(&self) {
+        let mut receiver = self.exhausted.subscribe();
+        if receiver.wait_for(|exhausted| *exhausted).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    // Smaller chunks for slow rates keep the traffic smooth
+    pub(crate) fn chunk_size(&self, direction: Direction, buffer_size: usize) -> usize {
+        self.bucket(direction).map_or(buffer_size, |bucket| {
+            (bucket.rate as usize).clamp(1, buffer_size)
+        })
+    }
+
+    // Wait for bandwidth and charge the quota before bytes are forwarded; the chunk
+    // that reaches the quota still goes through, then all sessions of the user close
+    pub(crate) async fn acquire(&self, direction: Direction, amount: usize) -> std::io::Result<()> {
+        if let Some(bucket) = self.bucket(direction) {
+            bucket.take(amount).await;
+        }
+
+        if let (Some(user), Some(quota)) = (&self.user, self.quota) {
+            if *self.exhausted.borrow() {
+                return Err(std::io::Error::other(format!(
+                    "Transfer quota of user {} exhausted",
+                    user
+                )));
+            }
+            let used = self.store.add(user, quota.period, amount as u64);
+            if used >= quota.bytes && !self.exhausted.send_replace(true) {
+                warn!("Transfer quota of user {} exhausted", user);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Usage {
+    period: String,
+    bytes: u64,
+}
+
+// Bytes transferred per user in the current period, persisted to a state file
+struct QuotaStore {
+    path: PathBuf,
+    usage: Mutex<HashMap<String, Usage>>,
+    dirty: AtomicBool,
+    // Set once a successor process owns the state file
+    handed_over: AtomicBool,
+}
+
+impl QuotaStore {
+    // Lines are "username period bytes"; a missing file starts with empty usage
+    async fn load(path: PathBuf) -> Result<Self> {
+        let mut usage = HashMap::new();
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => {
+                for (index, line) in content.lines().enumerate() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    let [user, period, bytes] = fields[..] else {
+                        warn!(
+                            "Ignoring malformed quota state line {}: {}",
+                            index + 1,
+                            line
+                        );
+                        continue;
+                    };
+                    let Ok(bytes) = bytes.parse::<u64>() else {
+                        warn!(
+                            "Ignoring malformed quota state line {}: {}",
+                            index + 1,
+                            line
+                        );
+                        continue;
+                    };
+                    usage.insert(
+                        user.to_string(),
+                        Usage {
+                            period: period.to_string(),
+                            bytes,
+                        },
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("Quota state file {:?} not found, starting empty", path);
+            }
+            Err(e) => {
+                return Err(e).context(format!("Failed to read quota state file: {:?}", path));
+            }
+        }
+
+        Ok(QuotaStore {
+            path,
+            usage: Mutex::new(usage),
+            dirty: AtomicBool::new(false),
+            handed_over: AtomicBool::new(false),
+        })
+    }
+
+    fn used(&self, user: &str, period: QuotaPeriod) -> u64 {
+        let current = period.current();
+        let usage = self.usage.lock().unwrap();
+        usage
+            .get(user)
+            .filter(|entry| entry.period == current)
+            .map_or(0, |entry| entry.bytes)
+    }
+
+    // Add to the usage of the current period and return the new total
+    fn add(&self, user: &str, period: QuotaPeriod, bytes: u64) -> u64 {
+        let current = period.current();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(user.to_string()).or_insert_with(|| Usage {
+            period: current.clone(),
+            bytes: 0,
+        });
+        if entry.period != current {
+            entry.period = current;
+            entry.bytes = 0;
+        }
+        entry.bytes = entry.bytes.saturating_add(bytes);
+        self.dirty.store(true, Ordering::Relaxed);
+        entry.bytes
+    }
+
+    // Write the state file if usage changed, replacing it atomically
+    async fn save(&self) -> Result<()> {
+        if self.handed_over.load(Ordering::Relaxed) || !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let mut content = String::from("# username period bytes\n");
+        {
+            let usage = self.usage.lock().unwrap();
+            let mut users: Vec<_> = usage.iter().collect();
+            users.sort_by(|a, b| a.0.cmp(b.0));
+            for (user, entry) in users {
+                content.push_str(&format!("{} {} {}\n", user, entry.period, entry.bytes));
+            }
+        }
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let result = async {
+            tokio::fs::write(&temp_path, content).await?;
+            tokio::fs::rename(&temp_path, &self.path).await
+        }
+        .await;
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result.context(format!("Failed to write quota state file: {:?}", self.path))
+    }
+}
+
+// Default limits plus the limiter of every user seen so far
+pub(crate) struct Limits {
+    defaults: LimitSettings,
+    store: Arc<QuotaStore>,
+    limiters: Mutex<HashMap<String, Option<Arc<UserLimiter>>>>,
+}
+
+impl Limits {
+    pub(crate) async fn new(defaults: LimitSettings, state_file: PathBuf) -> Result<Self> {
+        Ok(Limits {
+            defaults,
+            store: Arc::new(QuotaStore::load(state_file).await?),
+            limiters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Limiter for a session, None when nothing limits it; sessions without a user get
+    // their own buckets and no quota
+    pub(crate) fn limiter(
+        &self,
+        user: Option<&str>,
+        settings: Option<&LimitSettings>,
+    ) -> Option<Arc<UserLimiter>> {
+        let Some(user) = user else {
+            return self.build(None, settings);
+        };
+
+        let mut limiters = self.limiters.lock().unwrap();
+        limiters
+            .entry(user.to_string())
+            .or_insert_with(|| self.build(Some(user), settings))
+            .clone()
+    }
+
+    fn build(
+        &self,
+        user: Option<&str>,
+        settings: Option<&LimitSettings>,
+    ) -> Option<Arc<UserLimiter>> {
+        let effective = settings.copied().unwrap_or_default().or(self.defaults);
+        let quota = effective.quota.filter(|_| user.is_some());
+        if effective.upload.is_none() && effective.download.is_none() && quota.is_none() {
+            return None;
+        }
+
+        Some(Arc::new(UserLimiter {
+            user: user.map(str::to_string),
+            upload: effective.upload.map(TokenBucket::new),
+            download: effective.download.map(TokenBucket::new),
+            quota,
+            store: Arc::clone(&self.store),
+            exhausted: watch::Sender::new(false),
+        }))
+    }
+
+    // Forget the cached limiters after the user settings changed
+    pub(crate) fn reset(&self) {
+        self.limiters.lock().unwrap().clear();
+    }
+
+    pub(crate) async fn save(&self) -> Result<()> {
+        self.store.save().await
+    }
+
+    // Leave the state file to the process that took over; later usage of this one
+    // is no longer written
+    pub(crate) fn hand_over(&self) {
+        self.store.handed_over.store(true, Ordering::Relaxed);
+    }
+}
+
+// Convert days since 1970-01-01 to a (year, month, day) date
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn state_file_is_not_written_after_hand_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quota_state.txt");
+        let settings = LimitSettings {
+            quota: Some("1M/day".parse().unwrap()),
+            ..LimitSettings::default()
+        };
+        let limits = Limits::new(LimitSettings::default(), path.clone())
+            .await
+            .unwrap();
+        let limiter = limits.limiter(Some("alice"), Some(&settings)).unwrap();
+
+        limiter.acquire(Direction::Upload, 100).await.unwrap();
+        limits.save().await.unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            saved.contains("alice") && saved.ends_with(" 100\n"),
+            "{}",
+            saved
+        );
+
+        limits.hand_over();
+        limiter.acquire(Direction::Upload, 100).await.unwrap();
+        limits.save().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+    }
+}
@@ -1,87 +1,38 @@
 use anyhow::{Context, Result};
-use clap::Parser;
 use clap::builder::PossibleValuesParser;
-use ipnet::IpNet;
-use log::{LevelFilter, debug, error, info, warn};
+use clap::{Parser, Subcommand};
+use log::{LevelFilter, error};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
-use rustls_pemfile::{certs, pkcs8_private_keys};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufReader;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use rust_socks5_proxy::config::{
+    self, AuthBackend, AuthSettings, ClientUser, ListenerSettings, TlsSettings,
+};
+use rust_socks5_proxy::{
+    Egress, HashAlgorithm, ProxyError, Quota, Rotation, Socks5Server, hash_password, parse_size,
+};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, lookup_host};
+use std::time::Duration;
 use tokio::signal::ctrl_c;
-use tokio::sync::broadcast;
-use tokio::task::JoinSet;
-use tokio::time::timeout;
-use tokio_rustls::TlsAcceptor;
-use tokio_rustls::rustls::ServerConfig;
-use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-
-// SOCKS5 protocol constants
-const SOCKS_VERSION: u8 = 5;
-const CONNECT_COMMAND: u8 = 1;
-const BIND_COMMAND: u8 = 2;
-const UDP_ASSOCIATE_COMMAND: u8 = 3;
-const NO_AUTH_METHOD: u8 = 0;
-const USER_PASS_AUTH_METHOD: u8 = 2;
-const ADDR_TYPE_IPV4: u8 = 1;
-const ADDR_TYPE_DOMAIN: u8 = 3;
-const ADDR_TYPE_IPV6: u8 = 4;
-const REPLY_SUCCEEDED: u8 = 0;
-
-// SOCKS5 error reply codes (refer to RFC 1928)
-const REPLY_GENERAL_FAILURE: u8 = 0x01;
-const REPLY_CONNECTION_REFUSED: u8 = 0x05;
-const REPLY_TTL_EXPIRED: u8 = 0x06; // TTL expired (defined but not used)
-const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
-const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
-const REPLY_HOST_UNREACHABLE: u8 = 0x04;
-
-#[derive(Debug)]
-enum ProxyError {
-    InvalidVersion,
-    AuthenticationRequired,
-    AuthenticationFailed,
-    UnsupportedCommand,
-    UnsupportedAddressType,
-    ConnectionFailed(String),
-    NetworkError(String),
-    Timeout(String),
-    ConfigError(String),
-}
-
-impl std::fmt::Display for ProxyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ProxyError::InvalidVersion => write!(f, "Unsupported SOCKS version"),
-            ProxyError::AuthenticationRequired => write!(f, "Authentication required"),
-            ProxyError::AuthenticationFailed => write!(f, "Authentication failed"),
-            ProxyError::UnsupportedCommand => write!(f, "Unsupported command"),
-            ProxyError::UnsupportedAddressType => write!(f, "Unsupported address type"),
-            ProxyError::ConnectionFailed(details) => write!(f, "Connection failed: {}", details),
-            ProxyError::NetworkError(details) => write!(f, "Network error: {}", details),
-            ProxyError::Timeout(details) => write!(f, "Timeout: {}", details),
-            ProxyError::ConfigError(details) => write!(f, "Configuration error: {}", details),
-        }
-    }
-}
-
-impl std::error::Error for ProxyError {}
 
 // Command-line argument parsing
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Path to a TOML file describing the listeners (replaces the per-listener flags)
+    #[clap(long, conflicts_with_all = [
+        "bind_ip", "bind_port", "timeout_seconds", "idle_timeout_seconds", "use_auth", "auth_file", "auth_backend",
+        "auth_url", "allowed_ips", "allowed_ips_file", "use_tls", "tls_cert", "tls_key",
+        "tls_client_ca", "tls_client_crl", "tls_client_user", "proxy_protocol", "trusted_proxies",
+        "allow_socks4_userid",
+    ])]
+    config: Option<PathBuf>,
+
     /// IP address to bind to (IPv4 or IPv6)
     #[clap(long, default_value = "127.0.0.1")]
     bind_ip: String,
@@ -90,22 +41,82 @@ struct Args {
     #[clap(long, default_value = "1080")]
     bind_port: u16,
 
-    /// Maximum number of concurrent connections
+    /// Maximum number of concurrent sessions; clients beyond it get a failure reply
     #[clap(long, default_value = "1000")]
     max_connections: usize,
 
+    /// Sessions that may wait for a free slot once max-connections is reached (0 to reply with a failure at once)
+    #[clap(long, default_value = "0")]
+    admission_queue_size: usize,
+
+    /// Longest time a session waits in the admission queue (seconds)
+    #[clap(long, default_value = "10")]
+    admission_queue_timeout_seconds: u64,
+
+    /// Connections held open beyond max-connections while negotiating or queued; more are closed without a reply
+    #[clap(long, default_value = "1000")]
+    max_pending_connections: usize,
+
+    /// Maximum number of concurrent sessions of one user (0 for unlimited)
+    #[clap(long, default_value = "0")]
+    max_connections_per_user: usize,
+
+    /// Maximum number of concurrent connections from one client IP (0 for unlimited)
+    #[clap(long, default_value = "0")]
+    max_connections_per_ip: usize,
+
+    /// Maximum number of new connections per second from one client IP (0 for unlimited)
+    #[clap(long, default_value = "0")]
+    connection_rate_per_ip: u32,
+
     /// Connection timeout (seconds)
     #[clap(long, default_value = "60")]
     timeout_seconds: u64,
 
+    /// Close tunnels without traffic in either direction for this long (seconds, 0 to disable)
+    #[clap(long, default_value = "300")]
+    idle_timeout_seconds: u64,
+
+    /// Largest chunk a tunnel copies at once through userspace buffers (K/M suffixes)
+    #[clap(long, default_value = "16K", value_parser = parse_size)]
+    relay_buffer_size: u64,
+
+    /// Pipe size for zero-copy splice(2) relaying of plain TCP tunnels on Linux (K/M suffixes, 0 to disable)
+    #[clap(long, default_value = "64K", value_parser = parse_size)]
+    splice_pipe_size: u64,
+
     /// Whether to use authentication
     #[clap(long)]
     use_auth: bool,
 
-    /// Path to authentication file (lines in username:password format)
+    /// Let SOCKS4 clients log in with a known username as USERID, without a password
+    #[clap(long, requires = "use_auth")]
+    allow_socks4_userid: bool,
+
+    /// Path to authentication file (lines in username:password format, or htpasswd format)
     #[clap(long, default_value = "auth.txt")]
     auth_file: String,
 
+    /// Authentication backend: auth file, htpasswd file or HTTP callback
+    #[clap(long, default_value = "file", value_parser = PossibleValuesParser::new(["file", "htpasswd", "http"]))]
+    auth_backend: String,
+
+    /// URL the http backend POSTs credentials to (http:// or https://)
+    #[clap(long)]
+    auth_url: Option<String>,
+
+    /// Failed logins from one IP or for one username before it is blocked (0 to disable)
+    #[clap(long, default_value = "5")]
+    auth_max_failures: u32,
+
+    /// Length of the first authentication block, doubled with every further block (seconds)
+    #[clap(long, default_value = "60")]
+    auth_block_seconds: u64,
+
+    /// Longest authentication block (seconds)
+    #[clap(long, default_value = "3600")]
+    auth_max_block_seconds: u64,
+
     /// Log level setting
     #[clap(long, default_value = "info", value_parser = PossibleValuesParser::new(&["error", "warn", "info", "debug", "trace"]))]
     log_level: String,
@@ -114,10 +125,42 @@ struct Args {
     #[clap(long)]
     log_file: Option<PathBuf>,
 
+    /// Path to the access log, one JSON record per session
+    #[clap(long)]
+    access_log: Option<PathBuf>,
+
+    /// When to rotate the access log: "daily", a size such as "100M", or "never"
+    #[clap(long, default_value = "daily")]
+    access_log_rotation: Rotation,
+
+    /// Number of rotated access log files to keep
+    #[clap(long, default_value = "30")]
+    access_log_keep: usize,
+
     /// List of allowed IP addresses or CIDR ranges (comma-separated)
     #[clap(long)]
     allowed_ips: Option<String>,
 
+    /// Path to a file of allowed IP addresses or CIDR ranges, reloaded with the other settings
+    #[clap(long)]
+    allowed_ips_file: Option<PathBuf>,
+
+    /// Read a PROXY protocol v1 or v2 header from trusted proxies before the handshake
+    #[clap(long, requires = "trusted_proxies")]
+    proxy_protocol: bool,
+
+    /// Load balancer addresses or CIDR ranges whose PROXY protocol headers are trusted (comma-separated)
+    #[clap(long, requires = "proxy_protocol")]
+    trusted_proxies: Option<String>,
+
+    /// Reload auth, allowed IPs and TLS files when they change (SIGHUP always reloads)
+    #[clap(long)]
+    watch_files: bool,
+
+    /// Longest time sessions continue in the old process after a SIGUSR2 upgrade (seconds)
+    #[clap(long, default_value = "300")]
+    drain_timeout_seconds: u64,
+
     /// Whether to enable TLS
     #[clap(long)]
     use_tls: bool,
@@ -130,97 +173,142 @@ struct Args {
     #[clap(long)]
     tls_key: Option<PathBuf>,
 
-    /// DNS lookup cache TTL (seconds, 0 to disable cache)
+    /// Require client certificates signed by a CA in this PEM bundle (mutual TLS)
+    #[clap(long, requires = "use_tls")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// PEM file of certificate revocation lists checked for client certificates
+    #[clap(long, requires = "tls_client_ca")]
+    tls_client_crl: Option<PathBuf>,
+
+    /// Certificate field that names the user: subject common name or first SAN entry
+    #[clap(long, default_value = "cn", value_parser = PossibleValuesParser::new(["cn", "san"]))]
+    tls_client_user: String,
+
+    /// Maximum time DNS answers are cached; records keep their own shorter TTLs (seconds, 0 to disable cache)
     #[clap(long, default_value = "300")]
     dns_cache_ttl: u64,
-}
 
-// Target address information
-struct TargetAddress {
-    ip: IpAddr,
-    domain: Option<String>,
-    port: u16,
-}
+    /// Upstream proxy in name=scheme://[user:pass@]host:port format (socks5 or http, repeatable)
+    #[clap(long = "upstream")]
+    upstreams: Vec<String>,
 
-// Connection statistics tracking
-struct Stats {
-    active_connections: AtomicUsize,
-    total_connections: AtomicUsize,
-    start_time: Instant,
-}
+    /// Source addresses of direct outbound connections, at most one IPv4 and one IPv6 (comma-separated)
+    #[clap(long)]
+    egress_address: Option<Egress>,
 
-// User authentication information storage
-struct Users {
-    credentials: HashMap<String, String>,
-}
+    /// Path to egress routing rules file (lines in "matchers... action" format)
+    #[clap(long)]
+    rules_file: Option<PathBuf>,
 
-// IP allow list
-struct AllowedIPs {
-    networks: Vec<IpNet>,
-}
+    /// Path to global destination access list (lines in "matchers... allow|deny" format)
+    #[clap(long)]
+    dest_acl: Option<PathBuf>,
 
-impl AllowedIPs {
-    fn new(allowed_ips: &str) -> Result<Self, ipnet::AddrParseError> {
-        let mut networks = Vec::new();
+    /// Default upload rate limit per user in bytes per second (K/M/G suffixes, 0 for unlimited)
+    #[clap(long, value_parser = parse_size)]
+    upload_limit: Option<u64>,
 
-        for ip_str in allowed_ips.split(',') {
-            let ip_str = ip_str.trim();
-            if !ip_str.is_empty() {
-                let network = IpNet::from_str(ip_str)?;
-                networks.push(network);
-            }
-        }
+    /// Default download rate limit per user in bytes per second (K/M/G suffixes, 0 for unlimited)
+    #[clap(long, value_parser = parse_size)]
+    download_limit: Option<u64>,
 
-        Ok(AllowedIPs { networks })
-    }
+    /// Default transfer quota per user (e.g. "10G/day" or "100G/month")
+    #[clap(long)]
+    quota: Option<Quota>,
 
-    fn is_allowed(&self, addr: &IpAddr) -> bool {
-        if self.networks.is_empty() {
-            return true; // Allow all IPs if the list is empty
-        }
+    /// Path to the file that keeps quota usage across restarts
+    #[clap(long, default_value = "quota_state.txt")]
+    quota_state_file: PathBuf,
 
-        self.networks.iter().any(|network| network.contains(addr))
-    }
-}
+    /// Address to serve Prometheus metrics on at /metrics (e.g. 127.0.0.1:9100)
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Address to serve the admin API on (e.g. 127.0.0.1:9101)
+    #[clap(long, requires = "admin_token_file")]
+    admin_addr: Option<SocketAddr>,
 
-// Simple DNS cache implementation
-struct DnsCache {
-    cache: Mutex<HashMap<String, (IpAddr, Instant)>>,
-    ttl: Duration,
+    /// Path to the file holding the bearer token of the admin API
+    #[clap(long, requires = "admin_addr")]
+    admin_token_file: Option<PathBuf>,
 }
 
-impl DnsCache {
-    fn new(ttl_seconds: u64) -> Self {
-        DnsCache {
-            cache: Mutex::new(HashMap::new()),
-            ttl: Duration::from_secs(ttl_seconds),
-        }
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a password hash for the auth file (the password is read from stdin if omitted)
+    HashPassword {
+        /// Hash algorithm (argon2 or bcrypt)
+        #[clap(long, default_value = "argon2")]
+        algorithm: HashAlgorithm,
+
+        /// Password to hash
+        password: Option<String>,
+    },
+}
 
-    fn get(&self, domain: &str) -> Option<IpAddr> {
-        if self.ttl.as_secs() == 0 {
-            return None; // Cache disabled
-        }
+// The single listener described by the command-line flags
+fn listener_from_args(args: &Args) -> Result<ListenerSettings> {
+    let ip: IpAddr = args.bind_ip.parse().map_err(|_| {
+        ProxyError::ConfigError(format!("Invalid bind IP address: {}", args.bind_ip))
+    })?;
+
+    let auth = args.use_auth.then(|| AuthSettings {
+        backend: match args.auth_backend.as_str() {
+            "htpasswd" => AuthBackend::Htpasswd,
+            "http" => AuthBackend::Http,
+            _ => AuthBackend::File,
+        },
+        file: args.auth_file.clone(),
+        url: args.auth_url.clone(),
+    });
 
-        let mut cache = self.cache.lock().unwrap();
-        if let Some((ip, timestamp)) = cache.get(domain) {
-            if timestamp.elapsed() < self.ttl {
-                return Some(*ip);
-            }
-            // TTL expired, remove entry
-            cache.remove(domain);
-        }
+    let tls = if args.use_tls {
+        let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+            return Err(ProxyError::ConfigError(
+                "TLS enabled but certificate or key file not specified".to_string(),
+            )
+            .into());
+        };
+        Some(TlsSettings {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: args.tls_client_ca.clone(),
+            client_crl: args.tls_client_crl.clone(),
+            client_user: match args.tls_client_user.as_str() {
+                "san" => ClientUser::San,
+                _ => ClientUser::Cn,
+            },
+        })
+    } else {
         None
-    }
-
-    fn set(&self, domain: String, ip: IpAddr) {
-        if self.ttl.as_secs() == 0 {
-            return; // Cache disabled
-        }
+    };
 
-        let mut cache = self.cache.lock().unwrap();
-        cache.insert(domain, (ip, Instant::now()));
-    }
+    Ok(ListenerSettings {
+        name: String::new(),
+        bind: SocketAddr::new(ip, args.bind_port),
+        timeout_seconds: args.timeout_seconds,
+        idle_timeout_seconds: args.idle_timeout_seconds,
+        auth,
+        allowed_ips: args
+            .allowed_ips
+            .iter()
+            .flat_map(|ips| ips.split(','))
+            .map(|ip| ip.trim().to_string())
+            .filter(|ip| !ip.is_empty())
+            .collect(),
+        allowed_ips_file: args.allowed_ips_file.clone(),
+        tls,
+        proxy_protocol: args.proxy_protocol,
+        trusted_proxies: args
+            .trusted_proxies
+            .iter()
+            .flat_map(|ips| ips.split(','))
+            .map(|ip| ip.trim().to_string())
+            .filter(|ip| !ip.is_empty())
+            .collect(),
+        allow_socks4_userid: args.allow_socks4_userid,
+    })
 }
 
 // Logging setup function
@@ -268,469 +356,28 @@ fn setup_logging(args: &Args) -> Result<()> {
     Ok(())
 }
 
-// TLS setup function
-async fn setup_tls(args: &Args) -> Result<TlsAcceptor> {
-    if args.tls_cert.is_none() || args.tls_key.is_none() {
-        return Err(anyhow::anyhow!(
-            "TLS enabled but certificate or key file not specified"
-        ));
-    }
-
-    let cert_file = File::open(args.tls_cert.as_ref().unwrap()).context(format!(
-        "Failed to open TLS certificate file: {:?}",
-        args.tls_cert
-    ))?;
-    let key_file = File::open(args.tls_key.as_ref().unwrap())
-        .context(format!("Failed to open TLS key file: {:?}", args.tls_key))?;
-
-    let cert_chain: Vec<CertificateDer> = certs(&mut BufReader::new(cert_file))
-        .context("Failed to parse TLS certificate")?
-        .into_iter()
-        .map(CertificateDer::from)
-        .collect();
-
-    let mut keys: Vec<PrivatePkcs8KeyDer> = pkcs8_private_keys(&mut BufReader::new(key_file))
-        .context("Failed to parse TLS key")?
-        .into_iter()
-        .map(PrivatePkcs8KeyDer::from)
-        .collect();
-
-    if keys.is_empty() {
-        return Err(anyhow::anyhow!("No private key found in key file"));
-    }
-
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, PrivateKeyDer::from(keys.remove(0)))
-        .context("Failed to build TLS configuration")?;
-
-    Ok(TlsAcceptor::from(Arc::new(config)))
-}
-
-async fn handle_client<T>(
-    mut socket: T,
-    addr: SocketAddr,
-    users: Option<Arc<Users>>,
-    timeout_duration: Duration,
-    dns_cache: Arc<DnsCache>,
-) -> Result<()>
-where
-    T: AsyncRead + AsyncWrite + Unpin + Send,
-{
-    debug!("Processing new client: {}", addr);
-
-    // --- Authentication method negotiation ---
-    let mut buf = [0u8; 2];
-    socket
-        .read_exact(&mut buf)
-        .await
-        .context("Failed to read authentication header")?;
-
-    if buf[0] != SOCKS_VERSION {
-        return Err(ProxyError::InvalidVersion.into());
-    }
-
-    let method_count = buf[1] as usize;
-    let mut methods = vec![0u8; method_count];
-    socket
-        .read_exact(&mut methods)
-        .await
-        .context("Failed to read authentication methods")?;
-
-    // Authentication processing
-    if let Some(users_data) = &users {
-        if !methods.contains(&USER_PASS_AUTH_METHOD) {
-            socket
-                .write_all(&[SOCKS_VERSION, 0xFF])
-                .await
-                .context("Failed to send auth rejection")?;
-            return Err(ProxyError::AuthenticationRequired.into());
+// hash-password subcommand
+fn print_password_hash(algorithm: HashAlgorithm, password: Option<String>) -> Result<()> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            std::io::stdin()
+                .read_line(&mut line)
+                .context("Failed to read password from stdin")?;
+            line.trim_end_matches(['\r', '\n']).to_string()
         }
-
-        // Select user/password authentication method
-        socket
-            .write_all(&[SOCKS_VERSION, USER_PASS_AUTH_METHOD])
-            .await
-            .context("Failed to send auth method")?;
-
-        // Authentication process (sub-negotiation version 0x01)
-        let mut auth_header = [0u8; 2];
-        socket
-            .read_exact(&mut auth_header)
-            .await
-            .context("Failed to read auth header")?;
-
-        if auth_header[0] != 1 {
-            return Err(anyhow::anyhow!("Unsupported auth version"));
-        }
-
-        // Read username
-        let ulen = auth_header[1] as usize;
-        let mut username = vec![0u8; ulen];
-        socket
-            .read_exact(&mut username)
-            .await
-            .context("Failed to read username")?;
-        let username = String::from_utf8(username).context("Username is not valid UTF-8")?;
-
-        // Read password
-        let mut plen = [0u8; 1];
-        socket
-            .read_exact(&mut plen)
-            .await
-            .context("Failed to read password length")?;
-        let mut password = vec![0u8; plen[0] as usize];
-        socket
-            .read_exact(&mut password)
-            .await
-            .context("Failed to read password")?;
-        let password = String::from_utf8(password).context("Password is not valid UTF-8")?;
-
-        // Authentication verification
-        let authenticated = users_data.credentials.get(&username) == Some(&password);
-
-        if authenticated {
-            socket
-                .write_all(&[1, 0])
-                .await
-                .context("Failed to send auth success")?; // Success
-            debug!("{} authenticated as {}", addr, username);
-        } else {
-            socket
-                .write_all(&[1, 1])
-                .await
-                .context("Failed to send auth failure")?; // Failure
-            return Err(ProxyError::AuthenticationFailed.into());
-        }
-    } else {
-        // No authentication
-        if !methods.contains(&NO_AUTH_METHOD) {
-            socket
-                .write_all(&[SOCKS_VERSION, 0xFF])
-                .await
-                .context("Failed to send no-auth rejection")?;
-            return Err(anyhow::anyhow!("No supported authentication methods"));
-        }
-
-        socket
-            .write_all(&[SOCKS_VERSION, NO_AUTH_METHOD])
-            .await
-            .context("Failed to send no-auth acceptance")?;
-    }
-
-    // --- Request processing ---
-    let mut request = [0u8; 4];
-    socket
-        .read_exact(&mut request)
-        .await
-        .context("Failed to read request header")?;
-
-    if request[0] != SOCKS_VERSION {
-        return Err(ProxyError::InvalidVersion.into());
+    };
+    if password.is_empty() {
+        return Err(anyhow::anyhow!("Password must not be empty"));
     }
-
-    // Command processing
-    match request[1] {
-        CONNECT_COMMAND => {
-            // Parse target address
-            let target_addr = match request[3] {
-                ADDR_TYPE_IPV4 => {
-                    let mut ipv4 = [0u8; 4];
-                    socket
-                        .read_exact(&mut ipv4)
-                        .await
-                        .context("Failed to read IPv4 address")?;
-                    let ip = IpAddr::V4(Ipv4Addr::from(ipv4));
-
-                    // Read port
-                    let mut port_buf = [0u8; 2];
-                    socket
-                        .read_exact(&mut port_buf)
-                        .await
-                        .context("Failed to read port")?;
-                    let port = u16::from_be_bytes(port_buf);
-
-                    TargetAddress {
-                        ip,
-                        domain: None,
-                        port,
-                    }
-                }
-                ADDR_TYPE_DOMAIN => {
-                    let mut domain_length = [0u8; 1];
-                    socket
-                        .read_exact(&mut domain_length)
-                        .await
-                        .context("Failed to read domain length")?;
-                    let domain_len = domain_length[0] as usize;
-                    let mut domain = vec![0u8; domain_len];
-                    socket
-                        .read_exact(&mut domain)
-                        .await
-                        .context("Failed to read domain name")?;
-                    let domain_str =
-                        String::from_utf8(domain).context("Domain is not valid UTF-8")?;
-                    debug!("Resolving domain: {}", domain_str);
-
-                    // Read port
-                    let mut port_buf = [0u8; 2];
-                    socket
-                        .read_exact(&mut port_buf)
-                        .await
-                        .context("Failed to read port")?;
-                    let port = u16::from_be_bytes(port_buf);
-
-                    // Check DNS cache
-                    let ip = if let Some(cached_ip) = dns_cache.get(&domain_str) {
-                        debug!("DNS cache hit for {}: {}", domain_str, cached_ip);
-                        cached_ip
-                    } else {
-                        // Cache miss, perform DNS lookup
-                        match timeout(
-                            Duration::from_secs(5),
-                            lookup_host((domain_str.as_str(), 0)),
-                        )
-                        .await
-                        {
-                            Ok(Ok(mut addrs)) => {
-                                if let Some(addr) = addrs.next() {
-                                    let ip = addr.ip();
-                                    debug!("Resolved {} to {}", domain_str, ip);
-
-                                    // Store in cache
-                                    dns_cache.set(domain_str.clone(), ip);
-                                    ip
-                                } else {
-                                    send_reply(&mut socket, REPLY_HOST_UNREACHABLE)
-                                        .await
-                                        .context("Failed to send host unreachable reply")?;
-                                    return Err(ProxyError::ConnectionFailed(format!(
-                                        "Domain resolution failed: {}",
-                                        domain_str
-                                    ))
-                                    .into());
-                                }
-                            }
-                            Ok(Err(e)) => {
-                                send_reply(&mut socket, REPLY_HOST_UNREACHABLE)
-                                    .await
-                                    .context("Failed to send host unreachable reply")?;
-                                return Err(ProxyError::ConnectionFailed(format!(
-                                    "Domain resolution error: {}",
-                                    e
-                                ))
-                                .into());
-                            }
-                            Err(_) => {
-                                send_reply(&mut socket, REPLY_HOST_UNREACHABLE)
-                                    .await
-                                    .context("Failed to send host unreachable reply")?;
-                                return Err(ProxyError::Timeout(format!(
-                                    "Domain resolution timeout: {}",
-                                    domain_str
-                                ))
-                                .into());
-                            }
-                        }
-                    };
-
-                    TargetAddress {
-                        ip,
-                        domain: Some(domain_str),
-                        port,
-                    }
-                }
-                ADDR_TYPE_IPV6 => {
-                    let mut ipv6 = [0u8; 16];
-                    socket
-                        .read_exact(&mut ipv6)
-                        .await
-                        .context("Failed to read IPv6 address")?;
-                    let ip = IpAddr::V6(Ipv6Addr::from(ipv6));
-
-                    // Read port
-                    let mut port_buf = [0u8; 2];
-                    socket
-                        .read_exact(&mut port_buf)
-                        .await
-                        .context("Failed to read port")?;
-                    let port = u16::from_be_bytes(port_buf);
-
-                    TargetAddress {
-                        ip,
-                        domain: None,
-                        port,
-                    }
-                }
-                _ => {
-                    send_reply(&mut socket, REPLY_ADDRESS_TYPE_NOT_SUPPORTED)
-                        .await
-                        .context("Failed to send address type not supported reply")?;
-                    return Err(ProxyError::UnsupportedAddressType.into());
-                }
-            };
-
-            // Log remote server connection information
-            if let Some(domain) = &target_addr.domain {
-                info!(
-                    "{} connecting to {}:{} ({})",
-                    addr, domain, target_addr.port, target_addr.ip
-                );
-            } else {
-                info!(
-                    "{} connecting to {}:{}",
-                    addr, target_addr.ip, target_addr.port
-                );
-            }
-
-            // Connect to remote server (with timeout)
-            let mut remote = match timeout(
-                timeout_duration,
-                TcpStream::connect((target_addr.ip, target_addr.port)),
-            )
-            .await
-            {
-                Ok(Ok(stream)) => {
-                    if let Some(domain) = &target_addr.domain {
-                        info!(
-                            "{} connected to {}:{} ({})",
-                            addr, domain, target_addr.port, target_addr.ip
-                        );
-                    } else {
-                        info!(
-                            "{} connected to {}:{}",
-                            addr, target_addr.ip, target_addr.port
-                        );
-                    }
-                    stream
-                }
-                Ok(Err(e)) => {
-                    // Send appropriate response code for connection failure
-                    let reply_code = match e.kind() {
-                        std::io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
-                        std::io::ErrorKind::TimedOut => REPLY_HOST_UNREACHABLE,
-                        _ => REPLY_GENERAL_FAILURE,
-                    };
-                    send_reply(&mut socket, reply_code)
-                        .await
-                        .context("Failed to send connection error reply")?;
-                    return Err(ProxyError::ConnectionFailed(format!(
-                        "Failed to connect to remote: {}",
-                        e
-                    ))
-                    .into());
-                }
-                Err(_) => {
-                    send_reply(&mut socket, REPLY_HOST_UNREACHABLE)
-                        .await
-                        .context("Failed to send timeout reply")?;
-
-                    let target_info = if let Some(domain) = &target_addr.domain {
-                        format!("{}:{} ({})", domain, target_addr.port, target_addr.ip)
-                    } else {
-                        format!("{}:{}", target_addr.ip, target_addr.port)
-                    };
-
-                    return Err(ProxyError::Timeout(format!(
-                        "Connection timeout to {}",
-                        target_info
-                    ))
-                    .into());
-                }
-            };
-
-            // Send successful connection response
-            send_reply(&mut socket, REPLY_SUCCEEDED)
-                .await
-                .context("Failed to send success reply")?;
-
-            // Set up bidirectional data transfer
-            let (mut ri, mut wi) = tokio::io::split(&mut socket);
-            let (mut ro, mut wo) = remote.split();
-
-            // Execute bidirectional copy (with timeout)
-            match tokio::select! {
-                result = tokio::io::copy(&mut ri, &mut wo) => { result.map(|_| ()) },
-                result = tokio::io::copy(&mut ro, &mut wi) => { result.map(|_| ()) }
-            } {
-                Ok(_) => {
-                    if let Some(domain) = &target_addr.domain {
-                        info!(
-                            "{} closed connection to {}:{} ({})",
-                            addr, domain, target_addr.port, target_addr.ip
-                        );
-                    } else {
-                        info!(
-                            "{} closed connection to {}:{}",
-                            addr, target_addr.ip, target_addr.port
-                        );
-                    }
-                }
-                Err(e) => {
-                    let target_info = if let Some(domain) = &target_addr.domain {
-                        format!("{}:{} ({})", domain, target_addr.port, target_addr.ip)
-                    } else {
-                        format!("{}:{}", target_addr.ip, target_addr.port)
-                    };
-
-                    warn!("{} error with {} - {}", addr, target_info, e);
-                    return Err(
-                        ProxyError::NetworkError(format!("Data transfer error: {}", e)).into(),
-                    );
-                }
-            }
-
-            Ok(())
-        }
-        BIND_COMMAND => {
-            // BIND command implementation is omitted
-            warn!("{} received BIND command, but it is not implemented.", addr);
-            send_reply(&mut socket, REPLY_COMMAND_NOT_SUPPORTED)
-                .await
-                .context("Failed to send command not supported reply")?;
-            Err(ProxyError::UnsupportedCommand.into())
-        }
-        UDP_ASSOCIATE_COMMAND => {
-            // UDP ASSOCIATE command implementation is omitted
-            warn!(
-                "{} received UDP ASSOCIATE command, but it is not implemented.",
-                addr
-            ); // Log added
-            send_reply(&mut socket, REPLY_COMMAND_NOT_SUPPORTED)
-                .await
-                .context("Failed to send command not supported reply")?;
-            Err(ProxyError::UnsupportedCommand.into())
-        }
-        _ => {
-            send_reply(&mut socket, REPLY_COMMAND_NOT_SUPPORTED)
-                .await
-                .context("Failed to send unknown command reply")?;
-            Err(ProxyError::UnsupportedCommand.into())
-        }
+    if password.contains(['\t', '\r', '\n']) {
+        return Err(anyhow::anyhow!(
+            "Password must not contain tabs or line breaks (auth file lines cannot hold them)"
+        ));
     }
-}
 
-// SOCKS5 response sending helper function
-async fn send_reply<T>(socket: &mut T, reply_code: u8) -> Result<()>
-where
-    T: AsyncWriteExt + Unpin,
-{
-    // Standard SOCKS5 response format: VER, REP, RSV, ATYP, BIND.ADDR, BIND.PORT
-    socket
-        .write_all(&[
-            SOCKS_VERSION,
-            reply_code,
-            0x00, // Reserved field must be 0x00
-            ADDR_TYPE_IPV4,
-            0,
-            0,
-            0,
-            0, // IP address (0.0.0.0)
-            0,
-            0, // Port (0)
-        ])
-        .await
-        .context("Failed to send SOCKS5 reply")?;
+    println!("{}", hash_password(&password, algorithm)?);
     Ok(())
 }
 
@@ -739,245 +386,101 @@ async fn main() -> Result<()> {
     // Command-line argument parsing
     let args = Args::parse();
 
+    if let Some(Command::HashPassword {
+        algorithm,
+        password,
+    }) = &args.command
+    {
+        return print_password_hash(*algorithm, password.clone());
+    }
+
     // Logging setup
     setup_logging(&args)?;
 
-    // TLS setup
-    let tls_acceptor = if args.use_tls {
-        Some(setup_tls(&args).await.context("Failed to setup TLS")?)
-    } else {
-        None
-    };
-
-    // Load user authentication information (if needed)
-    let users = if args.use_auth {
-        let content = tokio::fs::read_to_string(&args.auth_file)
-            .await
-            .context(format!("Failed to read auth file: {}", args.auth_file))?;
-
-        let mut credentials = HashMap::new();
-        for line in content.lines() {
-            if let Some((username, password)) = line.split_once(':') {
-                credentials.insert(username.to_string(), password.to_string());
-            }
-        }
-
-        Some(Arc::new(Users { credentials }))
-    } else {
-        None
+    // Listener settings, from --config or the command-line flags
+    let listeners = match &args.config {
+        Some(path) => config::load(path).await?,
+        None => vec![listener_from_args(&args)?],
     };
 
-    // IP allow list setup
-    let allowed_ips = if let Some(ip_list) = &args.allowed_ips {
-        match AllowedIPs::new(ip_list) {
-            Ok(allowed) => Some(Arc::new(allowed)),
-            Err(e) => {
-                return Err(anyhow::anyhow!("Failed to parse allowed IPs: {}", e));
-            }
-        }
-    } else {
-        None
-    };
-
-    // DNS cache initialization
-    let dns_cache = Arc::new(DnsCache::new(args.dns_cache_ttl));
-
-    // Statistics information initialization
-    let stats = Arc::new(Stats {
-        active_connections: AtomicUsize::new(0),
-        total_connections: AtomicUsize::new(0),
-        start_time: Instant::now(),
-    });
-
-    // Server start
-    let bind_addr = format!("{}:{}", args.bind_ip, args.bind_port);
-    let listener = TcpListener::bind(&bind_addr)
-        .await
-        .context(format!("Failed to bind to {}", bind_addr))?;
-
-    info!("SOCKS5 proxy running on {}", bind_addr);
-    info!(
-        "Authentication: {}",
-        if users.is_some() {
-            "Enabled"
-        } else {
-            "Disabled"
-        }
-    );
-    info!("Max connections: {}", args.max_connections);
-    info!("Connection timeout: {} seconds", args.timeout_seconds);
-    info!(
-        "DNS cache: {}",
-        if args.dns_cache_ttl > 0 {
-            format!("Enabled (TTL: {} seconds)", args.dns_cache_ttl)
-        } else {
-            "Disabled".to_string()
-        }
-    );
-
-    if let Some(ips) = &allowed_ips {
-        info!("IP restrictions enabled with {} rules", ips.networks.len());
+    let mut builder = Socks5Server::builder()
+        .max_connections(args.max_connections)
+        .admission_queue(
+            args.admission_queue_size,
+            Duration::from_secs(args.admission_queue_timeout_seconds),
+        )
+        .max_pending_connections(args.max_pending_connections)
+        .max_connections_per_user(args.max_connections_per_user)
+        .max_connections_per_ip(args.max_connections_per_ip)
+        .connection_rate_per_ip(args.connection_rate_per_ip)
+        .auth_blocking(
+            args.auth_max_failures,
+            Duration::from_secs(args.auth_block_seconds),
+            Duration::from_secs(args.auth_max_block_seconds),
+        )
+        .dns_cache_ttl(Duration::from_secs(args.dns_cache_ttl))
+        .quota_state_file(args.quota_state_file.clone())
+        .relay_buffer_size(usize::try_from(args.relay_buffer_size).unwrap_or(usize::MAX))
+        .splice_pipe_size(usize::try_from(args.splice_pipe_size).unwrap_or(usize::MAX))
+        .watch_files(args.watch_files)
+        .reload_on_sighup(true)
+        .socket_activation(true)
+        .upgrade_on_sigusr2(true)
+        .drain_timeout(Duration::from_secs(args.drain_timeout_seconds));
+    for listener in listeners {
+        builder = builder.listener(listener);
     }
-
-    if tls_acceptor.is_some() {
-        info!("TLS encryption enabled");
+    for upstream in &args.upstreams {
+        builder = builder.upstream(upstream.as_str());
     }
-
-    // Channel creation for Graceful Shutdown
-    let (shutdown_tx, _) = broadcast::channel::<()>(1);
-    let shutdown_tx_clone = shutdown_tx.clone();
-
-    // Ctrl+C signal handler
-    tokio::spawn(async move {
-        match ctrl_c().await {
-            Ok(()) => {
-                info!("Shutdown signal received, initiating graceful shutdown...");
-                let _ = shutdown_tx_clone.send(());
-            }
-            Err(err) => {
-                error!("Failed to listen for shutdown signal: {}", err);
-            }
-        }
-    });
-
-    // Periodic statistics reporting task
-    let stats_clone = Arc::clone(&stats);
-    let mut shutdown_rx = shutdown_tx.subscribe();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(60));
-        loop {
-            tokio::select! {
-                _ = interval.tick() => {
-                    info!(
-                        "Stats: Active={}, Total={}, Uptime={}s",
-                        stats_clone.active_connections.load(Ordering::Relaxed),
-                        stats_clone.total_connections.load(Ordering::Relaxed),
-                        stats_clone.start_time.elapsed().as_secs()
-                    );
-                }
-                _ = shutdown_rx.recv() => {
-                    debug!("Stats reporter received shutdown signal");
-                    break;
-                }
-            }
-        }
-    });
-
-    // Connection processing loop
-    let mut active_tasks = JoinSet::new();
-    let mut shutdown_rx = shutdown_tx.subscribe();
-
-    loop {
-        tokio::select! {
-            accept_result = listener.accept() => {
-                match accept_result {
-                    Ok((socket, addr)) => {
-                        // Check maximum number of connections
-                        let active_count = stats.active_connections.load(Ordering::Relaxed);
-                        if active_count >= args.max_connections {
-                            warn!("Max connections ({}) reached, rejecting {}", args.max_connections, addr);
-                            continue;
-                        }
-
-                        stats.active_connections.fetch_add(1, Ordering::SeqCst);
-                        stats.total_connections.fetch_add(1, Ordering::SeqCst);
-
-                        // Check IP restriction
-                        if let Some(allowed) = &allowed_ips {
-                            if !allowed.is_allowed(&addr.ip()) {
-                                warn!("Connection from {} rejected (not in allowed IPs)", addr);
-                                stats.active_connections.fetch_sub(1, Ordering::SeqCst);
-                                continue;
-                            }
-                        }
-
-                        // Connection processing
-                        let users_clone = users.clone();
-                        let stats_clone = Arc::clone(&stats);
-                        let dns_cache_clone = Arc::clone(&dns_cache);
-                        let timeout_duration = Duration::from_secs(args.timeout_seconds);
-                        let tls_acceptor_clone = tls_acceptor.clone();
-                        let mut shutdown_rx_task = shutdown_tx.subscribe();
-
-                        debug!("New connection from {} (active: {})", addr,
-                               stats.active_connections.load(Ordering::Relaxed));
-
-                        active_tasks.spawn(async move {
-                            let result = if let Some(tls) = tls_acceptor_clone {
-                                match timeout(timeout_duration, tls.accept(socket)).await {
-                                    Ok(Ok(tls_stream)) => {
-                                        debug!("TLS handshake completed with {}", addr);
-                                        tokio::select! {
-                                            result = handle_client(tls_stream, addr, users_clone, timeout_duration, dns_cache_clone) => {
-                                                result
-                                            }
-                                            _ = shutdown_rx_task.recv() => {
-                                                info!("Client handler for {} received shutdown signal", addr);
-                                                return Ok(()); // Shutdown signal received, gracefully exit
-                                            }
-                                        }
-                                    },
-                                    Ok(Err(e)) => {
-                                        error!("TLS handshake failed with {}: {}", addr, e);
-                                        Err(ProxyError::NetworkError(format!("TLS handshake error: {}", e)).into())
-                                    },
-                                    Err(_) => {
-                                        error!("TLS handshake with {} timed out", addr);
-                                        Err(ProxyError::Timeout("TLS handshake timeout".to_string()).into())
-                                    }
-                                }
-                            } else {
-                                tokio::select! {
-                                    result = handle_client(socket, addr, users_clone, timeout_duration, dns_cache_clone) => {
-                                        result
-                                    }
-                                    _ = shutdown_rx_task.recv() => {
-                                        info!("Client handler for {} received shutdown signal", addr);
-                                        return Ok(()); // Shutdown signal received, gracefully exit
-                                    }
-                                }
-                            };
-
-                            // Decrease active connection count upon connection closure
-                            let prev_count = stats_clone.active_connections.fetch_sub(1, Ordering::SeqCst);
-
-                            if let Err(ref e) = result {
-                                error!("SOCKS5 error from {}: {} (active: {})",
-                                      addr, e, prev_count - 1);
-                            } else {
-                                debug!("Connection from {} closed successfully (active: {})",
-                                      addr, prev_count - 1);
-                            }
-                            result
-                        });
-                    }
-                    Err(e) => {
-                        error!("Error accepting connection: {}", e);
-                    }
-                }
-            }
-            _ = shutdown_rx.recv() => {
-                info!("Main loop received shutdown signal");
-                break;
-            }
-        }
+    if let Some(egress) = args.egress_address {
+        builder = builder.egress(egress);
     }
-
-    // Wait for all active connections to complete
-    info!(
-        "Waiting for {} active connections to complete...",
-        active_tasks.len()
-    );
-
-    // Wait for all tasks to complete using JoinSet
-    while let Some(result) = active_tasks.join_next().await {
-        match result {
-            Ok(Ok(_)) => debug!("Task completed successfully"),
-            Ok(Err(e)) => error!("Task completed with error: {}", e),
-            Err(e) => error!("Task join error: {}", e),
+    if let Some(path) = &args.rules_file {
+        builder = builder.rules_file(path);
+    }
+    if let Some(path) = &args.dest_acl {
+        builder = builder.destination_acl(path);
+    }
+    if let Some(rate) = args.upload_limit {
+        builder = builder.upload_limit(rate);
+    }
+    if let Some(rate) = args.download_limit {
+        builder = builder.download_limit(rate);
+    }
+    if let Some(quota) = args.quota {
+        builder = builder.quota(quota);
+    }
+    if let Some(path) = &args.access_log {
+        builder = builder.access_log(path, args.access_log_rotation, args.access_log_keep);
+    }
+    if let Some(metrics_addr) = args.metrics_addr {
+        builder = builder.metrics_addr(metrics_addr);
+    }
+    if let (Some(admin_addr), Some(token_file)) = (args.admin_addr, &args.admin_token_file) {
+        let token = tokio::fs::read_to_string(token_file)
+            .await
+            .context(format!("Failed to read admin token file: {:?}", token_file))?
+            .trim()
+            .to_string();
+        if token.is_empty() {
+            return Err(ProxyError::ConfigError(format!(
+                "Admin token file is empty: {:?}",
+                token_file
+            ))
+            .into());
         }
+        builder = builder.admin(admin_addr, token);
     }
 
-    info!("SOCKS5 proxy shutdown completed");
-    Ok(())
+    // Serve until Ctrl+C
+    let server = builder.build().await?;
+    server
+        .run(async {
+            if let Err(err) = ctrl_c().await {
+                error!("Failed to listen for shutdown signal: {}", err);
+                std::future::pending::<()>().await;
+            }
+        })
+        .await
 }
@@ -0,0 +1,318 @@
+// Prometheus metrics and the HTTP endpoint that exposes them
+use crate::ProxyError;
+use crate::dns::DnsCache;
+use crate::server::Stats;
+use anyhow::Result;
+use log::{debug, info};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+// Upper bounds of the connect latency histogram buckets (seconds)
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+// Upper bound for a metrics request header
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// Bytes relayed for one user
+#[derive(Default)]
+pub(crate) struct Traffic {
+    pub(crate) up: AtomicU64,
+    pub(crate) down: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Event counters; connection counts and uptime come from Stats
+#[derive(Default)]
+pub(crate) struct Metrics {
+    handshake_failures: Mutex<BTreeMap<&'static str, u64>>,
+    auth_successes: AtomicU64,
+    auth_failures: AtomicU64,
+    traffic: Mutex<HashMap<String, Arc<Traffic>>>,
+    connect_latency: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Metrics {
+    // Count a session that failed before data was relayed, by ProxyError variant
+    pub(crate) fn record_handshake_failure(&self, error: &anyhow::Error) {
+        let kind = error
+            .downcast_ref::<ProxyError>()
+            .map_or("Other", ProxyError::kind);
+        *self
+            .handshake_failures
+            .lock()
+            .unwrap()
+            .entry(kind)
+            .or_default() += 1;
+    }
+
+    pub(crate) fn record_auth(&self, success: bool) {
+        let counter = if success {
+            &self.auth_successes
+        } else {
+            &self.auth_failures
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Time from starting the outbound connection to having it, per egress route
+    pub(crate) fn record_connect_latency(&self, route: &str, latency: Duration) {
+        self.connect_latency
+            .lock()
+            .unwrap()
+            .entry(route.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    // Traffic counters of a user, sessions without a user are counted under ""
+    pub(crate) fn traffic(&self, user: Option<&str>) -> Arc<Traffic> {
+        let mut traffic = self.traffic.lock().unwrap();
+        Arc::clone(
+            traffic
+                .entry(user.unwrap_or_default().to_string())
+                .or_default(),
+        )
+    }
+
+    // Prometheus text exposition format
+    fn render(&self, stats: &Stats, dns_cache: Option<&DnsCache>) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+        let mut out = Exposition::default();
+
+        out.family("active_connections", "gauge", "Connections being handled");
+        out.sample(
+            "",
+            "",
+            stats.active_connections.load(Ordering::Relaxed) as f64,
+        );
+
+        out.family("connections_total", "counter", "Connections accepted");
+        out.sample(
+            "",
+            "",
+            stats.total_connections.load(Ordering::Relaxed) as f64,
+        );
+
+        out.family(
+            "rejected_connections_total",
+            "counter",
+            "Connections and sessions turned away for lack of capacity",
+        );
+        out.sample(
+            "",
+            "",
+            stats.rejected_connections.load(Ordering::Relaxed) as f64,
+        );
+
+        out.family(
+            "handshake_failures_total",
+            "counter",
+            "Sessions that failed before relaying data, by error",
+        );
+        for (kind, count) in self.handshake_failures.lock().unwrap().iter() {
+            out.sample("", &format!("error=\"{}\"", kind), *count as f64);
+        }
+
+        out.family("auth_total", "counter", "Authentication attempts by result");
+        out.sample("", "result=\"success\"", load(&self.auth_successes));
+        out.sample("", "result=\"failure\"", load(&self.auth_failures));
+
+        out.family(
+            "bytes_total",
+            "counter",
+            "Bytes relayed per user and direction",
+        );
+        let traffic = self.traffic.lock().unwrap();
+        let mut users: Vec<_> = traffic.iter().collect();
+        users.sort_by(|a, b| a.0.cmp(b.0));
+        for (user, traffic) in users {
+            let user = escape_label(user);
+            for (direction, bytes) in [("up", &traffic.up), ("down", &traffic.down)] {
+                let labels = format!("user=\"{}\",direction=\"{}\"", user, direction);
+                out.sample("", &labels, load(bytes));
+            }
+        }
+        drop(traffic);
+
+        out.family(
+            "connect_duration_seconds",
+            "histogram",
+            "Outbound connect latency per egress route",
+        );
+        for (route, histogram) in self.connect_latency.lock().unwrap().iter() {
+            let route = format!("route=\"{}\"", escape_label(route));
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let labels = format!("{},le=\"{}\"", route, bound);
+                out.sample("_bucket", &labels, count as f64);
+            }
+            let labels = format!("{},le=\"+Inf\"", route);
+            out.sample("_bucket", &labels, histogram.count as f64);
+            out.sample("_sum", &route, histogram.sum);
+            out.sample("_count", &route, histogram.count as f64);
+        }
+
+        // A custom resolver has no cache to report on
+        if let Some(dns_cache) = dns_cache {
+            out.family(
+                "dns_cache_requests_total",
+                "counter",
+                "DNS cache lookups by result",
+            );
+            out.sample("", "result=\"hit\"", load(&dns_cache.hits));
+            out.sample("", "result=\"miss\"", load(&dns_cache.misses));
+        }
+
+        out.family("uptime_seconds", "gauge", "Seconds since the proxy started");
+        out.sample("", "", stats.start_time.elapsed().as_secs_f64());
+
+        out.text
+    }
+}
+
+// Text exposition writer, samples belong to the last declared family
+#[derive(Default)]
+struct Exposition {
+    text: String,
+    name: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        self.name = format!("socks5_proxy_{}", name);
+        let _ = writeln!(self.text, "# HELP {} {}", self.name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", self.name, kind);
+    }
+
+    fn sample(&mut self, suffix: &str, labels: &str, value: f64) {
+        let _ = if labels.is_empty() {
+            writeln!(self.text, "{}{} {}", self.name, suffix, value)
+        } else {
+            writeln!(self.text, "{}{}{{{}}} {}", self.name, suffix, labels, value)
+        };
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Serve GET /metrics until shutdown
+pub(crate) async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    stats: Arc<Stats>,
+    dns_cache: Option<Arc<DnsCache>>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            accept_result = listener.accept() => {
+                let (stream, addr) = match accept_result {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        debug!("Error accepting metrics connection: {}", e);
+                        continue;
+                    }
+                };
+                let metrics = Arc::clone(&metrics);
+                let stats = Arc::clone(&stats);
+                let dns_cache = dns_cache.clone();
+                tokio::spawn(async move {
+                    let result = timeout(
+                        Duration::from_secs(10),
+                        handle_request(stream, &metrics, &stats, dns_cache.as_deref()),
+                    )
+                    .await;
+                    if let Ok(Err(e)) = result {
+                        debug!("Metrics request from {} failed: {}", addr, e);
+                    }
+                });
+            }
+            _ = shutdown_rx.recv() => {
+                info!("Metrics endpoint received shutdown signal");
+                break;
+            }
+        }
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    stats: &Stats,
+    dns_cache: Option<&DnsCache>,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return Err(anyhow::anyhow!("Metrics request header too large"));
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut request = httparse::Request::new(&mut headers);
+    let parsed = request.parse(&buf).is_ok();
+    let path = request.path.unwrap_or_default();
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+    let (status, content_type, body) = if !parsed {
+        ("400 Bad Request", "text/plain", String::new())
+    } else if request.method != Some("GET") {
+        ("405 Method Not Allowed", "text/plain", String::new())
+    } else if path == "/metrics" {
+        (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.render(stats, dns_cache),
+        )
+    } else {
+        ("404 Not Found", "text/plain", String::new())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,128 @@
+// HAProxy PROXY protocol v1 and v2 headers sent by load balancers ahead of the client's data
+use crate::ProxyError;
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Start of every v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// Longest v1 header including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+// v2 commands
+const V2_LOCAL: u8 = 0x0;
+const V2_PROXY: u8 = 0x1;
+
+// v2 address families with the stream transport
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+fn invalid(reason: &str) -> anyhow::Error {
+    ProxyError::NetworkError(format!("Invalid PROXY protocol header: {}", reason)).into()
+}
+
+// Read the header and return the client address it carries. None when the header does
+// not name one (v1 UNKNOWN, v2 LOCAL or unspecified family), the connection's own peer
+// address then applies. Reads exactly the header so the client's data stays in the stream
+pub(crate) async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // The shortest v1 header, "PROXY UNKNOWN\r\n", is longer than the v2 signature
+    let mut start = [0u8; 12];
+    stream
+        .read_exact(&mut start)
+        .await
+        .context("Failed to read PROXY protocol header")?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol signature"))
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Byte by byte, anything past the CRLF belongs to the client
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(
+            stream
+                .read_u8()
+                .await
+                .context("Failed to read PROXY protocol header")?,
+        );
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("not ASCII"))?;
+    // PROXY, family, source, destination, source port, destination port
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad source address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("bad source port"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("address does not match the protocol family"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed v1 header")),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Version and command, family and transport, address length
+    let mut header = [0u8; 4];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("Failed to read PROXY protocol header")?;
+    if header[0] >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+
+    // The addresses are followed by optional TLVs, which are not used
+    let mut payload = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+    stream
+        .read_exact(&mut payload)
+        .await
+        .context("Failed to read PROXY protocol addresses")?;
+
+    match header[0] & 0x0F {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+
+    // Source address, destination address, source port, destination port; the destination
+    // is the balancer's own frontend
+    match header[1] {
+        V2_UNSPEC => Ok(None),
+        V2_TCP4 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[..4]).unwrap());
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_TCP6 if payload.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[..16]).unwrap());
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_TCP4 | V2_TCP6 => Err(invalid("address block too short")),
+        _ => Err(invalid("unsupported address family")),
+    }
+}
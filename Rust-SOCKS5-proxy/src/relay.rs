@@ -0,0 +1,266 @@
+// Relay of an established tunnel: half-close in each direction, idle timeout and byte counts
+use crate::access_log::CloseReason;
+use crate::context::ServerContext;
+use crate::limits::{Direction, UserLimiter};
+use crate::sessions::Session;
+#[cfg(target_os = "linux")]
+use crate::splice::Pipe;
+use log::info;
+#[cfg(target_os = "linux")]
+use log::{debug, warn};
+#[cfg(target_os = "linux")]
+use std::any::Any;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(target_os = "linux")]
+use tokio::net::tcp::WriteHalf;
+
+// How tunnels move their bytes
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RelaySettings {
+    // Largest chunk moved by one read and write on the buffered path
+    pub(crate) buffer_size: usize,
+    // Pipe size for splice(2) between plain TCP sockets on Linux, 0 to always buffer
+    pub(crate) pipe_size: usize,
+}
+
+// Bytes written to the other side in each direction
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Transferred {
+    pub(crate) up: u64,
+    pub(crate) down: u64,
+}
+
+impl std::fmt::Display for Transferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes up, {} bytes down", self.up, self.down)
+    }
+}
+
+// Time of the last read or write in either direction
+struct Activity {
+    start: Instant,
+    // Milliseconds since start
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    // Resolves once there was no traffic for the idle timeout, never without one
+    async fn idle(&self, idle_timeout: Option<Duration>) {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = last + idle_timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+// One direction of the tunnel
+struct Half<'a> {
+    session: &'a Session,
+    direction: Direction,
+    // Why the session ends when this direction sees EOF first
+    close_reason: CloseReason,
+    // Per-user traffic metric and session counter
+    counters: [&'a AtomicU64; 2],
+    written: AtomicU64,
+    limiter: Option<&'a UserLimiter>,
+    activity: &'a Activity,
+}
+
+impl Half<'_> {
+    // Copy until EOF, then pass the half-close on so the other side sees it too
+    async fn copy<R, W>(&self, reader: &mut R, writer: &mut W, size: usize) -> std::io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; self.chunk_size(size)];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            self.received(n).await?;
+            writer.write_all(&buf[..n]).await?;
+            writer.flush().await?;
+            self.delivered(n);
+        }
+        self.finish(writer).await
+    }
+
+    // Like copy, but the bytes go from socket to pipe to socket without leaving the kernel
+    #[cfg(target_os = "linux")]
+    async fn splice(
+        &self,
+        reader: &TcpStream,
+        writer: &mut WriteHalf<'_>,
+        pipe: &Pipe,
+    ) -> std::io::Result<()> {
+        let chunk_size = self.chunk_size(pipe.size());
+        loop {
+            let n = pipe.fill(reader, chunk_size).await?;
+            if n == 0 {
+                break;
+            }
+            self.received(n).await?;
+            pipe.drain(writer.as_ref(), n).await?;
+            self.delivered(n);
+        }
+        self.finish(writer).await
+    }
+
+    // Smaller chunks for slow rates keep the traffic smooth
+    fn chunk_size(&self, size: usize) -> usize {
+        self.limiter
+            .map_or(size, |limiter| limiter.chunk_size(self.direction, size))
+    }
+
+    // Wait for bandwidth and charge the quota for bytes about to be forwarded
+    async fn received(&self, n: usize) -> std::io::Result<()> {
+        self.activity.touch();
+        match self.limiter {
+            Some(limiter) => limiter.acquire(self.direction, n).await,
+            None => Ok(()),
+        }
+    }
+
+    // Only bytes the other side was handed are counted
+    fn delivered(&self, n: usize) {
+        self.activity.touch();
+        for counter in self.counters.iter().copied().chain([&self.written]) {
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    async fn finish<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<()> {
+        // The direction that finishes first tells which side closed the connection
+        self.session.record_close(self.close_reason);
+        writer.shutdown().await
+    }
+
+    fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+// Relay until both directions are closed, the tunnel idles past the idle timeout or the
+// user's quota runs out
+pub(crate) async fn relay<T>(
+    socket: &mut T,
+    remote: &mut TcpStream,
+    session: &Session,
+    context: &ServerContext,
+) -> std::io::Result<Transferred>
+where
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    session.establish();
+    let traffic = context.metrics.traffic(session.user());
+    let limiter = context.limiter(session.user());
+    let activity = Activity::new();
+
+    let upload = Half {
+        session,
+        direction: Direction::Upload,
+        close_reason: CloseReason::ClientClosed,
+        counters: [&traffic.up, &session.bytes_up],
+        written: AtomicU64::new(0),
+        limiter: limiter.as_deref(),
+        activity: &activity,
+    };
+    let download = Half {
+        session,
+        direction: Direction::Download,
+        close_reason: CloseReason::RemoteClosed,
+        counters: [&traffic.down, &session.bytes_down],
+        written: AtomicU64::new(0),
+        limiter: limiter.as_deref(),
+        activity: &activity,
+    };
+
+    let settings = context.relay;
+    let transfer = async {
+        #[cfg(target_os = "linux")]
+        if let Some(client) = (socket as &mut dyn Any).downcast_mut::<TcpStream>()
+            && let Some((up, down)) = pipes(settings.pipe_size)
+        {
+            debug!("{} relaying with splice", session.client);
+            let (client_read, mut client_write) = client.split();
+            let (remote_read, mut remote_write) = remote.split();
+            return tokio::try_join!(
+                upload.splice(client_read.as_ref(), &mut remote_write, &up),
+                download.splice(remote_read.as_ref(), &mut client_write, &down),
+            );
+        }
+
+        let (mut ri, mut wi) = tokio::io::split(socket);
+        let (mut ro, mut wo) = remote.split();
+        tokio::try_join!(
+            upload.copy(&mut ri, &mut wo, settings.buffer_size),
+            download.copy(&mut ro, &mut wi, settings.buffer_size),
+        )
+    };
+
+    let result = tokio::select! {
+        result = transfer => result.map(|_| ()),
+        _ = activity.idle(context.idle_timeout) => {
+            let idle_timeout = context.idle_timeout.unwrap_or_default();
+            info!("{} tunnel idle for {}s, closing", session.client, idle_timeout.as_secs());
+            session.record_close(CloseReason::IdleTimeout);
+            Ok(())
+        }
+        _ = async {
+            match &limiter {
+                Some(limiter) => limiter.wait_exhausted().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            session.record_close(CloseReason::QuotaExhausted);
+            Err(std::io::Error::other("Transfer quota exhausted"))
+        }
+    };
+
+    result.map(|()| Transferred {
+        up: upload.written(),
+        down: download.written(),
+    })
+}
+
+// One pipe per direction; splicing is off when the size is 0 or pipes cannot be had
+#[cfg(target_os = "linux")]
+fn pipes(size: usize) -> Option<(Pipe, Pipe)> {
+    if size == 0 {
+        return None;
+    }
+    match Pipe::new(size).and_then(|up| Ok((up, Pipe::new(size)?))) {
+        Ok(pipes) => Some(pipes),
+        Err(e) => {
+            warn!(
+                "Failed to create splice pipes, copying through buffers: {}",
+                e
+            );
+            None
+        }
+    }
+}
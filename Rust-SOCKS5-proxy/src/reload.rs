@@ -0,0 +1,259 @@
+// Hot reload of credentials, IP allow list and TLS certificates
+use crate::ProxyError;
+use crate::auth::{Authenticator, HtpasswdAuthenticator, HttpCallbackAuthenticator, Users};
+use crate::config::{AuthBackend, ListenerSettings};
+use crate::context::{AllowedIPs, ServerContext};
+use crate::server::setup_tls;
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+use tokio_rustls::TlsAcceptor;
+
+// How often watched files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+// Settings of a listener that are reloaded together
+pub(crate) struct ReloadableConfig {
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) users: Option<Arc<Users>>,
+    pub(crate) allowed_ips: Option<Arc<AllowedIPs>>,
+    pub(crate) tls_acceptor: Option<TlsAcceptor>,
+}
+
+impl ReloadableConfig {
+    // Everything is loaded before anything is applied, so one bad file keeps the
+    // current configuration as a whole
+    pub(crate) async fn load(settings: &ListenerSettings) -> Result<Self> {
+        let tls_acceptor = match &settings.tls {
+            Some(tls) => Some(setup_tls(tls).await.context("Failed to setup TLS")?),
+            None => None,
+        };
+
+        // Per-user options are only available with the auth file backend
+        let mut users = None;
+        let authenticator: Option<Arc<dyn Authenticator>> = match &settings.auth {
+            None => None,
+            Some(auth) => match auth.backend {
+                AuthBackend::Htpasswd => Some(Arc::new(
+                    HtpasswdAuthenticator::load(Path::new(&auth.file)).await?,
+                )),
+                AuthBackend::Http => {
+                    let url = auth.url.as_deref().ok_or_else(|| {
+                        ProxyError::ConfigError("The http auth backend requires a URL".to_string())
+                    })?;
+                    Some(Arc::new(HttpCallbackAuthenticator::new(
+                        url,
+                        settings.timeout(),
+                    )?))
+                }
+                AuthBackend::File => {
+                    let file_users = Arc::new(Users::load(&auth.file).await?);
+                    users = Some(Arc::clone(&file_users));
+                    Some(file_users)
+                }
+            },
+        };
+
+        Ok(ReloadableConfig {
+            authenticator,
+            users,
+            allowed_ips: load_allowed_ips(settings).await?,
+            tls_acceptor,
+        })
+    }
+}
+
+// Combine the listed allowed IPs with the entries of the allowed IPs file (one or more per line)
+async fn load_allowed_ips(settings: &ListenerSettings) -> Result<Option<Arc<AllowedIPs>>> {
+    if settings.allowed_ips.is_empty() && settings.allowed_ips_file.is_none() {
+        return Ok(None);
+    }
+
+    let mut entries = settings.allowed_ips.clone();
+    if let Some(path) = &settings.allowed_ips_file {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .context(format!("Failed to read allowed IPs file: {:?}", path))?;
+        entries.extend(
+            content
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+    }
+
+    match AllowedIPs::new(&entries.join(",")) {
+        Ok(allowed) => Ok(Some(Arc::new(allowed))),
+        Err(e) => {
+            Err(ProxyError::ConfigError(format!("Failed to parse allowed IPs: {}", e)).into())
+        }
+    }
+}
+
+// The context new connections of a listener start with; sessions keep the snapshot they
+// started with
+pub(crate) struct SharedContext {
+    settings: ListenerSettings,
+    current: RwLock<Arc<ServerContext>>,
+    // New connections are closed while draining, sessions in progress continue
+    draining: AtomicBool,
+}
+
+impl SharedContext {
+    pub(crate) fn new(settings: ListenerSettings, context: ServerContext) -> Self {
+        SharedContext {
+            settings,
+            current: RwLock::new(Arc::new(context)),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn settings(&self) -> &ListenerSettings {
+        &self.settings
+    }
+
+    pub(crate) fn current(&self) -> Arc<ServerContext> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    pub(crate) fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    // Load the reloadable settings and swap them in as one unit
+    pub(crate) async fn reload(&self) -> Result<()> {
+        let config = ReloadableConfig::load(&self.settings).await?;
+
+        let mut next = ServerContext::clone(&self.current());
+        // Without auth settings the listener keeps the authenticator it was built with
+        if self.settings.auth.is_some() {
+            next.authenticator = config.authenticator;
+            next.users = config.users;
+        }
+        next.allowed_ips = config.allowed_ips;
+        next.tls_acceptor = config.tls_acceptor;
+        *self.current.write().unwrap() = Arc::new(next);
+        Ok(())
+    }
+}
+
+// Reload every listener on SIGHUP if enabled and, when watching files, whenever one changes
+pub(crate) async fn run(
+    listeners: Vec<Arc<SharedContext>>,
+    watch_files: bool,
+    sighup: bool,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    #[cfg(unix)]
+    let mut hangup = match sighup
+        .then(|| tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()))
+    {
+        Some(Ok(hangup)) => Some(hangup),
+        Some(Err(e)) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            None
+        }
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let _ = sighup;
+
+    let files: Vec<PathBuf> = listeners
+        .iter()
+        .flat_map(|listener| watched_files(listener.settings()))
+        .collect();
+    let mut modified = modification_times(&files).await;
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+    loop {
+        #[cfg(unix)]
+        let hangup_received = async {
+            match &mut hangup {
+                Some(hangup) => hangup.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<Option<()>>();
+
+        let reason = tokio::select! {
+            _ = hangup_received => "SIGHUP received",
+            _ = interval.tick(), if watch_files => {
+                let current = modification_times(&files).await;
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+                "configuration file changed"
+            }
+            _ = shutdown_rx.recv() => {
+                debug!("Config reloader received shutdown signal");
+                break;
+            }
+        };
+
+        info!("Reloading configuration ({})", reason);
+        let mut reloaded = false;
+        for listener in &listeners {
+            let name = &listener.settings().name;
+            match listener.reload().await {
+                Ok(()) => {
+                    info!("Configuration of listener {} reloaded", name);
+                    reloaded = true;
+                }
+                Err(e) => error!(
+                    "Reload of listener {} failed, keeping current configuration: {:#}",
+                    name, e
+                ),
+            }
+        }
+
+        // Per-user limiters are rebuilt from the new user settings; when every reload
+        // failed the old settings stay and so do the limiters and their token buckets
+        if reloaded
+            && let Some(limits) = listeners
+                .first()
+                .and_then(|listener| listener.current().limits.clone())
+        {
+            limits.reset();
+        }
+    }
+}
+
+fn watched_files(settings: &ListenerSettings) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Some(auth) = &settings.auth
+        && auth.backend != AuthBackend::Http
+    {
+        files.push(PathBuf::from(&auth.file));
+    }
+    files.extend(settings.allowed_ips_file.clone());
+    if let Some(tls) = &settings.tls {
+        files.push(tls.cert.clone());
+        files.push(tls.key.clone());
+        files.extend(tls.client_ca.clone());
+        files.extend(tls.client_crl.clone());
+    }
+    files
+}
+
+async fn modification_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(files.len());
+    for file in files {
+        let modified = tokio::fs::metadata(file)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        times.push(modified);
+    }
+    times
+}
@@ -0,0 +1,170 @@
+// SOCKS5 requests and replies (RFC 1928, sections 4 to 6)
+use crate::{
+    ADDR_TYPE_DOMAIN, ADDR_TYPE_IPV4, ADDR_TYPE_IPV6, BIND_COMMAND, CONNECT_COMMAND, ProxyError,
+    SOCKS_VERSION, UDP_ASSOCIATE_COMMAND,
+};
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Destination as requested by the client (DST.ADDR, DST.PORT)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    pub(crate) fn domain(&self) -> Option<&str> {
+        match self {
+            Address::Ip(_) => None,
+            Address::Domain(domain, _) => Some(domain),
+        }
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        match self {
+            Address::Ip(socket_addr) => socket_addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Ip(socket_addr) => write!(f, "{}", socket_addr),
+            Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub command: Command,
+    pub address: Address,
+}
+
+// Read a request once the method negotiation is done. An unknown address type fails with
+// ProxyError::UnsupportedAddressType and an unknown command, after its address has been
+// read, with ProxyError::UnsupportedCommand; the caller answers both with a reply
+pub async fn read_request<S>(stream: &mut S) -> Result<Request>
+where
+    S: AsyncRead + Unpin,
+{
+    // VER, CMD, RSV, ATYP
+    let mut header = [0u8; 4];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("Failed to read request header")?;
+
+    if header[0] != SOCKS_VERSION {
+        return Err(ProxyError::InvalidVersion.into());
+    }
+
+    let address = read_address(stream, header[3]).await?;
+    let command = match header[1] {
+        CONNECT_COMMAND => Command::Connect,
+        BIND_COMMAND => Command::Bind,
+        UDP_ASSOCIATE_COMMAND => Command::UdpAssociate,
+        _ => return Err(ProxyError::UnsupportedCommand.into()),
+    };
+    Ok(Request { command, address })
+}
+
+// Reply with REP and BND.ADDR, BND.PORT: VER, REP, RSV, ATYP, BND.ADDR, BND.PORT
+pub async fn write_reply<S>(stream: &mut S, reply_code: u8, bind_addr: SocketAddr) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut reply = vec![
+        SOCKS_VERSION,
+        reply_code,
+        0x00, // Reserved field must be 0x00
+    ];
+    encode_socket_addr(&bind_addr, &mut reply);
+
+    stream
+        .write_all(&reply)
+        .await
+        .context("Failed to send SOCKS5 reply")?;
+    Ok(())
+}
+
+// Read the DST.ADDR and DST.PORT fields of a request for the given address type
+pub(crate) async fn read_address<T>(socket: &mut T, addr_type: u8) -> Result<Address>
+where
+    T: AsyncRead + Unpin,
+{
+    match addr_type {
+        ADDR_TYPE_IPV4 => {
+            let mut ipv4 = [0u8; 4];
+            socket
+                .read_exact(&mut ipv4)
+                .await
+                .context("Failed to read IPv4 address")?;
+            let ip = IpAddr::V4(Ipv4Addr::from(ipv4));
+            Ok(Address::Ip(SocketAddr::new(ip, read_port(socket).await?)))
+        }
+        ADDR_TYPE_DOMAIN => {
+            let mut domain_length = [0u8; 1];
+            socket
+                .read_exact(&mut domain_length)
+                .await
+                .context("Failed to read domain length")?;
+            let domain_len = domain_length[0] as usize;
+            let mut domain = vec![0u8; domain_len];
+            socket
+                .read_exact(&mut domain)
+                .await
+                .context("Failed to read domain name")?;
+            let domain_str = String::from_utf8(domain).context("Domain is not valid UTF-8")?;
+            Ok(Address::Domain(domain_str, read_port(socket).await?))
+        }
+        ADDR_TYPE_IPV6 => {
+            let mut ipv6 = [0u8; 16];
+            socket
+                .read_exact(&mut ipv6)
+                .await
+                .context("Failed to read IPv6 address")?;
+            let ip = IpAddr::V6(Ipv6Addr::from(ipv6));
+            Ok(Address::Ip(SocketAddr::new(ip, read_port(socket).await?)))
+        }
+        _ => Err(ProxyError::UnsupportedAddressType.into()),
+    }
+}
+
+async fn read_port<T>(socket: &mut T) -> Result<u16>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut port_buf = [0u8; 2];
+    socket
+        .read_exact(&mut port_buf)
+        .await
+        .context("Failed to read port")?;
+    Ok(u16::from_be_bytes(port_buf))
+}
+
+// Append ATYP, address and port in SOCKS5 wire format
+pub(crate) fn encode_socket_addr(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            buf.push(ADDR_TYPE_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ADDR_TYPE_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
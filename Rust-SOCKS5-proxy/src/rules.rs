@@ -0,0 +1,412 @@
+// Rule-based egress routing
+use crate::ProxyError;
+use crate::connect::{Egress, resolve_address};
+use crate::dns::Resolver;
+use crate::request::Address;
+use crate::upstream::Upstream;
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Egress route selected for a destination
+#[derive(Clone)]
+pub(crate) enum Route {
+    // Direct connection, from the rule's egress addresses if it names any
+    Direct(Egress),
+    Reject,
+    Upstream(Arc<Upstream>),
+}
+
+// Destination properties a matcher is evaluated against
+pub(crate) struct Destination<'a> {
+    pub(crate) domain: Option<&'a str>,
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) port: u16,
+}
+
+// Single condition of a rule, a list matches when any of its entries matches
+pub(crate) enum Matcher {
+    // "example.com" matches the domain and its subdomains, "*.example.com" only subdomains
+    Domain(Vec<String>),
+    Cidr(Vec<IpNet>),
+    Port(Vec<RangeInclusive<u16>>),
+}
+
+impl Matcher {
+    // Parse a "domain:", "cidr:" or "port:" token with comma-separated values
+    pub(crate) fn parse(token: &str) -> Result<Self, String> {
+        let (kind, values) = token
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid matcher: {}", token))?;
+        let values = values.split(',').map(str::trim).filter(|v| !v.is_empty());
+
+        let matcher = match kind {
+            "domain" => Matcher::Domain(
+                values
+                    .map(|v| v.trim_end_matches('.').to_ascii_lowercase())
+                    .collect(),
+            ),
+            "cidr" => Matcher::Cidr(
+                values
+                    .map(parse_networks)
+                    .collect::<Result<Vec<_>, _>>()?
+                    .concat(),
+            ),
+            "port" => Matcher::Port(
+                values
+                    .map(parse_port_range)
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            _ => return Err(format!("Unknown matcher type: {}", kind)),
+        };
+
+        let is_empty = match &matcher {
+            Matcher::Domain(v) => v.is_empty(),
+            Matcher::Cidr(v) => v.is_empty(),
+            Matcher::Port(v) => v.is_empty(),
+        };
+        if is_empty {
+            return Err(format!("Matcher without values: {}", token));
+        }
+
+        Ok(matcher)
+    }
+
+    pub(crate) fn needs_ip(&self) -> bool {
+        matches!(self, Matcher::Cidr(_))
+    }
+
+    pub(crate) fn matches(&self, dest: &Destination) -> bool {
+        match self {
+            Matcher::Domain(patterns) => dest.domain.is_some_and(|domain| {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                patterns
+                    .iter()
+                    .any(|pattern| domain_matches(pattern, &domain))
+            }),
+            Matcher::Cidr(networks) => dest.ip.is_some_and(|ip| {
+                let ip = ip.to_canonical();
+                networks.iter().any(|network| network.contains(&ip))
+            }),
+            Matcher::Port(ranges) => ranges.iter().any(|range| range.contains(&dest.port)),
+        }
+    }
+}
+
+fn domain_matches(pattern: &str, domain: &str) -> bool {
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        return domain.len() > suffix.len() && domain.ends_with(&format!(".{}", suffix));
+    }
+    let pattern = pattern.trim_start_matches('.');
+    domain == pattern || domain.ends_with(&format!(".{}", pattern))
+}
+
+// Loopback, private, link-local, shared and unspecified ranges covered by "cidr:private"
+const PRIVATE_NETWORKS: [&str; 12] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+// Accept CIDR notation, bare addresses and the "private" alias
+fn parse_networks(value: &str) -> Result<Vec<IpNet>, String> {
+    if value.eq_ignore_ascii_case("private") {
+        return Ok(PRIVATE_NETWORKS
+            .iter()
+            .map(|network| IpNet::from_str(network).unwrap())
+            .collect());
+    }
+
+    IpNet::from_str(value)
+        .or_else(|_| IpAddr::from_str(value).map(IpNet::from))
+        .map(|network| vec![network])
+        .map_err(|_| format!("Invalid network: {}", value))
+}
+
+fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("Invalid port range: {}", value);
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+    let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+    let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+    if start > end {
+        return Err(invalid());
+    }
+    Ok(start..=end)
+}
+
+// Ordered rule, all matchers must match for the route to be selected
+struct Rule {
+    matchers: Vec<Matcher>,
+    route: Route,
+}
+
+pub(crate) struct Router {
+    upstreams: HashMap<String, Arc<Upstream>>,
+    rules: Vec<Rule>,
+}
+
+impl Router {
+    pub(crate) async fn load(upstream_specs: &[String], rules_file: Option<&Path>) -> Result<Self> {
+        let mut upstreams = HashMap::new();
+        for spec in upstream_specs {
+            let upstream = Upstream::from_str(spec)?;
+            if upstreams.contains_key(&upstream.name) {
+                return Err(ProxyError::ConfigError(format!(
+                    "Duplicate upstream name: {}",
+                    upstream.name
+                ))
+                .into());
+            }
+            upstreams.insert(upstream.name.clone(), Arc::new(upstream));
+        }
+
+        let rules = match rules_file {
+            Some(path) => {
+                let content = tokio::fs::read_to_string(path)
+                    .await
+                    .context(format!("Failed to read rules file: {:?}", path))?;
+                parse_rules(&content, &upstreams)?
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Router { upstreams, rules })
+    }
+
+    pub(crate) fn upstream_count(&self) -> usize {
+        self.upstreams.len()
+    }
+
+    pub(crate) fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    // Return the route of the first matching rule, direct if none matches
+    pub(crate) async fn route(&self, address: &Address, resolver: &dyn Resolver) -> Route {
+        let (domain, mut ip, port) = match address {
+            Address::Ip(socket_addr) => (None, Some(socket_addr.ip()), socket_addr.port()),
+            Address::Domain(domain, port) => (Some(domain.as_str()), None, *port),
+        };
+        let mut resolved = ip.is_some();
+
+        for rule in &self.rules {
+            // Domains are only resolved once a CIDR rule has to be evaluated
+            if !resolved && rule.matchers.iter().any(Matcher::needs_ip) {
+                resolved = true;
+                match resolve_address(address.clone(), resolver).await {
+                    Ok(target_addr) => ip = target_addr.ips.first().copied(),
+                    Err(e) => debug!("Rule matching without IP for {}: {}", address, e),
+                }
+            }
+
+            let dest = Destination { domain, ip, port };
+            if rule.matchers.iter().all(|matcher| matcher.matches(&dest)) {
+                return rule.route.clone();
+            }
+        }
+
+        Route::Direct(Egress::default())
+    }
+}
+
+// Each line holds whitespace-separated matchers followed by the action, e.g.
+// "domain:corp.example.com port:443 corp"; "direct:203.0.113.5,2001:db8::5" connects
+// directly from the given egress addresses
+fn parse_rules(
+    content: &str,
+    upstreams: &HashMap<String, Arc<Upstream>>,
+) -> Result<Vec<Rule>, ProxyError> {
+    parse_rule_lines(content)?
+        .into_iter()
+        .map(|line| {
+            let invalid = |reason: String| {
+                ProxyError::ConfigError(format!("Line {}: {}", line.line_number, reason))
+            };
+            let route = match line.action {
+                "direct" => Route::Direct(Egress::default()),
+                "reject" => Route::Reject,
+                action if let Some(addresses) = action.strip_prefix("direct:") => {
+                    Route::Direct(addresses.parse().map_err(invalid)?)
+                }
+                name => match upstreams.get(name) {
+                    Some(upstream) => Route::Upstream(Arc::clone(upstream)),
+                    None => return Err(invalid(format!("unknown upstream '{}'", name))),
+                },
+            };
+            Ok(Rule {
+                matchers: line.matchers,
+                route,
+            })
+        })
+        .collect()
+}
+
+// Line of a "matchers... action" file before the action is interpreted
+pub(crate) struct RuleLine<'a> {
+    pub(crate) line_number: usize,
+    pub(crate) matchers: Vec<Matcher>,
+    pub(crate) action: &'a str,
+}
+
+// Split lines into matchers and action, skipping blank lines and comments;
+// "*" or no matchers matches everything
+pub(crate) fn parse_rule_lines(content: &str) -> Result<Vec<RuleLine<'_>>, ProxyError> {
+    let mut lines = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        let action = tokens.pop().unwrap_or_default();
+        let matchers = tokens
+            .into_iter()
+            .filter(|token| *token != "*")
+            .map(Matcher::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ProxyError::ConfigError(format!("Line {}: {}", index + 1, e)))?;
+
+        lines.push(RuleLine {
+            line_number: index + 1,
+            matchers,
+            action,
+        });
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn dest(domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> Destination<'_> {
+        Destination { domain, ip, port }
+    }
+
+    #[test]
+    fn domain_matcher_covers_subdomains() {
+        let matcher = Matcher::parse("domain:Example.com.").unwrap();
+        assert!(matcher.matches(&dest(Some("example.com"), None, 80)));
+        assert!(matcher.matches(&dest(Some("www.EXAMPLE.com."), None, 80)));
+        assert!(!matcher.matches(&dest(Some("badexample.com"), None, 80)));
+        assert!(!matcher.matches(&dest(None, None, 80)));
+    }
+
+    #[test]
+    fn wildcard_domain_matches_only_subdomains() {
+        let matcher = Matcher::parse("domain:*.example.com").unwrap();
+        assert!(matcher.matches(&dest(Some("a.b.example.com"), None, 80)));
+        assert!(!matcher.matches(&dest(Some("example.com"), None, 80)));
+    }
+
+    #[test]
+    fn port_matcher_takes_single_ports_and_ranges() {
+        let matcher = Matcher::parse("port:22, 8000-8080").unwrap();
+        assert!(matcher.matches(&dest(None, None, 22)));
+        assert!(matcher.matches(&dest(None, None, 8080)));
+        assert!(!matcher.matches(&dest(None, None, 8081)));
+        assert!(Matcher::parse("port:90-80").is_err());
+        assert!(Matcher::parse("port:65536").is_err());
+    }
+
+    #[test]
+    fn private_cidr_alias() {
+        let matcher = Matcher::parse("cidr:private").unwrap();
+        assert!(matcher.needs_ip());
+        for ip in [
+            "10.1.2.3",
+            "127.0.0.1",
+            "192.168.0.1",
+            "fd00::1",
+            "::ffff:10.0.0.1",
+        ] {
+            let ip = ip.parse().ok();
+            assert!(matcher.matches(&dest(None, ip, 80)), "{:?}", ip);
+        }
+        assert!(!matcher.matches(&dest(None, Some(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))), 80)));
+        assert!(!matcher.matches(&dest(Some("example.com"), None, 80)));
+    }
+
+    #[test]
+    fn cidr_matcher_accepts_networks_and_addresses() {
+        let matcher = Matcher::parse("cidr:203.0.113.0/24,2001:db8::1").unwrap();
+        assert!(matcher.matches(&dest(None, "203.0.113.9".parse().ok(), 80)));
+        assert!(matcher.matches(&dest(None, "2001:db8::1".parse().ok(), 80)));
+        assert!(!matcher.matches(&dest(None, "2001:db8::2".parse().ok(), 80)));
+        assert!(Matcher::parse("cidr:not-a-network").is_err());
+    }
+
+    #[test]
+    fn invalid_matchers() {
+        assert!(Matcher::parse("example.com").is_err());
+        assert!(Matcher::parse("host:example.com").is_err());
+        assert!(Matcher::parse("domain:").is_err());
+    }
+
+    #[test]
+    fn rule_lines_skip_comments_and_keep_line_numbers() {
+        let content = "# comment\n\n* reject\ndomain:corp.example.com port:443 corp\n";
+        let lines = parse_rule_lines(content).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].line_number, 3);
+        assert!(lines[0].matchers.is_empty());
+        assert_eq!(lines[0].action, "reject");
+        assert_eq!(lines[1].line_number, 4);
+        assert_eq!(lines[1].matchers.len(), 2);
+        assert_eq!(lines[1].action, "corp");
+
+        let error = parse_rule_lines("direct\nport:x direct\n").err().unwrap();
+        assert!(error.to_string().contains("Line 2"), "{}", error);
+    }
+
+    #[test]
+    fn direct_rules_with_egress_addresses() {
+        let rules = parse_rules(
+            "domain:example.com direct:203.0.113.5,2001:db8::5\ndirect\n",
+            &HashMap::new(),
+        )
+        .unwrap();
+        let Route::Direct(egress) = &rules[0].route else {
+            panic!("expected a direct route");
+        };
+        assert_eq!(egress.ipv4, Some(Ipv4Addr::new(203, 0, 113, 5)));
+        assert_eq!(
+            egress.ipv6,
+            Some("2001:db8::5".parse::<Ipv6Addr>().unwrap())
+        );
+        assert!(matches!(rules[1].route, Route::Direct(egress) if egress == Egress::default()));
+
+        assert!(parse_rules("direct:203.0.113.5,203.0.113.6\n", &HashMap::new()).is_err());
+        assert!(parse_rules("direct:nowhere\n", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn rules_name_known_upstreams() {
+        let upstream: Upstream = "corp=socks5://127.0.0.1:1080".parse().unwrap();
+        let upstreams = HashMap::from([("corp".to_string(), Arc::new(upstream))]);
+        let rules = parse_rules("port:443 corp\n", &upstreams).unwrap();
+        assert!(matches!(&rules[0].route, Route::Upstream(upstream) if upstream.name == "corp"));
+
+        let error = parse_rules("\nport:443 other\n", &upstreams).err().unwrap();
+        assert!(error.to_string().contains("Line 2"), "{}", error);
+    }
+}
@@ -0,0 +1,1290 @@
+// Embeddable proxy server: listener setup, accept loops and graceful shutdown
+use crate::access_log::{AccessLog, CloseReason, Rotation};
+use crate::acl::DestinationAcl;
+use crate::admission::{Admission, AdmissionSettings};
+use crate::auth::Authenticator;
+use crate::config::{ListenerSettings, TlsSettings};
+use crate::connect::{Connector, DirectConnector, Egress};
+use crate::context::{AllowedIPs, ServerContext};
+use crate::dns::{DnsCache, Resolver};
+#[cfg(target_os = "linux")]
+use crate::handoff;
+use crate::handoff::Inherited;
+use crate::limits::{LimitSettings, Limits, Quota};
+use crate::metrics::Metrics;
+use crate::relay::RelaySettings;
+use crate::reload::{ReloadableConfig, SharedContext};
+use crate::rules::Router;
+use crate::sessions::{Session, SessionRegistry};
+use crate::throttle::{AuthThrottle, AuthThrottleSettings, IpLimiter, IpPermit, IpRejection};
+use crate::{
+    ProxyError, SOCKS_VERSION, admin, client_cert, http_proxy, metrics, proxy_protocol, reload,
+    socks4, socks5,
+};
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use rustls_pemfile::{certs, pkcs8_private_keys};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+// Connection statistics tracking
+pub(crate) struct Stats {
+    pub(crate) active_connections: AtomicUsize,
+    pub(crate) total_connections: AtomicUsize,
+    // Connections and sessions turned away for lack of capacity
+    pub(crate) rejected_connections: AtomicUsize,
+    pub(crate) start_time: Instant,
+}
+
+// Settings of a Socks5Server; the defaults match those of the command line
+pub struct Socks5ServerBuilder {
+    listeners: Vec<ListenerSettings>,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    max_connections_per_user: usize,
+    admission_queue_size: usize,
+    admission_queue_timeout: Duration,
+    max_pending_connections: usize,
+    connection_rate_per_ip: u32,
+    auth_throttle: AuthThrottleSettings,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    resolver: Option<Arc<dyn Resolver>>,
+    connector: Arc<dyn Connector>,
+    egress: Egress,
+    dns_cache_ttl: Duration,
+    upstreams: Vec<String>,
+    rules_file: Option<PathBuf>,
+    destination_acl: Option<PathBuf>,
+    limits: LimitSettings,
+    quota_state_file: PathBuf,
+    access_log: Option<(PathBuf, Rotation, usize)>,
+    metrics_addr: Option<SocketAddr>,
+    admin: Option<(SocketAddr, String)>,
+    relay: RelaySettings,
+    watch_files: bool,
+    reload_on_sighup: bool,
+    socket_activation: bool,
+    upgrade_on_sigusr2: bool,
+    drain_timeout: Duration,
+}
+
+impl Default for Socks5ServerBuilder {
+    fn default() -> Self {
+        Socks5ServerBuilder {
+            listeners: Vec::new(),
+            max_connections: 1000,
+            max_connections_per_ip: 0,
+            max_connections_per_user: 0,
+            admission_queue_size: 0,
+            admission_queue_timeout: Duration::from_secs(10),
+            max_pending_connections: 1000,
+            connection_rate_per_ip: 0,
+            auth_throttle: AuthThrottleSettings {
+                max_failures: 5,
+                block: Duration::from_secs(60),
+                max_block: Duration::from_secs(3600),
+            },
+            authenticator: None,
+            resolver: None,
+            connector: Arc::new(DirectConnector),
+            egress: Egress::default(),
+            dns_cache_ttl: Duration::from_secs(300),
+            upstreams: Vec::new(),
+            rules_file: None,
+            destination_acl: None,
+            limits: LimitSettings::default(),
+            quota_state_file: PathBuf::from("quota_state.txt"),
+            access_log: None,
+            metrics_addr: None,
+            admin: None,
+            relay: RelaySettings {
+                buffer_size: 16 * 1024,
+                pipe_size: 64 * 1024,
+            },
+            watch_files: false,
+            reload_on_sighup: false,
+            socket_activation: false,
+            upgrade_on_sigusr2: false,
+            drain_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Socks5ServerBuilder {
+    // Add a listener; one without a name is named after the address it is bound to
+    pub fn listener(mut self, settings: ListenerSettings) -> Self {
+        self.listeners.push(settings);
+        self
+    }
+
+    // Add a listener with default settings
+    pub fn bind(self, addr: SocketAddr) -> Self {
+        self.listener(ListenerSettings::new(addr))
+    }
+
+    // Concurrent sessions; a client beyond them waits in the admission queue or is
+    // turned away with a failure reply
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    // Sessions that wait up to timeout for a slot when all are taken, 0 turns them away
+    pub fn admission_queue(mut self, size: usize, timeout: Duration) -> Self {
+        self.admission_queue_size = size;
+        self.admission_queue_timeout = timeout;
+        self
+    }
+
+    // Connections held open beyond max_connections: those still negotiating or waiting in
+    // the admission queue. Connections past both are closed without a reply
+    pub fn max_pending_connections(mut self, max_connections: usize) -> Self {
+        self.max_pending_connections = max_connections;
+        self
+    }
+
+    // Concurrent sessions of one user, 0 for unlimited; the auth file may set others
+    pub fn max_connections_per_user(mut self, max_connections: usize) -> Self {
+        self.max_connections_per_user = max_connections;
+        self
+    }
+
+    // Concurrent connections from one client IP, 0 for unlimited
+    pub fn max_connections_per_ip(mut self, max_connections: usize) -> Self {
+        self.max_connections_per_ip = max_connections;
+        self
+    }
+
+    // New connections per second from one client IP, 0 for unlimited
+    pub fn connection_rate_per_ip(mut self, rate: u32) -> Self {
+        self.connection_rate_per_ip = rate;
+        self
+    }
+
+    // Block an IP or username after max_failures failed logins (0 disables blocking),
+    // first for block and then twice as long each time, up to max_block
+    pub fn auth_blocking(
+        mut self,
+        max_failures: u32,
+        block: Duration,
+        max_block: Duration,
+    ) -> Self {
+        self.auth_throttle = AuthThrottleSettings {
+            max_failures,
+            block,
+            max_block,
+        };
+        self
+    }
+
+    // Authentication for listeners without auth settings of their own
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    // Replaces the caching system resolver
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn connector(mut self, connector: Arc<dyn Connector>) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    // Source addresses of direct connections for users and rules without their own
+    pub fn egress(mut self, egress: Egress) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    // Longest time the system resolver's answers are cached, zero disables the cache
+    pub fn dns_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.dns_cache_ttl = ttl;
+        self
+    }
+
+    // Upstream proxy in name=scheme://[user:pass@]host:port format
+    pub fn upstream(mut self, spec: impl Into<String>) -> Self {
+        self.upstreams.push(spec.into());
+        self
+    }
+
+    pub fn rules_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.rules_file = Some(path.into());
+        self
+    }
+
+    pub fn destination_acl(mut self, path: impl Into<PathBuf>) -> Self {
+        self.destination_acl = Some(path.into());
+        self
+    }
+
+    // Default upload rate per user in bytes per second, 0 for unlimited
+    pub fn upload_limit(mut self, rate: u64) -> Self {
+        self.limits.upload = Some(rate);
+        self
+    }
+
+    // Default download rate per user in bytes per second, 0 for unlimited
+    pub fn download_limit(mut self, rate: u64) -> Self {
+        self.limits.download = Some(rate);
+        self
+    }
+
+    // Default transfer quota per user
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.limits.quota = Some(quota);
+        self
+    }
+
+    pub fn quota_state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.quota_state_file = path.into();
+        self
+    }
+
+    // One JSON record per session, keeping this many rotated files
+    pub fn access_log(mut self, path: impl Into<PathBuf>, rotation: Rotation, keep: usize) -> Self {
+        self.access_log = Some((path.into(), rotation, keep));
+        self
+    }
+
+    // Serve Prometheus metrics at /metrics
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    // Serve the admin API, requests must carry the token as bearer token
+    pub fn admin(mut self, addr: SocketAddr, token: impl Into<String>) -> Self {
+        self.admin = Some((addr, token.into()));
+        self
+    }
+
+    // Largest chunk a tunnel copies at once through userspace buffers
+    pub fn relay_buffer_size(mut self, size: usize) -> Self {
+        self.relay.buffer_size = size;
+        self
+    }
+
+    // Pipe size for splice(2) relaying of plain TCP tunnels on Linux, 0 disables splicing
+    pub fn splice_pipe_size(mut self, size: usize) -> Self {
+        self.relay.pipe_size = size;
+        self
+    }
+
+    // Reload auth, allowed IPs and TLS files when they change
+    pub fn watch_files(mut self, watch_files: bool) -> Self {
+        self.watch_files = watch_files;
+        self
+    }
+
+    // Reload them on SIGHUP
+    pub fn reload_on_sighup(mut self, reload_on_sighup: bool) -> Self {
+        self.reload_on_sighup = reload_on_sighup;
+        self
+    }
+
+    // Use the listening sockets passed in by systemd (LISTEN_FDS) or by an upgrading
+    // process instead of binding those addresses
+    pub fn socket_activation(mut self, socket_activation: bool) -> Self {
+        self.socket_activation = socket_activation;
+        self
+    }
+
+    // On SIGUSR2, start the executable again with the listening sockets and drain this
+    // process once the new one is ready (Linux only)
+    pub fn upgrade_on_sigusr2(mut self, upgrade_on_sigusr2: bool) -> Self {
+        self.upgrade_on_sigusr2 = upgrade_on_sigusr2;
+        self
+    }
+
+    // Longest time sessions may continue after an upgrade before they are closed
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    // Load every file and bind every socket, so the server only has to run
+    pub async fn build(self) -> Result<Socks5Server> {
+        if self.listeners.is_empty() {
+            return Err(ProxyError::ConfigError("No listeners configured".to_string()).into());
+        }
+        for settings in &self.listeners {
+            if let Some(reason) = settings.check() {
+                return Err(ProxyError::ConfigError(format!(
+                    "Listener {}: {}",
+                    settings.bind, reason
+                ))
+                .into());
+            }
+        }
+
+        // Sockets passed in are used instead of binding their addresses again
+        let mut inherited = match self.socket_activation {
+            true => Inherited::from_env()?,
+            false => Inherited::default(),
+        };
+
+        // Listener sockets, and TLS, user authentication and IP allow list setup per
+        // listener (reloadable)
+        let mut bound = Vec::with_capacity(self.listeners.len());
+        for mut settings in self.listeners {
+            let listener = match inherited.take(settings.bind)? {
+                Some(listener) => listener,
+                None => TcpListener::bind(settings.bind).await.context(format!(
+                    "Failed to bind listener {} to {}",
+                    settings.name, settings.bind
+                ))?,
+            };
+            let local_addr = listener
+                .local_addr()
+                .context("Failed to get listener address")?;
+            if settings.name.is_empty() {
+                settings.name = local_addr.to_string();
+            }
+            let config = ReloadableConfig::load(&settings)
+                .await
+                .context(format!("Failed to setup listener {}", settings.name))?;
+            bound.push((listener, local_addr, settings, config));
+        }
+
+        // Egress routing setup
+        let router = if self.rules_file.is_some() || !self.upstreams.is_empty() {
+            Some(Arc::new(
+                Router::load(&self.upstreams, self.rules_file.as_deref())
+                    .await
+                    .context("Failed to setup egress routing")?,
+            ))
+        } else {
+            None
+        };
+
+        // Global destination access list setup
+        let destination_acl = match &self.destination_acl {
+            Some(path) => Some(Arc::new(DestinationAcl::load(path).await?)),
+            None => None,
+        };
+
+        // Bandwidth limits and transfer quotas setup
+        let default_limits = self.limits;
+        let user_limits = bound.iter().any(|(_, _, _, config)| {
+            config.users.as_ref().is_some_and(|users| {
+                users
+                    .settings
+                    .values()
+                    .any(|settings| !settings.limits.is_empty())
+            })
+        });
+        // With authentication a reload may introduce per-user limits later
+        let use_auth = bound
+            .iter()
+            .any(|(_, _, settings, _)| settings.auth.is_some());
+        let limits = if !default_limits.is_empty() || use_auth {
+            Some(Arc::new(
+                Limits::new(default_limits, self.quota_state_file.clone())
+                    .await
+                    .context("Failed to setup transfer limits")?,
+            ))
+        } else {
+            None
+        };
+
+        // Tunnel copy settings; a pipe is created once so an unusable size fails here
+        let relay_settings = self.relay;
+        if relay_settings.buffer_size == 0 {
+            return Err(ProxyError::ConfigError(
+                "Relay buffer size must be greater than 0".to_string(),
+            )
+            .into());
+        }
+        #[cfg(target_os = "linux")]
+        if relay_settings.pipe_size > 0 {
+            crate::splice::Pipe::new(relay_settings.pipe_size).map_err(|e| {
+                ProxyError::ConfigError(format!(
+                    "Cannot create splice pipes of {} bytes: {} (see /proc/sys/fs/pipe-max-size)",
+                    relay_settings.pipe_size, e
+                ))
+            })?;
+        }
+
+        // Name resolution, cached unless the embedder brings its own resolver
+        let (resolver, dns_cache) = match self.resolver {
+            Some(resolver) => (resolver, None),
+            None => {
+                let dns_cache = Arc::new(DnsCache::new(self.dns_cache_ttl.as_secs())?);
+                (Arc::clone(&dns_cache) as Arc<dyn Resolver>, Some(dns_cache))
+            }
+        };
+
+        let metrics = Arc::new(Metrics::default());
+
+        // Access log setup
+        let access_log = match &self.access_log {
+            Some((path, rotation, keep)) => {
+                Some(Arc::new(AccessLog::open(path.clone(), *rotation, *keep)?))
+            }
+            None => None,
+        };
+
+        // Brute-force protection and per-IP connection limits
+        let auth_settings = self.auth_throttle;
+        let auth_blocking = (auth_settings.max_failures > 0).then(|| {
+            format!(
+                "after {} failures, {}s doubling up to {}s",
+                auth_settings.max_failures,
+                auth_settings.block.as_secs(),
+                auth_settings.max_block.as_secs()
+            )
+        });
+        let auth_throttle =
+            (auth_settings.max_failures > 0).then(|| Arc::new(AuthThrottle::new(auth_settings)));
+        let ip_limiter =
+            (self.max_connections_per_ip > 0 || self.connection_rate_per_ip > 0).then(|| {
+                Arc::new(IpLimiter::new(
+                    self.connection_rate_per_ip,
+                    self.max_connections_per_ip,
+                ))
+            });
+
+        // Session slots are shared by all listeners
+        let stats = Arc::new(Stats {
+            active_connections: AtomicUsize::new(0),
+            total_connections: AtomicUsize::new(0),
+            rejected_connections: AtomicUsize::new(0),
+            start_time: Instant::now(),
+        });
+        let admission = Arc::new(Admission::new(
+            AdmissionSettings {
+                max_sessions: self.max_connections,
+                queue_size: self.admission_queue_size,
+                queue_timeout: self.admission_queue_timeout,
+                max_per_user: self.max_connections_per_user,
+            },
+            Arc::clone(&stats),
+        ));
+
+        // The resolver, statistics and limits are shared by all listeners
+        let mut listeners = Vec::with_capacity(bound.len());
+        for (listener, local_addr, settings, config) in bound {
+            info!(
+                "SOCKS5/HTTP proxy listener {} running on {}",
+                settings.name, local_addr
+            );
+            info!(
+                "[{}] Authentication: {}",
+                settings.name,
+                match (&settings.auth, &self.authenticator) {
+                    (Some(auth), _) => format!("Enabled ({} backend)", auth.backend),
+                    (None, Some(_)) => "Enabled (custom authenticator)".to_string(),
+                    (None, None) => "Disabled".to_string(),
+                }
+            );
+            info!(
+                "[{}] Connection timeout: {} seconds",
+                settings.name, settings.timeout_seconds
+            );
+            info!(
+                "[{}] Idle timeout: {}",
+                settings.name,
+                match settings.idle_timeout() {
+                    Some(idle_timeout) => format!("{} seconds", idle_timeout.as_secs()),
+                    None => "Disabled".to_string(),
+                }
+            );
+            if let Some(ips) = &config.allowed_ips {
+                info!(
+                    "[{}] IP restrictions enabled with {} rules",
+                    settings.name,
+                    ips.networks.len()
+                );
+            }
+            if config.tls_acceptor.is_some() {
+                info!("[{}] TLS encryption enabled", settings.name);
+            }
+            if let Some(client_user) = settings.tls.as_ref().and_then(TlsSettings::client_user) {
+                info!(
+                    "[{}] Client certificates required (user from {})",
+                    settings.name, client_user
+                );
+            }
+            // The entries were checked with the rest of the settings
+            let trusted_proxies = settings
+                .proxy_protocol
+                .then(|| AllowedIPs::new(&settings.trusted_proxies.join(",")))
+                .transpose()
+                .map_err(|e| ProxyError::ConfigError(format!("Invalid trusted proxy: {}", e)))?
+                .map(Arc::new);
+            if let Some(trusted) = &trusted_proxies {
+                info!(
+                    "[{}] PROXY protocol enabled for {} trusted sources",
+                    settings.name,
+                    trusted.networks.len()
+                );
+            }
+
+            let context = ServerContext {
+                authenticator: config.authenticator.or_else(|| self.authenticator.clone()),
+                users: config.users,
+                allowed_ips: config.allowed_ips,
+                tls_acceptor: config.tls_acceptor,
+                trusted_proxies,
+                allow_socks4_userid: settings.allow_socks4_userid,
+                timeout_duration: settings.timeout(),
+                idle_timeout: settings.idle_timeout(),
+                relay: relay_settings,
+                resolver: Arc::clone(&resolver),
+                connector: Arc::clone(&self.connector),
+                egress: self.egress,
+                router: router.clone(),
+                destination_acl: destination_acl.clone(),
+                limits: limits.clone(),
+                metrics: Arc::clone(&metrics),
+                access_log: access_log.clone(),
+                auth_throttle: auth_throttle.clone(),
+                ip_limiter: ip_limiter.clone(),
+                admission: Arc::clone(&admission),
+            };
+            listeners.push((listener, Arc::new(SharedContext::new(settings, context))));
+        }
+
+        info!(
+            "Max connections: {} (plus {} pending)",
+            self.max_connections, self.max_pending_connections
+        );
+        if self.admission_queue_size > self.max_pending_connections {
+            warn!(
+                "Admission queue size {} exceeds the {} pending connections allowed",
+                self.admission_queue_size, self.max_pending_connections
+            );
+        }
+        if self.admission_queue_size > 0 {
+            info!(
+                "Admission queue: {} sessions for up to {} seconds",
+                self.admission_queue_size,
+                self.admission_queue_timeout.as_secs()
+            );
+        }
+        if self.max_connections_per_user > 0 {
+            info!(
+                "Max connections per user: {}",
+                self.max_connections_per_user
+            );
+        }
+        info!(
+            "Relay: {}",
+            if cfg!(target_os = "linux") && relay_settings.pipe_size > 0 {
+                format!(
+                    "splice with {} byte pipes for plain TCP, {} byte buffers otherwise",
+                    relay_settings.pipe_size, relay_settings.buffer_size
+                )
+            } else {
+                format!("{} byte buffers", relay_settings.buffer_size)
+            }
+        );
+        if self.max_connections_per_ip > 0 {
+            info!("Max connections per IP: {}", self.max_connections_per_ip);
+        }
+        if self.connection_rate_per_ip > 0 {
+            info!(
+                "New connections per IP: {} per second",
+                self.connection_rate_per_ip
+            );
+        }
+        info!(
+            "Authentication blocking: {}",
+            auth_blocking.as_deref().unwrap_or("Disabled")
+        );
+        info!(
+            "DNS cache: {}",
+            match &dns_cache {
+                None => "Custom resolver".to_string(),
+                Some(_) if self.dns_cache_ttl.is_zero() => "Disabled".to_string(),
+                Some(_) => format!(
+                    "Enabled (max TTL: {} seconds)",
+                    self.dns_cache_ttl.as_secs()
+                ),
+            }
+        );
+
+        if let Some(acl) = &destination_acl {
+            info!(
+                "Destination access list enabled with {} rules",
+                acl.rule_count()
+            );
+        }
+
+        if !default_limits.is_empty() || user_limits {
+            info!(
+                "Transfer limits enabled (quota state: {:?})",
+                self.quota_state_file
+            );
+        }
+
+        if let Some((path, rotation, keep)) = &self.access_log {
+            info!(
+                "Access log: {:?} (rotation: {}, keep: {})",
+                path, rotation, keep
+            );
+        }
+
+        if self.egress != Egress::default() {
+            info!("Egress addresses: {}", self.egress);
+        }
+
+        if let Some(router) = &router {
+            info!(
+                "Egress routing enabled with {} upstreams and {} rules",
+                router.upstream_count(),
+                router.rule_count()
+            );
+        }
+
+        // Metrics endpoint listener
+        let metrics_listener = match self.metrics_addr {
+            Some(metrics_addr) => {
+                let listener = match inherited.take(metrics_addr)? {
+                    Some(listener) => listener,
+                    None => TcpListener::bind(metrics_addr).await.context(format!(
+                        "Failed to bind metrics endpoint to {}",
+                        metrics_addr
+                    ))?,
+                };
+                info!(
+                    "Prometheus metrics available at http://{}/metrics",
+                    metrics_addr
+                );
+                Some(listener)
+            }
+            None => None,
+        };
+
+        // Admin API listener
+        let admin = match self.admin {
+            Some((admin_addr, token)) => {
+                if token.is_empty() {
+                    return Err(ProxyError::ConfigError(
+                        "Admin token must not be empty".to_string(),
+                    )
+                    .into());
+                }
+                let listener = match inherited.take(admin_addr)? {
+                    Some(listener) => listener,
+                    None => TcpListener::bind(admin_addr)
+                        .await
+                        .context(format!("Failed to bind admin API to {}", admin_addr))?,
+                };
+                info!("Admin API available at http://{}", admin_addr);
+                Some((listener, token))
+            }
+            None => None,
+        };
+        inherited.close_unused();
+
+        if self.upgrade_on_sigusr2 {
+            info!(
+                "SIGUSR2 upgrades drain sessions for up to {} seconds",
+                self.drain_timeout.as_secs()
+            );
+        }
+
+        Ok(Socks5Server {
+            listeners,
+            stats,
+            sessions: Arc::new(SessionRegistry::default()),
+            metrics,
+            dns_cache,
+            limits,
+            metrics_listener,
+            admin,
+            connection_limit: self.max_connections + self.max_pending_connections,
+            watch_files: self.watch_files,
+            reload_on_sighup: self.reload_on_sighup,
+            upgrade_on_sigusr2: self.upgrade_on_sigusr2,
+            drain_timeout: self.drain_timeout,
+        })
+    }
+}
+
+// SOCKS5/HTTP proxy with its listeners bound, ready to run
+pub struct Socks5Server {
+    listeners: Vec<(TcpListener, Arc<SharedContext>)>,
+    stats: Arc<Stats>,
+    sessions: Arc<SessionRegistry>,
+    metrics: Arc<Metrics>,
+    dns_cache: Option<Arc<DnsCache>>,
+    limits: Option<Arc<Limits>>,
+    metrics_listener: Option<TcpListener>,
+    admin: Option<(TcpListener, String)>,
+    connection_limit: usize,
+    watch_files: bool,
+    reload_on_sighup: bool,
+    upgrade_on_sigusr2: bool,
+    drain_timeout: Duration,
+}
+
+impl Socks5Server {
+    pub fn builder() -> Socks5ServerBuilder {
+        Socks5ServerBuilder::default()
+    }
+
+    // Addresses the listeners are bound to, in the order they were added
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|(listener, _)| listener.local_addr().ok())
+            .collect()
+    }
+
+    // Serve until shutdown completes, then wait for the active connections to finish
+    pub async fn run<F>(self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let Socks5Server {
+            listeners,
+            stats,
+            sessions,
+            metrics,
+            dns_cache,
+            limits,
+            metrics_listener,
+            admin,
+            connection_limit,
+            watch_files,
+            reload_on_sighup,
+            upgrade_on_sigusr2,
+            drain_timeout,
+        } = self;
+
+        // The process that started this one during an upgrade may now stop accepting
+        #[cfg(target_os = "linux")]
+        handoff::notify_ready();
+
+        // Channel creation for Graceful Shutdown: stop_tx stops accepting connections and
+        // the background tasks, shutdown_tx closes the sessions
+        let (shutdown_tx, _) = broadcast::channel::<()>(1);
+        let (stop_tx, _) = broadcast::channel::<()>(1);
+        let shutdown_tx_clone = shutdown_tx.clone();
+        let stop_tx_clone = stop_tx.clone();
+
+        // Shutdown trigger
+        tokio::spawn(async move {
+            shutdown.await;
+            info!("Shutdown signal received, initiating graceful shutdown...");
+            let _ = stop_tx_clone.send(());
+            let _ = shutdown_tx_clone.send(());
+        });
+
+        // Upgrade task, handing the listening sockets to a new process
+        #[cfg(target_os = "linux")]
+        if upgrade_on_sigusr2 {
+            let fds = listeners
+                .iter()
+                .map(|(listener, _)| listener.as_raw_fd())
+                .chain(metrics_listener.as_ref().map(AsRawFd::as_raw_fd))
+                .chain(admin.as_ref().map(|(listener, _)| listener.as_raw_fd()))
+                .collect();
+            tokio::spawn(upgrade(
+                fds,
+                drain_timeout,
+                limits.clone(),
+                stop_tx.clone(),
+                shutdown_tx.clone(),
+            ));
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (upgrade_on_sigusr2, drain_timeout);
+
+        // Periodic statistics reporting task
+        let stats_clone = Arc::clone(&stats);
+        let mut shutdown_rx = stop_tx.subscribe();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        info!(
+                            "Stats: Active={}, Total={}, Rejected={}, Uptime={}s",
+                            stats_clone.active_connections.load(Ordering::Relaxed),
+                            stats_clone.total_connections.load(Ordering::Relaxed),
+                            stats_clone.rejected_connections.load(Ordering::Relaxed),
+                            stats_clone.start_time.elapsed().as_secs()
+                        );
+                    }
+                    _ = shutdown_rx.recv() => {
+                        debug!("Stats reporter received shutdown signal");
+                        break;
+                    }
+                }
+            }
+        });
+
+        // Configuration reload task
+        if watch_files || reload_on_sighup {
+            tokio::spawn(reload::run(
+                listeners
+                    .iter()
+                    .map(|(_, shared_context)| Arc::clone(shared_context))
+                    .collect(),
+                watch_files,
+                reload_on_sighup,
+                stop_tx.subscribe(),
+            ));
+        }
+
+        // Metrics endpoint task
+        if let Some(listener) = metrics_listener {
+            tokio::spawn(metrics::serve(
+                listener,
+                Arc::clone(&metrics),
+                Arc::clone(&stats),
+                dns_cache,
+                stop_tx.subscribe(),
+            ));
+        }
+
+        // Admin API task
+        if let Some((listener, token)) = admin {
+            tokio::spawn(admin::serve(
+                listener,
+                Arc::clone(&sessions),
+                listeners
+                    .iter()
+                    .map(|(_, shared_context)| Arc::clone(shared_context))
+                    .collect(),
+                token,
+                stop_tx.subscribe(),
+            ));
+        }
+
+        // Periodic quota state persistence task
+        if let Some(limits) = limits.clone() {
+            let mut shutdown_rx = stop_tx.subscribe();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(30));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            if let Err(e) = limits.save().await {
+                                error!("{:#}", e);
+                            }
+                        }
+                        _ = shutdown_rx.recv() => break,
+                    }
+                }
+            });
+        }
+
+        // Connection processing, one accept loop per listener
+        let mut accept_loops = JoinSet::new();
+        for (listener, shared_context) in listeners {
+            accept_loops.spawn(accept_connections(
+                listener,
+                shared_context,
+                Arc::clone(&stats),
+                Arc::clone(&sessions),
+                connection_limit,
+                stop_tx.subscribe(),
+                shutdown_tx.clone(),
+            ));
+        }
+        while accept_loops.join_next().await.is_some() {}
+
+        // Persist the final quota usage
+        if let Some(limits) = &limits
+            && let Err(e) = limits.save().await
+        {
+            error!("{:#}", e);
+        }
+
+        info!("SOCKS5 proxy shutdown completed");
+        Ok(())
+    }
+}
+
+// Hand the listening sockets and the quota state file to a new process on SIGUSR2, then
+// let the sessions of this one finish until the drain deadline
+#[cfg(target_os = "linux")]
+async fn upgrade(
+    fds: Vec<RawFd>,
+    drain_timeout: Duration,
+    limits: Option<Arc<Limits>>,
+    stop_tx: broadcast::Sender<()>,
+    shutdown_tx: broadcast::Sender<()>,
+) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut user_defined2 = match signal(SignalKind::user_defined2()) {
+        Ok(user_defined2) => user_defined2,
+        Err(e) => {
+            error!("Failed to listen for SIGUSR2: {}", e);
+            return;
+        }
+    };
+    let mut stop_rx = stop_tx.subscribe();
+    loop {
+        tokio::select! {
+            _ = user_defined2.recv() => {}
+            _ = stop_rx.recv() => return,
+        }
+        info!("SIGUSR2 received, starting the new process");
+        // The new process starts from the usage recorded so far
+        if let Some(limits) = &limits
+            && let Err(e) = limits.save().await
+        {
+            error!("{:#}", e);
+        }
+        match handoff::spawn_successor(&fds).await {
+            Ok(pid) => {
+                // Only one process may write the state file
+                if let Some(limits) = &limits {
+                    limits.hand_over();
+                }
+                info!(
+                    "Process {} took over the listeners, draining sessions for up to {} seconds",
+                    pid,
+                    drain_timeout.as_secs()
+                );
+                break;
+            }
+            Err(e) => error!("Upgrade failed, this process keeps serving: {:#}", e),
+        }
+    }
+
+    handoff::drain(drain_timeout, &stop_tx, &shutdown_tx).await;
+}
+
+// TLS setup function
+pub(crate) async fn setup_tls(tls: &TlsSettings) -> Result<TlsAcceptor> {
+    let cert_file = File::open(&tls.cert).context(format!(
+        "Failed to open TLS certificate file: {:?}",
+        tls.cert
+    ))?;
+    let key_file =
+        File::open(&tls.key).context(format!("Failed to open TLS key file: {:?}", tls.key))?;
+
+    let cert_chain: Vec<CertificateDer> = certs(&mut BufReader::new(cert_file))
+        .context("Failed to parse TLS certificate")?
+        .into_iter()
+        .map(CertificateDer::from)
+        .collect();
+
+    let mut keys: Vec<PrivatePkcs8KeyDer> = pkcs8_private_keys(&mut BufReader::new(key_file))
+        .context("Failed to parse TLS key")?
+        .into_iter()
+        .map(PrivatePkcs8KeyDer::from)
+        .collect();
+
+    if keys.is_empty() {
+        return Err(anyhow::anyhow!("No private key found in key file"));
+    }
+
+    let builder = ServerConfig::builder();
+    let builder = match &tls.client_ca {
+        Some(ca) => {
+            builder.with_client_cert_verifier(client_cert::verifier(ca, tls.client_crl.as_deref())?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(cert_chain, PrivateKeyDer::from(keys.remove(0)))
+        .context("Failed to build TLS configuration")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Dispatch on the first byte: SOCKS version or the first letter of an HTTP method
+async fn handle_client<T>(
+    mut socket: T,
+    session: &Session,
+    local_addr: SocketAddr,
+    context: Arc<ServerContext>,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    debug!("Processing new client: {}", session.client);
+
+    let mut version = [0u8; 1];
+    socket
+        .read_exact(&mut version)
+        .await
+        .context("Failed to read protocol version")?;
+
+    match version[0] {
+        SOCKS_VERSION => {
+            session.record_protocol("socks5");
+            socks5::handle_socks5(socket, session, local_addr, &context).await
+        }
+        socks4::SOCKS4_VERSION => {
+            session.record_protocol("socks4");
+            socks4::handle_socks4(socket, session, local_addr, &context).await
+        }
+        first_byte if first_byte.is_ascii_alphabetic() => {
+            session.record_protocol("http");
+            http_proxy::handle_http(socket, first_byte, session, &context).await
+        }
+        _ => Err(ProxyError::InvalidVersion.into()),
+    }
+}
+
+// Client address of a new connection, taken from the PROXY protocol header of a trusted
+// load balancer, and its per-IP permit. None when the connection is turned away
+async fn admit(
+    socket: &mut TcpStream,
+    peer: SocketAddr,
+    context: &ServerContext,
+    name: &str,
+) -> Option<(SocketAddr, Option<IpPermit>)> {
+    // Other peers connect directly and are not asked for a header
+    let addr = match &context.trusted_proxies {
+        Some(trusted) if trusted.is_allowed(&peer.ip()) => {
+            match timeout(
+                context.timeout_duration,
+                proxy_protocol::read_header(socket),
+            )
+            .await
+            {
+                Ok(Ok(client)) => client.unwrap_or(peer),
+                Ok(Err(e)) => {
+                    warn!("Connection from {} rejected: {:#}", peer, e);
+                    return None;
+                }
+                Err(_) => {
+                    warn!("PROXY protocol header from {} timed out", peer);
+                    return None;
+                }
+            }
+        }
+        _ => peer,
+    };
+    if addr != peer {
+        debug!("{} connected through {}", addr, peer);
+    }
+
+    // Check IP restriction
+    if let Some(allowed) = &context.allowed_ips
+        && !allowed.is_allowed(&addr.ip())
+    {
+        warn!(
+            "Connection from {} rejected (not in allowed IPs of listener {})",
+            addr, name
+        );
+        return None;
+    }
+
+    // Per-IP limits apply before any handshake work; the permit is held until the
+    // connection ends
+    match context
+        .ip_limiter
+        .as_ref()
+        .map(|limiter| limiter.acquire(addr.ip()))
+        .transpose()
+    {
+        Ok(permit) => Some((addr, permit)),
+        Err(rejection) => {
+            let limit = match rejection {
+                IpRejection::Rate => "new connections per second",
+                IpRejection::Concurrency => "concurrent connections",
+            };
+            warn!(
+                "Connection from {} rejected (per-IP limit of {} reached)",
+                addr, limit
+            );
+            None
+        }
+    }
+}
+
+// Accept connections on a listener until shutdown, then wait for its active connections
+async fn accept_connections(
+    listener: TcpListener,
+    shared_context: Arc<SharedContext>,
+    stats: Arc<Stats>,
+    sessions: Arc<SessionRegistry>,
+    connection_limit: usize,
+    mut stop_rx: broadcast::Receiver<()>,
+    shutdown_tx: broadcast::Sender<()>,
+) {
+    let name = &shared_context.settings().name;
+    let mut active_tasks = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((mut socket, peer)) => {
+                        // A draining listener turns new connections away
+                        if shared_context.is_draining() {
+                            debug!("Listener {} is draining, closing connection from {}", name, peer);
+                            continue;
+                        }
+
+                        // Sessions over max_connections are turned away with a reply once their
+                        // request is read; only connections past max_connections plus
+                        // max_pending_connections are closed here, before any protocol is known
+                        let counted = stats.active_connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                            (active < connection_limit).then_some(active + 1)
+                        });
+                        if counted.is_err() {
+                            stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                            warn!("Pending connection limit reached ({} in total), closing connection from {}", connection_limit, peer);
+                            continue;
+                        }
+                        stats.total_connections.fetch_add(1, Ordering::SeqCst);
+
+                        // The connection keeps this configuration even if it is reloaded
+                        let context = shared_context.current();
+
+                        let local_addr = match socket.local_addr() {
+                            Ok(local_addr) => local_addr,
+                            Err(e) => {
+                                error!("Failed to get local address for {}: {}", peer, e);
+                                stats.active_connections.fetch_sub(1, Ordering::SeqCst);
+                                continue;
+                            }
+                        };
+
+                        // Connection processing
+                        let tls_acceptor_clone = context.tls_acceptor.clone();
+                        let context_clone = context;
+                        let stats_clone = Arc::clone(&stats);
+                        let sessions_clone = Arc::clone(&sessions);
+                        let shared_context_clone = Arc::clone(&shared_context);
+                        let timeout_duration = context_clone.timeout_duration;
+                        let mut shutdown_rx_task = shutdown_tx.subscribe();
+                        let client_user = shared_context.settings().tls.as_ref().and_then(TlsSettings::client_user);
+
+                        active_tasks.spawn(async move {
+                            let name = &shared_context_clone.settings().name;
+                            let Some((addr, ip_permit)) = admit(&mut socket, peer, &context_clone, name).await else {
+                                stats_clone.active_connections.fetch_sub(1, Ordering::SeqCst);
+                                return Ok(());
+                            };
+                            let session = sessions_clone.register(name, addr);
+
+                            debug!("New connection from {} on listener {} (active: {})", addr, name,
+                                   stats_clone.active_connections.load(Ordering::Relaxed));
+
+                            let result = if let Some(tls) = tls_acceptor_clone {
+                                match timeout(timeout_duration, tls.accept(socket)).await {
+                                    Ok(Ok(tls_stream)) => {
+                                        debug!("TLS handshake completed with {}", addr);
+                                        // With mutual TLS the client certificate names the user
+                                        let identified = client_user.map_or(Ok(()), |source| {
+                                            client_cert::identify(&tls_stream, source, &session)
+                                        });
+                                        if let Err(e) = identified {
+                                            error!("No user in client certificate of {}", addr);
+                                            Err(e.into())
+                                        } else {
+                                            tokio::select! {
+                                                result = handle_client(tls_stream, &session, local_addr, Arc::clone(&context_clone)) => {
+                                                    result
+                                                }
+                                                _ = shutdown_rx_task.recv() => {
+                                                    info!("Client handler for {} received shutdown signal", addr);
+                                                    session.record_close(CloseReason::Shutdown);
+                                                    Ok(()) // Shutdown signal received, gracefully exit
+                                                }
+                                                _ = session.killed() => {
+                                                    info!("Session {} from {} killed by admin request", session.id, addr);
+                                                    session.record_close(CloseReason::Killed);
+                                                    Ok(())
+                                                }
+                                            }
+                                        }
+                                    },
+                                    Ok(Err(e)) => {
+                                        error!("TLS handshake failed with {}: {}", addr, e);
+                                        Err(ProxyError::NetworkError(format!("TLS handshake error: {}", e)).into())
+                                    },
+                                    Err(_) => {
+                                        error!("TLS handshake with {} timed out", addr);
+                                        Err(ProxyError::Timeout("TLS handshake timeout".to_string()).into())
+                                    }
+                                }
+                            } else {
+                                tokio::select! {
+                                    result = handle_client(socket, &session, local_addr, Arc::clone(&context_clone)) => {
+                                        result
+                                    }
+                                    _ = shutdown_rx_task.recv() => {
+                                        info!("Client handler for {} received shutdown signal", addr);
+                                        session.record_close(CloseReason::Shutdown);
+                                        Ok(()) // Shutdown signal received, gracefully exit
+                                    }
+                                    _ = session.killed() => {
+                                        info!("Session {} from {} killed by admin request", session.id, addr);
+                                        session.record_close(CloseReason::Killed);
+                                        Ok(())
+                                    }
+                                }
+                            };
+
+                            // Decrease active connection counts upon connection closure
+                            drop(ip_permit);
+                            let prev_count = stats_clone.active_connections.fetch_sub(1, Ordering::SeqCst);
+
+                            if let Err(ref e) = result {
+                                if !session.is_established() {
+                                    context_clone.metrics.record_handshake_failure(e);
+                                }
+                                error!("Proxy error from {}: {} (active: {})",
+                                      addr, e, prev_count - 1);
+                            } else {
+                                debug!("Connection from {} closed successfully (active: {})",
+                                      addr, prev_count - 1);
+                            }
+                            if let Some(access_log) = &context_clone.access_log {
+                                access_log.write(&session, &result);
+                            }
+                            result
+                        });
+                    }
+                    Err(e) => {
+                        error!("Error accepting connection: {}", e);
+                    }
+                }
+            }
+            _ = stop_rx.recv() => {
+                info!("Listener {} stopped accepting connections", name);
+                break;
+            }
+        }
+    }
+
+    // Wait for all active connections to complete
+    info!(
+        "Waiting for {} active connections on listener {} to complete...",
+        active_tasks.len(),
+        name
+    );
+
+    // Wait for all tasks to complete using JoinSet
+    while let Some(result) = active_tasks.join_next().await {
+        match result {
+            Ok(Ok(_)) => debug!("Task completed successfully"),
+            Ok(Err(e)) => error!("Task completed with error: {}", e),
+            Err(e) => error!("Task join error: {}", e),
+        }
+    }
+}
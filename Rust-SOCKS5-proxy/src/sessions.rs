@@ -0,0 +1,184 @@
+// Client sessions and their registry, for listing and killing them through the admin API
+use crate::access_log::{CloseReason, SessionDetails};
+use crate::request::Address;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Notify;
+
+// State of a single client connection
+pub(crate) struct Session {
+    pub(crate) id: u64,
+    pub(crate) listener: String,
+    pub(crate) client: SocketAddr,
+    user: OnceLock<String>,
+    pub(crate) start: Instant,
+    pub(crate) started: SystemTime,
+    // Time from accepting the connection until data started flowing
+    handshake: OnceLock<Duration>,
+    pub(crate) bytes_up: AtomicU64,
+    pub(crate) bytes_down: AtomicU64,
+    details: Mutex<SessionDetails>,
+    killed: Notify,
+}
+
+impl Session {
+    pub(crate) fn new(id: u64, listener: &str, client: SocketAddr) -> Self {
+        Session {
+            id,
+            listener: listener.to_string(),
+            client,
+            user: OnceLock::new(),
+            start: Instant::now(),
+            started: SystemTime::now(),
+            handshake: OnceLock::new(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            details: Mutex::new(SessionDetails::default()),
+            killed: Notify::new(),
+        }
+    }
+
+    pub(crate) fn set_user(&self, user: &str) {
+        let _ = self.user.set(user.to_string());
+    }
+
+    pub(crate) fn user(&self) -> Option<&str> {
+        self.user.get().map(String::as_str)
+    }
+
+    // The handshake is over once data starts flowing
+    pub(crate) fn establish(&self) {
+        let _ = self.handshake.set(self.start.elapsed());
+    }
+
+    pub(crate) fn is_established(&self) -> bool {
+        self.handshake.get().is_some()
+    }
+
+    pub(crate) fn handshake_latency(&self) -> Option<Duration> {
+        self.handshake.get().copied()
+    }
+
+    pub(crate) fn bytes_up(&self) -> u64 {
+        self.bytes_up.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_down(&self) -> u64 {
+        self.bytes_down.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn details(&self) -> MutexGuard<'_, SessionDetails> {
+        self.details.lock().unwrap()
+    }
+
+    pub(crate) fn record_protocol(&self, protocol: &'static str) {
+        self.details().protocol = Some(protocol);
+    }
+
+    pub(crate) fn record_request(&self, command: &'static str, destination: &Address) {
+        let mut details = self.details();
+        details.command = Some(command);
+        details.destination = Some(destination.clone());
+    }
+
+    // Reply code in the client's protocol: SOCKS5 REP, SOCKS4 CD or HTTP status
+    pub(crate) fn record_reply(&self, code: impl Into<u16>) {
+        self.details().reply_code = Some(code.into());
+    }
+
+    pub(crate) fn record_close(&self, reason: CloseReason) {
+        self.details().close_reason.get_or_insert(reason);
+    }
+
+    // Ask the session's task to close the connection, even if it has not started waiting yet
+    pub(crate) fn kill(&self) {
+        self.killed.notify_one();
+    }
+
+    pub(crate) async fn killed(&self) {
+        self.killed.notified().await;
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+}
+
+impl SessionRegistry {
+    // The session stays listed until the returned guard is dropped
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        listener: &str,
+        client: SocketAddr,
+    ) -> RegisteredSession {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(Session::new(id, listener, client));
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&session));
+        RegisteredSession {
+            registry: Arc::clone(self),
+            session,
+        }
+    }
+
+    // Live sessions ordered by id, optionally only those of one user
+    pub(crate) fn list(&self, user: Option<&str>) -> Vec<Arc<Session>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| user.is_none_or(|user| session.user() == Some(user)))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => {
+                session.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Kill every session of a user, returning how many there were
+    pub(crate) fn kill_user(&self, user: &str) -> usize {
+        let sessions = self.list(Some(user));
+        for session in &sessions {
+            session.kill();
+        }
+        sessions.len()
+    }
+}
+
+pub(crate) struct RegisteredSession {
+    registry: Arc<SessionRegistry>,
+    session: Arc<Session>,
+}
+
+impl Deref for RegisteredSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for RegisteredSession {
+    fn drop(&mut self) {
+        self.registry
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.session.id);
+    }
+}
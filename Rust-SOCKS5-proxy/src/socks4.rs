@@ -0,0 +1,222 @@
+// SOCKS4 and SOCKS4a support for legacy clients
+use crate::bind::{self, BindProtocol};
+use crate::connect::{connect_outbound, resolve_address};
+use crate::context::ServerContext;
+use crate::relay::relay;
+use crate::request::Address;
+use crate::sessions::Session;
+use crate::{BIND_COMMAND, CONNECT_COMMAND, ProxyError};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub(crate) const SOCKS4_VERSION: u8 = 4;
+
+// SOCKS4 reply codes, the reply version field is always 0
+const SOCKS4_REPLY_VERSION: u8 = 0;
+const SOCKS4_REQUEST_GRANTED: u8 = 0x5A;
+const SOCKS4_REQUEST_REJECTED: u8 = 0x5B;
+
+// Longest USERID or SOCKS4a domain accepted
+const MAX_FIELD_LENGTH: usize = 255;
+
+// The version byte has already been consumed by the caller
+pub(crate) async fn handle_socks4<T>(
+    mut socket: T,
+    session: &Session,
+    local_addr: SocketAddr,
+    context: &ServerContext,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let addr = session.client;
+
+    // CD, DSTPORT, DSTIP
+    let mut request = [0u8; 7];
+    socket
+        .read_exact(&mut request)
+        .await
+        .context("Failed to read SOCKS4 request")?;
+    let command = request[0];
+    let port = u16::from_be_bytes([request[1], request[2]]);
+    let ip = Ipv4Addr::new(request[3], request[4], request[5], request[6]);
+
+    let user_id = read_null_terminated(&mut socket)
+        .await
+        .context("Failed to read SOCKS4 USERID")?;
+
+    // SOCKS4a: DSTIP 0.0.0.x (x != 0) announces a domain name after the USERID
+    let octets = ip.octets();
+    let address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let domain = read_null_terminated(&mut socket)
+            .await
+            .context("Failed to read SOCKS4a domain")?;
+        Address::Domain(domain, port)
+    } else {
+        Address::Ip(SocketAddr::new(IpAddr::V4(ip), port))
+    };
+
+    // SOCKS4 has no passwords, so on a listener with authentication only a client
+    // certificate identifies the user, unless the listener accepts the USERID instead
+    if let Some(authenticator) = &context.authenticator
+        && session.user().is_none()
+    {
+        if !context.allow_socks4_userid {
+            debug!(
+                "{} refused, SOCKS4 cannot authenticate on this listener",
+                addr
+            );
+            send_reply(&mut socket, session, false, unspecified_addr())
+                .await
+                .context("Failed to send SOCKS4 rejection")?;
+            return Err(ProxyError::AuthenticationFailed.into());
+        }
+        let known = if context.auth_blocked(&user_id, addr) {
+            false
+        } else {
+            let known = authenticator.has_user(&user_id);
+            context.record_auth(&user_id, addr, known);
+            known
+        };
+        if !known {
+            send_reply(&mut socket, session, false, unspecified_addr())
+                .await
+                .context("Failed to send SOCKS4 rejection")?;
+            return Err(ProxyError::AuthenticationFailed.into());
+        }
+        debug!("{} identified as {} (SOCKS4)", addr, user_id);
+        session.set_user(&user_id);
+    }
+
+    // The session slot is held until the command is done
+    let _permit = match context.admit(session).await {
+        Ok(permit) => permit,
+        Err(e) => {
+            send_reply(&mut socket, session, false, unspecified_addr())
+                .await
+                .context("Failed to send SOCKS4 rejection")?;
+            return Err(e.into());
+        }
+    };
+
+    match command {
+        CONNECT_COMMAND => {
+            session.record_request("connect", &address);
+            let (mut remote, target_info) = match connect_outbound(session, address, context).await
+            {
+                Ok(connected) => connected,
+                Err((_, e)) => {
+                    send_reply(&mut socket, session, false, unspecified_addr())
+                        .await
+                        .context("Failed to send SOCKS4 rejection")?;
+                    return Err(e.into());
+                }
+            };
+
+            send_reply(&mut socket, session, true, unspecified_addr())
+                .await
+                .context("Failed to send SOCKS4 success reply")?;
+
+            match relay(&mut socket, &mut remote, session, context).await {
+                Ok(transferred) => {
+                    info!(
+                        "{} closed connection to {} ({})",
+                        addr, target_info, transferred
+                    );
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("{} error with {} - {}", addr, target_info, e);
+                    Err(ProxyError::NetworkError(format!("Data transfer error: {}", e)).into())
+                }
+            }
+        }
+        BIND_COMMAND => {
+            session.record_request("bind", &address);
+            let expected_peer = match resolve_address(address, context.resolver.as_ref()).await {
+                Ok(target_addr) => target_addr,
+                Err(e) => {
+                    send_reply(&mut socket, session, false, unspecified_addr())
+                        .await
+                        .context("Failed to send SOCKS4 rejection")?;
+                    return Err(e.into());
+                }
+            };
+
+            bind::handle_bind(
+                socket,
+                BindProtocol::Socks4,
+                session,
+                local_addr,
+                expected_peer,
+                context,
+            )
+            .await
+        }
+        _ => {
+            send_reply(&mut socket, session, false, unspecified_addr())
+                .await
+                .context("Failed to send SOCKS4 rejection")?;
+            Err(ProxyError::UnsupportedCommand.into())
+        }
+    }
+}
+
+// SOCKS4 reply: VN, CD, DSTPORT, DSTIP; IPv6 addresses cannot be expressed and are zeroed
+pub(crate) async fn send_reply<T>(
+    socket: &mut T,
+    session: &Session,
+    granted: bool,
+    bind_addr: SocketAddr,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let code = if granted {
+        SOCKS4_REQUEST_GRANTED
+    } else {
+        SOCKS4_REQUEST_REJECTED
+    };
+    session.record_reply(code);
+    let (ip, port) = match bind_addr.ip().to_canonical() {
+        IpAddr::V4(ip) => (ip, bind_addr.port()),
+        IpAddr::V6(_) => (Ipv4Addr::UNSPECIFIED, 0),
+    };
+
+    let mut reply = vec![SOCKS4_REPLY_VERSION, code];
+    reply.extend_from_slice(&port.to_be_bytes());
+    reply.extend_from_slice(&ip.octets());
+
+    socket
+        .write_all(&reply)
+        .await
+        .context("Failed to send SOCKS4 reply")?;
+    Ok(())
+}
+
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+async fn read_null_terminated<T>(socket: &mut T) -> Result<String>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        let byte = socket.read_u8().await?;
+        if byte == 0 {
+            break;
+        }
+        if field.len() >= MAX_FIELD_LENGTH {
+            return Err(anyhow::anyhow!(
+                "SOCKS4 field exceeds {} bytes",
+                MAX_FIELD_LENGTH
+            ));
+        }
+        field.push(byte);
+    }
+    String::from_utf8(field).context("SOCKS4 field is not valid UTF-8")
+}
@@ -0,0 +1,168 @@
+// SOCKS5 sessions: negotiation, request and the command handlers
+use crate::bind::{self, BindProtocol};
+use crate::connect::{connect_outbound, resolve_address};
+use crate::context::ServerContext;
+use crate::handshake::{self, AuthMode};
+use crate::relay::relay;
+use crate::request::{self, Command};
+use crate::sessions::Session;
+use crate::{
+    ProxyError, REPLY_ADDRESS_TYPE_NOT_SUPPORTED, REPLY_COMMAND_NOT_SUPPORTED,
+    REPLY_GENERAL_FAILURE, REPLY_HOST_UNREACHABLE, REPLY_SUCCEEDED, udp,
+};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// The version byte has already been consumed by the caller
+pub(crate) async fn handle_socks5<T>(
+    mut socket: T,
+    session: &Session,
+    local_addr: SocketAddr,
+    context: &ServerContext,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let addr = session.client;
+
+    // --- Authentication method negotiation ---
+    // A user identified by a client certificate may skip authentication
+    let mode = match (context.authenticator.is_some(), session.user().is_some()) {
+        (false, _) => AuthMode::Disabled,
+        (true, false) => AuthMode::Required,
+        (true, true) => AuthMode::Optional,
+    };
+    // Credentials must name the certificate's user
+    let verify = |username: String, password: String| async move {
+        context.authenticate(&username, &password, addr).await
+            && session.user().is_none_or(|user| user == username)
+    };
+    if let Some(username) = handshake::negotiate(&mut socket, mode, verify).await? {
+        debug!("{} authenticated as {}", addr, username);
+        session.set_user(&username);
+    }
+
+    // --- Request processing ---
+    let request = match request::read_request(&mut socket).await {
+        Ok(request) => request,
+        Err(e) => {
+            let reply_code = match e.downcast_ref::<ProxyError>() {
+                Some(ProxyError::UnsupportedAddressType) => REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+                Some(ProxyError::UnsupportedCommand) => REPLY_COMMAND_NOT_SUPPORTED,
+                _ => return Err(e),
+            };
+            send_reply(&mut socket, session, reply_code)
+                .await
+                .context("Failed to send request error reply")?;
+            return Err(e);
+        }
+    };
+    let address = request.address;
+
+    // The session slot is held until the command is done
+    let _permit = match context.admit(session).await {
+        Ok(permit) => permit,
+        Err(e) => {
+            send_reply(&mut socket, session, REPLY_GENERAL_FAILURE)
+                .await
+                .context("Failed to send admission failure reply")?;
+            return Err(e.into());
+        }
+    };
+
+    // Command processing
+    match request.command {
+        Command::Connect => {
+            session.record_request("connect", &address);
+            let (mut remote, target_info) = match connect_outbound(session, address, context).await
+            {
+                Ok(connected) => connected,
+                Err((reply_code, e)) => {
+                    // Send appropriate response code for connection failure
+                    send_reply(&mut socket, session, reply_code)
+                        .await
+                        .context("Failed to send connection error reply")?;
+                    return Err(e.into());
+                }
+            };
+
+            // Send successful connection response
+            send_reply(&mut socket, session, REPLY_SUCCEEDED)
+                .await
+                .context("Failed to send success reply")?;
+
+            // Execute bidirectional copy
+            match relay(&mut socket, &mut remote, session, context).await {
+                Ok(transferred) => {
+                    info!(
+                        "{} closed connection to {} ({})",
+                        addr, target_info, transferred
+                    );
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("{} error with {} - {}", addr, target_info, e);
+                    Err(ProxyError::NetworkError(format!("Data transfer error: {}", e)).into())
+                }
+            }
+        }
+        Command::Bind => {
+            session.record_request("bind", &address);
+            // Resolve the address the inbound connection is expected from
+            let expected_peer = match resolve_address(address, context.resolver.as_ref()).await {
+                Ok(target_addr) => target_addr,
+                Err(e) => {
+                    send_reply(&mut socket, session, REPLY_HOST_UNREACHABLE)
+                        .await
+                        .context("Failed to send host unreachable reply")?;
+                    return Err(e.into());
+                }
+            };
+
+            bind::handle_bind(
+                socket,
+                BindProtocol::Socks5,
+                session,
+                local_addr,
+                expected_peer,
+                context,
+            )
+            .await
+        }
+        Command::UdpAssociate => {
+            session.record_request("udp_associate", &address);
+            udp::handle_udp_associate(&mut socket, session, local_addr, address, context).await
+        }
+    }
+}
+
+// SOCKS5 response sending helper function
+pub(crate) async fn send_reply<T>(socket: &mut T, session: &Session, reply_code: u8) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    // IP address (0.0.0.0) and port (0)
+    send_reply_with_addr(
+        socket,
+        session,
+        reply_code,
+        SocketAddr::from(([0, 0, 0, 0], 0)),
+    )
+    .await
+}
+
+// SOCKS5 response with an explicit BIND.ADDR and BIND.PORT
+pub(crate) async fn send_reply_with_addr<T>(
+    socket: &mut T,
+    session: &Session,
+    reply_code: u8,
+    bind_addr: SocketAddr,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    session.record_reply(reply_code);
+    request::write_reply(socket, reply_code, bind_addr).await
+}
@@ -0,0 +1,91 @@
+// Zero-copy transfer between TCP sockets through a pipe with splice(2) (Linux only)
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+// Kernel pipe that holds the bytes on their way from one socket to the other
+pub(crate) struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    size: usize,
+}
+
+impl Pipe {
+    // The kernel rounds the size up to a whole number of pages and refuses sizes above
+    // /proc/sys/fs/pipe-max-size for unprivileged processes
+    pub(crate) fn new(size: usize) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two descriptors pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both descriptors are open and owned by nobody else
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let size = libc::c_int::try_from(size).map_err(|_| io::ErrorKind::InvalidInput)?;
+        // SAFETY: F_SETPIPE_SZ only reads its integer argument
+        let size = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read,
+            write,
+            size: size as usize,
+        })
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    // Move up to len bytes the socket has received into the empty pipe; 0 means EOF
+    pub(crate) async fn fill(&self, socket: &TcpStream, len: usize) -> io::Result<usize> {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || {
+                splice(socket.as_raw_fd(), self.write.as_raw_fd(), len)
+            }) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    // Send the len bytes in the pipe out through the socket
+    pub(crate) async fn drain(&self, socket: &TcpStream, mut len: usize) -> io::Result<()> {
+        while len > 0 {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || {
+                splice(self.read.as_raw_fd(), socket.as_raw_fd(), len)
+            }) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => len -= n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: both descriptors are open for the duration of the call and no offsets are used
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            flags,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
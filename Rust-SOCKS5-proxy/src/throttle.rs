@@ -0,0 +1,216 @@
+// Brute-force protection for authentication and per-IP connection limits
+use log::warn;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Idle entries are swept once a table holds this many
+const SWEEP_THRESHOLD: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AuthThrottleSettings {
+    // Failures that trigger a block
+    pub(crate) max_failures: u32,
+    // Length of the first block, doubled with every further block
+    pub(crate) block: Duration,
+    pub(crate) max_block: Duration,
+}
+
+#[derive(Default)]
+struct Offender {
+    failures: u32,
+    blocks: u32,
+    blocked_until: Option<Instant>,
+    last_failure: Option<Instant>,
+}
+
+impl Offender {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.blocked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+// Failed logins per source IP and per username
+pub(crate) struct AuthThrottle {
+    settings: AuthThrottleSettings,
+    ips: Mutex<HashMap<IpAddr, Offender>>,
+    users: Mutex<HashMap<String, Offender>>,
+}
+
+impl AuthThrottle {
+    pub(crate) fn new(settings: AuthThrottleSettings) -> Self {
+        AuthThrottle {
+            settings,
+            ips: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Time left on the block of the IP or the username, whichever is longer
+    pub(crate) fn blocked(&self, ip: IpAddr, username: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let ip_block = self
+            .ips
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .and_then(|offender| offender.remaining(now));
+        let user_block = self
+            .users
+            .lock()
+            .unwrap()
+            .get(username)
+            .and_then(|offender| offender.remaining(now));
+        ip_block.max(user_block)
+    }
+
+    pub(crate) fn record_failure(&self, ip: IpAddr, username: &str) {
+        if let Some(block) = self.fail(&self.ips, ip) {
+            warn!(
+                "Blocking authentication from {} for {}s after repeated failures",
+                ip,
+                block.as_secs()
+            );
+        }
+        if let Some(block) = self.fail(&self.users, username.to_string()) {
+            warn!(
+                "Blocking authentication as user {} for {}s after repeated failures",
+                username,
+                block.as_secs()
+            );
+        }
+    }
+
+    // A successful login clears the failure counts, earlier blocks still count towards
+    // the next backoff until the entry is forgotten
+    pub(crate) fn record_success(&self, ip: IpAddr, username: &str) {
+        if let Some(offender) = self.ips.lock().unwrap().get_mut(&ip) {
+            offender.failures = 0;
+        }
+        if let Some(offender) = self.users.lock().unwrap().get_mut(username) {
+            offender.failures = 0;
+        }
+    }
+
+    // Count a failure, returning the block it triggers if any
+    fn fail<K: Eq + Hash>(&self, table: &Mutex<HashMap<K, Offender>>, key: K) -> Option<Duration> {
+        let now = Instant::now();
+        let mut table = table.lock().unwrap();
+        if table.len() >= SWEEP_THRESHOLD {
+            table.retain(|_, offender| !self.forgotten(offender, now));
+        }
+
+        let offender = table.entry(key).or_default();
+        if self.forgotten(offender, now) {
+            *offender = Offender::default();
+        }
+        offender.last_failure = Some(now);
+        offender.failures += 1;
+        if offender.failures < self.settings.max_failures {
+            return None;
+        }
+
+        let block = self
+            .settings
+            .block
+            .saturating_mul(2u32.saturating_pow(offender.blocks))
+            .min(self.settings.max_block);
+        offender.failures = 0;
+        offender.blocks += 1;
+        offender.blocked_until = Some(now + block);
+        Some(block)
+    }
+
+    // Entries without a failure for the longest block time start over
+    fn forgotten(&self, offender: &Offender, now: Instant) -> bool {
+        offender.remaining(now).is_none()
+            && offender
+                .last_failure
+                .is_none_or(|last| now - last >= self.settings.max_block)
+    }
+}
+
+// Why the accept loop turned a connection away
+pub(crate) enum IpRejection {
+    Rate,
+    Concurrency,
+}
+
+struct IpState {
+    tokens: f64,
+    refilled: Instant,
+    active: usize,
+}
+
+// New connections per second (token bucket with a one-second burst) and concurrent
+// connections per client IP; 0 disables either limit
+pub(crate) struct IpLimiter {
+    rate: u32,
+    max_concurrent: usize,
+    states: Mutex<HashMap<IpAddr, IpState>>,
+}
+
+impl IpLimiter {
+    pub(crate) fn new(rate: u32, max_concurrent: usize) -> Self {
+        IpLimiter {
+            rate,
+            max_concurrent,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The connection counts as active until the permit is dropped
+    pub(crate) fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<IpPermit, IpRejection> {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        if states.len() >= SWEEP_THRESHOLD {
+            // A bucket is full again one second after its last use
+            states.retain(|_, state| {
+                state.active > 0 || now - state.refilled < Duration::from_secs(1)
+            });
+        }
+
+        let burst = f64::from(self.rate);
+        let state = states.entry(ip).or_insert(IpState {
+            tokens: burst,
+            refilled: now,
+            active: 0,
+        });
+
+        if self.max_concurrent > 0 && state.active >= self.max_concurrent {
+            return Err(IpRejection::Concurrency);
+        }
+        if self.rate > 0 {
+            let elapsed = (now - state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * burst).min(burst);
+            state.refilled = now;
+            if state.tokens < 1.0 {
+                return Err(IpRejection::Rate);
+            }
+            state.tokens -= 1.0;
+        }
+
+        state.active += 1;
+        Ok(IpPermit {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+pub(crate) struct IpPermit {
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        if let Some(state) = self.limiter.states.lock().unwrap().get_mut(&self.ip) {
+            state.active -= 1;
+        }
+    }
+}
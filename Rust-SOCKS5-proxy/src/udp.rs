@@ -0,0 +1,292 @@
+// UDP ASSOCIATE relay (RFC 1928, section 7)
+use crate::access_log::CloseReason;
+use crate::connect::resolve_address;
+use crate::context::ServerContext;
+use crate::limits::{Direction, UserLimiter};
+use crate::request::{Address, encode_socket_addr};
+use crate::sessions::Session;
+use crate::socks5::{send_reply, send_reply_with_addr};
+use crate::{
+    ADDR_TYPE_DOMAIN, ADDR_TYPE_IPV4, ADDR_TYPE_IPV6, ProxyError, REPLY_GENERAL_FAILURE,
+    REPLY_SUCCEEDED,
+};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+// Largest payload a UDP datagram can carry
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+pub(crate) async fn handle_udp_associate<T>(
+    socket: &mut T,
+    session: &Session,
+    local_addr: SocketAddr,
+    requested: Address,
+    context: &ServerContext,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let addr = session.client;
+
+    // Bind the relay socket on the interface the client reached us on
+    let relay = match UdpSocket::bind(SocketAddr::new(local_addr.ip(), 0)).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            send_reply(socket, session, REPLY_GENERAL_FAILURE)
+                .await
+                .context("Failed to send general failure reply")?;
+            return Err(
+                ProxyError::NetworkError(format!("Failed to bind UDP relay: {}", e)).into(),
+            );
+        }
+    };
+    let relay_addr = relay
+        .local_addr()
+        .context("Failed to get UDP relay address")?;
+
+    send_reply_with_addr(socket, session, REPLY_SUCCEEDED, relay_addr)
+        .await
+        .context("Failed to send success reply")?;
+    info!("{} associated UDP relay on {}", addr, relay_addr);
+    session.establish();
+
+    // Datagrams are only accepted from the client's IP; the port is taken from the
+    // request when the client announced it, otherwise from its first datagram
+    let client_ip = addr.ip().to_canonical();
+    let mut client_udp_addr = match requested {
+        Address::Ip(requested_addr) if requested_addr.port() != 0 => {
+            Some(SocketAddr::new(client_ip, requested_addr.port()))
+        }
+        _ => None,
+    };
+
+    let timeout_duration = context.timeout_duration;
+    let acl = context.destination_acl(session.user());
+    let traffic = context.metrics.traffic(session.user());
+    let limiter = context.limiter(session.user());
+    let quota_exhausted = || {
+        info!("{} transfer quota exhausted, closing UDP association", addr);
+        session.record_close(CloseReason::QuotaExhausted);
+    };
+
+    let mut control_buf = [0u8; 1];
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        tokio::select! {
+            // The association lives as long as the controlling TCP connection
+            result = socket.read(&mut control_buf) => {
+                match result {
+                    Ok(0) => {
+                        debug!("{} closed UDP control connection", addr);
+                        session.record_close(CloseReason::ClientClosed);
+                        break;
+                    }
+                    Ok(_) => continue, // Data on the control connection is ignored
+                    Err(e) => {
+                        debug!("{} UDP control connection error: {}", addr, e);
+                        break;
+                    }
+                }
+            }
+            _ = async {
+                match &limiter {
+                    Some(limiter) => limiter.wait_exhausted().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                quota_exhausted();
+                break;
+            }
+            result = timeout(timeout_duration, relay.recv_from(&mut buf)) => {
+                let (len, from) = match result {
+                    Ok(Ok(received)) => received,
+                    Ok(Err(e)) => {
+                        return Err(ProxyError::NetworkError(format!("UDP relay error: {}", e)).into());
+                    }
+                    Err(_) => {
+                        info!("{} UDP association idle timeout", addr);
+                        session.record_close(CloseReason::IdleTimeout);
+                        break;
+                    }
+                };
+                let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+
+                let from_client = match client_udp_addr {
+                    Some(client) => from == client,
+                    None => from.ip() == client_ip,
+                };
+
+                if from_client {
+                    client_udp_addr.get_or_insert(from);
+
+                    let (target, payload) = match parse_udp_request(&buf[..len]) {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            debug!("{} dropped UDP datagram: {}", addr, e);
+                            continue;
+                        }
+                    };
+
+                    let target_addr = match resolve_address(target, context.resolver.as_ref()).await {
+                        Ok(target_addr) => target_addr,
+                        Err(e) => {
+                            debug!("{} dropped UDP datagram: {}", addr, e);
+                            continue;
+                        }
+                    };
+
+                    // The first address reachable from the relay socket's family
+                    let Some(dest) = target_addr
+                        .ips
+                        .iter()
+                        .find_map(|ip| relay_destination(&relay_addr, *ip, target_addr.port))
+                    else {
+                        debug!("{} dropped UDP datagram: no route from {} to {}", addr, relay_addr, target_addr);
+                        continue;
+                    };
+
+                    if let Some(acl) = acl
+                        && !acl.is_allowed(target_addr.domain.as_deref(), Some(dest.ip().to_canonical()), target_addr.port)
+                    {
+                        debug!("{} dropped UDP datagram to {}: denied by access list", addr, target_addr);
+                        continue;
+                    }
+
+                    if !charge(limiter.as_deref(), Direction::Upload, len - payload).await {
+                        quota_exhausted();
+                        break;
+                    }
+
+                    match relay.send_to(&buf[payload..len], dest).await {
+                        Ok(sent) => {
+                            session.bytes_up.fetch_add(sent as u64, Ordering::Relaxed);
+                            traffic.up.fetch_add(sent as u64, Ordering::Relaxed);
+                        }
+                        Err(e) => debug!("{} failed to relay UDP datagram to {}: {}", addr, dest, e),
+                    }
+                } else if let Some(client) = client_udp_addr {
+                    // Reply from a remote host, prepend the UDP request header
+                    let mut datagram = Vec::with_capacity(len + 22);
+                    datagram.extend_from_slice(&[0x00, 0x00, 0x00]); // RSV, FRAG
+                    encode_socket_addr(&from, &mut datagram);
+                    datagram.extend_from_slice(&buf[..len]);
+
+                    let Some(dest) = relay_destination(&relay_addr, client.ip(), client.port()) else {
+                        continue;
+                    };
+
+                    if !charge(limiter.as_deref(), Direction::Download, len).await {
+                        quota_exhausted();
+                        break;
+                    }
+
+                    match relay.send_to(&datagram, dest).await {
+                        Ok(_) => {
+                            session.bytes_down.fetch_add(len as u64, Ordering::Relaxed);
+                            traffic.down.fetch_add(len as u64, Ordering::Relaxed);
+                        }
+                        Err(e) => debug!("{} failed to relay UDP datagram from {}: {}", addr, from, e),
+                    }
+                } else {
+                    warn!("{} dropped UDP datagram from unexpected source {}", addr, from);
+                }
+            }
+        }
+    }
+
+    info!(
+        "{} closed UDP association on {} (sent: {} bytes, received: {} bytes)",
+        addr,
+        relay_addr,
+        session.bytes_up(),
+        session.bytes_down()
+    );
+    Ok(())
+}
+
+// Datagrams wait for bandwidth and count against the quota like relayed TCP data;
+// false once the quota has run out
+async fn charge(limiter: Option<&UserLimiter>, direction: Direction, amount: usize) -> bool {
+    match limiter {
+        Some(limiter) => limiter.acquire(direction, amount).await.is_ok(),
+        None => true,
+    }
+}
+
+// Parse the UDP request header (RSV, FRAG, ATYP, DST.ADDR, DST.PORT) and return the
+// destination together with the offset of the payload
+fn parse_udp_request(datagram: &[u8]) -> Result<(Address, usize), ProxyError> {
+    let truncated = || ProxyError::NetworkError("Truncated UDP request header".to_string());
+
+    if datagram.len() < 4 {
+        return Err(truncated());
+    }
+
+    // Fragmentation is not supported, such datagrams must be dropped
+    if datagram[2] != 0 {
+        return Err(ProxyError::NetworkError(format!(
+            "Fragmented UDP datagram (FRAG={})",
+            datagram[2]
+        )));
+    }
+
+    let port_at = |offset: usize| {
+        datagram
+            .get(offset..offset + 2)
+            .map(|port| u16::from_be_bytes([port[0], port[1]]))
+            .ok_or_else(truncated)
+    };
+
+    match datagram[3] {
+        ADDR_TYPE_IPV4 => {
+            let octets: [u8; 4] = datagram
+                .get(4..8)
+                .ok_or_else(truncated)?
+                .try_into()
+                .unwrap();
+            let ip = IpAddr::V4(Ipv4Addr::from(octets));
+            Ok((Address::Ip(SocketAddr::new(ip, port_at(8)?)), 10))
+        }
+        ADDR_TYPE_DOMAIN => {
+            let domain_len = *datagram.get(4).ok_or_else(truncated)? as usize;
+            let domain = datagram.get(5..5 + domain_len).ok_or_else(truncated)?;
+            let domain = String::from_utf8(domain.to_vec())
+                .map_err(|_| ProxyError::NetworkError("Domain is not valid UTF-8".to_string()))?;
+            Ok((
+                Address::Domain(domain, port_at(5 + domain_len)?),
+                7 + domain_len,
+            ))
+        }
+        ADDR_TYPE_IPV6 => {
+            let octets: [u8; 16] = datagram
+                .get(4..20)
+                .ok_or_else(truncated)?
+                .try_into()
+                .unwrap();
+            let ip = IpAddr::V6(Ipv6Addr::from(octets));
+            Ok((Address::Ip(SocketAddr::new(ip, port_at(20)?)), 22))
+        }
+        _ => Err(ProxyError::UnsupportedAddressType),
+    }
+}
+
+// Map a destination onto the address family of the relay socket
+fn relay_destination(relay_addr: &SocketAddr, ip: IpAddr, port: u16) -> Option<SocketAddr> {
+    match (relay_addr, ip) {
+        (SocketAddr::V4(_), IpAddr::V4(_)) | (SocketAddr::V6(_), IpAddr::V6(_)) => {
+            Some(SocketAddr::new(ip, port))
+        }
+        (SocketAddr::V6(_), IpAddr::V4(ipv4)) => {
+            Some(SocketAddr::new(IpAddr::V6(ipv4.to_ipv6_mapped()), port))
+        }
+        (SocketAddr::V4(_), IpAddr::V6(ipv6)) => ipv6
+            .to_ipv4_mapped()
+            .map(|ipv4| SocketAddr::new(IpAddr::V4(ipv4), port)),
+    }
+}
@@ -0,0 +1,47 @@
+// Listening sockets handed over through the environment, as by a SIGUSR2 upgrade
+#![cfg(target_os = "linux")]
+use rust_socks5_proxy::Socks5Server;
+use rust_socks5_proxy::config::ListenerSettings;
+use std::os::fd::IntoRawFd;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+// The only test of this binary, nothing else reads or changes the environment
+#[tokio::test]
+async fn server_accepts_on_handed_over_socket() {
+    let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let fd = socket.into_raw_fd();
+    // SAFETY: no other thread accesses the environment
+    unsafe { std::env::set_var("RUST_SOCKS5_PROXY_FDS", fd.to_string()) };
+
+    // Binding the address anew would fail, the socket is still open
+    let server = Socks5Server::builder()
+        .listener(ListenerSettings::new(addr))
+        .socket_activation(true)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(server.local_addrs(), [addr]);
+    let (shutdown, stop) = oneshot::channel::<()>();
+    let task = tokio::spawn(server.run(async {
+        let _ = stop.await;
+    }));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut selected = [0u8; 2];
+    timeout(WAIT, stream.read_exact(&mut selected))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(selected, [0x05, 0x00]);
+
+    shutdown.send(()).unwrap();
+    timeout(WAIT, task).await.unwrap().unwrap().unwrap();
+}
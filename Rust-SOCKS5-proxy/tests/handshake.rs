@@ -0,0 +1,119 @@
+// Protocol parsing over in-memory streams
+use rust_socks5_proxy::ProxyError;
+use rust_socks5_proxy::handshake::{self, AuthMode};
+use rust_socks5_proxy::request::{self, Address, Command, Request};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+fn accept_alice(username: String, password: String) -> std::future::Ready<bool> {
+    std::future::ready(username == "alice" && password == "secret")
+}
+
+#[tokio::test]
+async fn negotiate_without_auth() {
+    let (mut client, mut server) = duplex(64);
+    client.write_all(&[0x02, 0x00, 0x02]).await.unwrap();
+
+    let user = handshake::negotiate(&mut server, AuthMode::Disabled, accept_alice)
+        .await
+        .unwrap();
+    assert_eq!(user, None);
+
+    let mut selected = [0u8; 2];
+    client.read_exact(&mut selected).await.unwrap();
+    assert_eq!(selected, [0x05, 0x00]);
+}
+
+#[tokio::test]
+async fn negotiate_with_password() {
+    let (mut client, mut server) = duplex(64);
+    client.write_all(&[0x01, 0x02]).await.unwrap();
+    client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+
+    let user = handshake::negotiate(&mut server, AuthMode::Required, accept_alice)
+        .await
+        .unwrap();
+    assert_eq!(user.as_deref(), Some("alice"));
+
+    let mut replies = [0u8; 4];
+    client.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies, [0x05, 0x02, 0x01, 0x00]);
+}
+
+#[tokio::test]
+async fn negotiate_rejects_wrong_password() {
+    let (mut client, mut server) = duplex(64);
+    client.write_all(&[0x01, 0x02]).await.unwrap();
+    client.write_all(b"\x01\x05alice\x05wrong").await.unwrap();
+
+    let error = handshake::negotiate(&mut server, AuthMode::Required, accept_alice)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<ProxyError>(),
+        Some(ProxyError::AuthenticationFailed)
+    ));
+
+    let mut replies = [0u8; 4];
+    client.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies, [0x05, 0x02, 0x01, 0x01]);
+}
+
+#[tokio::test]
+async fn optional_auth_prefers_no_auth() {
+    let (mut client, mut server) = duplex(64);
+    client.write_all(&[0x02, 0x02, 0x00]).await.unwrap();
+
+    let user = handshake::negotiate(&mut server, AuthMode::Optional, accept_alice)
+        .await
+        .unwrap();
+    assert_eq!(user, None);
+}
+
+#[tokio::test]
+async fn read_domain_request() {
+    let (mut client, mut server) = duplex(64);
+    client
+        .write_all(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb")
+        .await
+        .unwrap();
+
+    let request = request::read_request(&mut server).await.unwrap();
+    assert_eq!(
+        request,
+        Request {
+            command: Command::Connect,
+            address: Address::Domain("example.com".to_string(), 443),
+        }
+    );
+}
+
+#[tokio::test]
+async fn read_request_rejects_unknown_command() {
+    let (mut client, mut server) = duplex(64);
+    client
+        .write_all(&[0x05, 0x09, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+
+    let error = request::read_request(&mut server).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<ProxyError>(),
+        Some(ProxyError::UnsupportedCommand)
+    ));
+}
+
+#[tokio::test]
+async fn write_ipv6_reply() {
+    let (mut client, mut server) = duplex(64);
+    let bind_addr: SocketAddr = "[::1]:1080".parse().unwrap();
+    request::write_reply(&mut server, 0x00, bind_addr)
+        .await
+        .unwrap();
+
+    let mut reply = [0u8; 22];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [0x05, 0x00, 0x00, 0x04]);
+    assert_eq!(reply[19], 1);
+    assert_eq!(reply[20..], 1080u16.to_be_bytes());
+}
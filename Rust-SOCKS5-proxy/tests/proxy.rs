@@ -0,0 +1,1113 @@
+// End-to-end tests: the proxy runs in-process next to local echo servers
+use anyhow::Result;
+use rust_socks5_proxy::config::{AuthBackend, AuthSettings, ListenerSettings, TlsSettings};
+use rust_socks5_proxy::{
+    ConnectFuture, Connector, Egress, ProxyError, ResolveFuture, Resolver, Socks5Server,
+    Socks5ServerBuilder,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+// Upper bound for anything a test waits for
+const WAIT: Duration = Duration::from_secs(5);
+
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+const CONNECT: u8 = 0x01;
+const UDP_ASSOCIATE: u8 = 0x03;
+
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const CONNECTION_NOT_ALLOWED: u8 = 0x02;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+// Proxy running in the test's runtime until stopped
+struct Proxy {
+    addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Proxy {
+    async fn start(builder: Socks5ServerBuilder) -> Proxy {
+        let server = builder.build().await.unwrap();
+        let addr = server.local_addrs()[0];
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(server.run(async {
+            let _ = stop.await;
+        }));
+        Proxy {
+            addr,
+            shutdown,
+            task,
+        }
+    }
+
+    async fn stop(self) {
+        self.shutdown.send(()).unwrap();
+        timeout(WAIT, self.task).await.unwrap().unwrap().unwrap();
+    }
+}
+
+fn listener() -> ListenerSettings {
+    ListenerSettings::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+}
+
+// Plain proxy with default settings on a free port
+async fn start_proxy() -> Proxy {
+    Proxy::start(Socks5Server::builder().listener(listener())).await
+}
+
+// Echo server on a free port of the given loopback address
+async fn echo_server(ip: IpAddr) -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                break;
+            };
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+// A port nothing listens on
+async fn closed_port() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+// Resolves "echo.test" to 127.0.0.1, "mixed.test" to 127.0.0.1 and 192.0.2.1, and
+// nothing else
+struct TestResolver;
+
+impl Resolver for TestResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            match domain {
+                "echo.test" => Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]),
+                "mixed.test" => Ok(vec![
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                ]),
+                _ => Err(ProxyError::ConnectionFailed(format!(
+                    "Domain resolution failed: {}",
+                    domain
+                ))),
+            }
+        })
+    }
+}
+
+// Connection attempts that never complete
+struct BlackHole;
+
+impl Connector for BlackHole {
+    fn connect<'a>(
+        &'a self,
+        _addresses: &'a [IpAddr],
+        _port: u16,
+        _egress: Egress,
+    ) -> ConnectFuture<'a> {
+        Box::pin(std::future::pending())
+    }
+}
+
+// ATYP, DST.ADDR and DST.PORT of an IP target
+fn ip_target(addr: SocketAddr) -> Vec<u8> {
+    let mut target = match addr.ip() {
+        IpAddr::V4(ip) => [&[0x01][..], &ip.octets()].concat(),
+        IpAddr::V6(ip) => [&[0x04][..], &ip.octets()].concat(),
+    };
+    target.extend_from_slice(&addr.port().to_be_bytes());
+    target
+}
+
+fn domain_target(domain: &str, port: u16) -> Vec<u8> {
+    let mut target = vec![0x03, domain.len() as u8];
+    target.extend_from_slice(domain.as_bytes());
+    target.extend_from_slice(&port.to_be_bytes());
+    target
+}
+
+// Offer methods and return the one the server selected
+async fn greet<S>(stream: &mut S, methods: &[u8]) -> u8
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await.unwrap();
+
+    let mut selected = [0u8; 2];
+    stream.read_exact(&mut selected).await.unwrap();
+    assert_eq!(selected[0], 0x05);
+    selected[1]
+}
+
+// Username/password sub-negotiation, returns the status (0 for success)
+async fn login<S>(stream: &mut S, username: &str, password: &str) -> u8
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![0x01, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await.unwrap();
+
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await.unwrap();
+    assert_eq!(status[0], 0x01);
+    status[1]
+}
+
+// Send a request and return the reply code, having read the whole reply
+async fn request<S>(stream: &mut S, command: u8, target: &[u8]) -> u8
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![0x05, command, 0x00];
+    request.extend_from_slice(target);
+    stream.write_all(&request).await.unwrap();
+
+    let mut header = [0u8; 4];
+    timeout(WAIT, stream.read_exact(&mut header))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(header[0], 0x05);
+    let address_len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        atyp => panic!("unexpected address type {} in reply", atyp),
+    };
+    let mut bind_addr = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bind_addr).await.unwrap();
+    header[1]
+}
+
+// Data sent through the tunnel comes back from the echo server
+async fn assert_echo<S>(stream: &mut S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(b"hello through the proxy").await.unwrap();
+    let mut echoed = [0u8; 23];
+    timeout(WAIT, stream.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&echoed, b"hello through the proxy");
+}
+
+// The server closed the connection without sending anything
+async fn assert_closed<S>(stream: &mut S)
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 1];
+    let read = timeout(WAIT, stream.read(&mut buf)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)), "connection still open");
+}
+
+// CONNECT without authentication and return the reply code
+async fn connect(proxy: &Proxy, target: &[u8]) -> (TcpStream, u8) {
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[NO_AUTH]).await, NO_AUTH);
+    let reply = request(&mut stream, CONNECT, target).await;
+    (stream, reply)
+}
+
+#[tokio::test]
+async fn no_auth_connect_relays_data() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let proxy = start_proxy().await;
+
+    let (mut stream, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn ipv6_target() {
+    let echo = echo_server(IpAddr::V6(Ipv6Addr::LOCALHOST)).await;
+    let proxy = start_proxy().await;
+
+    let (mut stream, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn domain_target_uses_resolver() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .resolver(Arc::new(TestResolver));
+    let proxy = Proxy::start(builder).await;
+
+    let (mut stream, reply) = connect(&proxy, &domain_target("echo.test", echo.port())).await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn access_list_checks_every_address_of_upstream_targets() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let upstream = Proxy::start(
+        Socks5Server::builder()
+            .listener(listener())
+            .resolver(Arc::new(TestResolver)),
+    )
+    .await;
+    let rules_file = dir.path().join("rules.txt");
+    std::fs::write(&rules_file, "* up\n").unwrap();
+    let acl_file = dir.path().join("acl.txt");
+    std::fs::write(&acl_file, "cidr:192.0.2.0/24 deny\n").unwrap();
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .resolver(Arc::new(TestResolver))
+        .upstream(format!("up=socks5://{}", upstream.addr))
+        .rules_file(rules_file)
+        .destination_acl(acl_file);
+    let proxy = Proxy::start(builder).await;
+
+    let (mut stream, reply) = connect(&proxy, &domain_target("echo.test", echo.port())).await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut stream).await;
+
+    // The upstream might connect to any of the addresses, so all must be allowed
+    let (_, reply) = connect(&proxy, &domain_target("mixed.test", echo.port())).await;
+    assert_eq!(reply, CONNECTION_NOT_ALLOWED);
+
+    // Without an address the CIDR rule cannot be checked
+    let (_, reply) = connect(&proxy, &domain_target("missing.test", echo.port())).await;
+    assert_eq!(reply, CONNECTION_NOT_ALLOWED);
+
+    proxy.stop().await;
+    upstream.stop().await;
+}
+
+// Proxy that authenticates against an auth file holding alice:secret
+async fn start_auth_proxy(dir: &tempfile::TempDir) -> Proxy {
+    let auth_file = dir.path().join("auth.txt");
+    std::fs::write(&auth_file, "alice:secret\n").unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    Proxy::start(Socks5Server::builder().listener(settings)).await
+}
+
+#[tokio::test]
+async fn user_pass_connect_relays_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let proxy = start_auth_proxy(&dir).await;
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[NO_AUTH, USER_PASS]).await, USER_PASS);
+    assert_eq!(login(&mut stream, "alice", "secret").await, 0x00);
+    assert_eq!(
+        request(&mut stream, CONNECT, &ip_target(echo)).await,
+        SUCCEEDED
+    );
+    assert_echo(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let proxy = start_auth_proxy(&dir).await;
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[USER_PASS]).await, USER_PASS);
+    assert_ne!(login(&mut stream, "alice", "wrong").await, 0x00);
+    assert_closed(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn password_with_spaces_and_options() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let auth_file = dir.path().join("auth.txt");
+    std::fs::write(&auth_file, "carol: pass word \tconnections=1\n").unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    let proxy = Proxy::start(Socks5Server::builder().listener(settings)).await;
+
+    // The password keeps its spaces, the options after the tab still apply
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[USER_PASS]).await, USER_PASS);
+    assert_eq!(login(&mut stream, "carol", " pass word ").await, 0x00);
+    assert_eq!(
+        request(&mut stream, CONNECT, &ip_target(echo)).await,
+        SUCCEEDED
+    );
+    let mut second = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut second, &[USER_PASS]).await, USER_PASS);
+    assert_eq!(login(&mut second, "carol", " pass word ").await, 0x00);
+    assert_eq!(
+        request(&mut second, CONNECT, &ip_target(echo)).await,
+        GENERAL_FAILURE
+    );
+    assert_echo(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn auth_file_usernames() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let auth_file = dir.path().join("auth.txt");
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+
+    // Indented comments are skipped and usernames lose surrounding whitespace
+    std::fs::write(&auth_file, "  # bob:secret\n dave :secret\n").unwrap();
+    let proxy = Proxy::start(Socks5Server::builder().listener(settings)).await;
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[USER_PASS]).await, USER_PASS);
+    assert_eq!(login(&mut stream, "dave", "secret").await, 0x00);
+    assert_eq!(
+        request(&mut stream, CONNECT, &ip_target(echo)).await,
+        SUCCEEDED
+    );
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[USER_PASS]).await, USER_PASS);
+    assert_ne!(login(&mut stream, "  # bob", "secret").await, 0x00);
+    proxy.stop().await;
+
+    // Usernames with whitespace inside are refused with their line
+    std::fs::write(&auth_file, "dave:secret\nda ve:secret\n").unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    let error = Socks5Server::builder()
+        .listener(settings)
+        .build()
+        .await
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", error).contains("line 2"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn unknown_users_take_as_long_as_known_ones() {
+    let dir = tempfile::tempdir().unwrap();
+    let auth_file = dir.path().join("auth.txt");
+    let hash = bcrypt::hash("secret", 8).unwrap();
+    std::fs::write(&auth_file, format!("alice:{}\n", hash)).unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    let builder =
+        Socks5Server::builder()
+            .listener(settings)
+            .auth_blocking(0, Duration::ZERO, Duration::ZERO);
+    let proxy = Proxy::start(builder).await;
+
+    let attempt = |username: &'static str| async move {
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        assert_eq!(greet(&mut stream, &[USER_PASS]).await, USER_PASS);
+        let started = Instant::now();
+        assert_ne!(login(&mut stream, username, "wrong").await, 0x00);
+        started.elapsed()
+    };
+    let known = attempt("alice").await;
+    let unknown = attempt("mallory").await;
+    assert!(
+        unknown * 2 >= known,
+        "unknown user rejected in {:?}, known user in {:?}",
+        unknown,
+        known
+    );
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn auth_required_rejects_no_auth_clients() {
+    let dir = tempfile::tempdir().unwrap();
+    let proxy = start_auth_proxy(&dir).await;
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[NO_AUTH]).await, NO_ACCEPTABLE_METHODS);
+    assert_closed(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+// SOCKS4 CONNECT to an IPv4 target with a USERID, returns the reply code
+async fn socks4_connect(stream: &mut TcpStream, target: SocketAddr, user_id: &str) -> u8 {
+    let IpAddr::V4(ip) = target.ip() else {
+        panic!("SOCKS4 targets are IPv4");
+    };
+    let mut request = vec![0x04, CONNECT];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request.extend_from_slice(&ip.octets());
+    request.extend_from_slice(user_id.as_bytes());
+    request.push(0x00);
+    stream.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 8];
+    timeout(WAIT, stream.read_exact(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply[0], 0x00);
+    reply[1]
+}
+
+#[tokio::test]
+async fn socks4_refused_on_authenticated_listeners() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let proxy = start_auth_proxy(&dir).await;
+
+    // Even a known username is refused, the USERID proves nothing
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(
+        socks4_connect(&mut stream, echo, "alice").await,
+        SOCKS4_REJECTED
+    );
+    assert_closed(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn socks4_userid_login_when_allowed() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let auth_file = dir.path().join("auth.txt");
+    std::fs::write(&auth_file, "alice:secret\n").unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    settings.allow_socks4_userid = true;
+    let proxy = Proxy::start(Socks5Server::builder().listener(settings)).await;
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(
+        socks4_connect(&mut stream, echo, "alice").await,
+        SOCKS4_GRANTED
+    );
+    assert_echo(&mut stream).await;
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(
+        socks4_connect(&mut stream, echo, "mallory").await,
+        SOCKS4_REJECTED
+    );
+    assert_closed(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn connection_refused_reply() {
+    let target = closed_port().await;
+    let proxy = start_proxy().await;
+
+    let (_stream, reply) = connect(&proxy, &ip_target(target)).await;
+    assert_eq!(reply, CONNECTION_REFUSED);
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn unresolvable_host_reply() {
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .resolver(Arc::new(TestResolver));
+    let proxy = Proxy::start(builder).await;
+
+    let (_stream, reply) = connect(&proxy, &domain_target("nowhere.test", 80)).await;
+    assert_eq!(reply, HOST_UNREACHABLE);
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn connect_timeout_reply() {
+    let mut settings = listener();
+    settings.timeout_seconds = 1;
+    let builder = Socks5Server::builder()
+        .listener(settings)
+        .connector(Arc::new(BlackHole));
+    let proxy = Proxy::start(builder).await;
+
+    let start = Instant::now();
+    let target = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80));
+    let (_stream, reply) = connect(&proxy, &ip_target(target)).await;
+    assert_eq!(reply, HOST_UNREACHABLE);
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn unsupported_command_reply() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let proxy = start_proxy().await;
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[NO_AUTH]).await, NO_AUTH);
+    let reply = request(&mut stream, 0x09, &ip_target(echo)).await;
+    assert_eq!(reply, COMMAND_NOT_SUPPORTED);
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn unsupported_address_type_reply() {
+    let proxy = start_proxy().await;
+
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut stream, &[NO_AUTH]).await, NO_AUTH);
+    let reply = request(&mut stream, CONNECT, &[0x09]).await;
+    assert_eq!(reply, ADDRESS_TYPE_NOT_SUPPORTED);
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn allowlist_rejects_other_clients() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+
+    let mut settings = listener();
+    settings.allowed_ips = vec!["10.0.0.0/8".to_string()];
+    let proxy = Proxy::start(Socks5Server::builder().listener(settings)).await;
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_closed(&mut stream).await;
+    proxy.stop().await;
+
+    let mut settings = listener();
+    settings.allowed_ips = vec!["127.0.0.1".to_string()];
+    let proxy = Proxy::start(Socks5Server::builder().listener(settings)).await;
+    let (mut stream, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut stream).await;
+    proxy.stop().await;
+}
+
+// Listener behind a load balancer on 127.0.0.1 that only admits 203.0.113.7 and 2001:db8::7
+async fn start_balanced_proxy(trusted: &str) -> Proxy {
+    let mut settings = listener();
+    settings.allowed_ips = vec!["203.0.113.7".to_string(), "2001:db8::7".to_string()];
+    settings.proxy_protocol = true;
+    settings.trusted_proxies = vec![trusted.to_string()];
+    Proxy::start(Socks5Server::builder().listener(settings)).await
+}
+
+// Connection that starts with a PROXY protocol header
+async fn connect_through_balancer(proxy: &Proxy, header: &[u8]) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+    stream.write_all(header).await.unwrap();
+    stream
+}
+
+// v2 PROXY command for a TCP over IPv6 client
+fn proxy_v2_header(client: SocketAddr, frontend: SocketAddr) -> Vec<u8> {
+    let (IpAddr::V6(source), IpAddr::V6(destination)) = (client.ip(), frontend.ip()) else {
+        panic!("IPv6 addresses expected");
+    };
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+    header.extend_from_slice(&source.octets());
+    header.extend_from_slice(&destination.octets());
+    header.extend_from_slice(&client.port().to_be_bytes());
+    header.extend_from_slice(&frontend.port().to_be_bytes());
+    header
+}
+
+#[tokio::test]
+async fn proxy_protocol_v1_client_address() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let proxy = start_balanced_proxy("127.0.0.1").await;
+
+    // The allowlist applies to the client named in the header, not the balancer
+    let header = b"PROXY TCP4 203.0.113.7 192.0.2.1 40000 1080\r\n";
+    let mut stream = connect_through_balancer(&proxy, header).await;
+    assert_eq!(greet(&mut stream, &[NO_AUTH]).await, NO_AUTH);
+    assert_eq!(
+        request(&mut stream, CONNECT, &ip_target(echo)).await,
+        SUCCEEDED
+    );
+    assert_echo(&mut stream).await;
+
+    let header = b"PROXY TCP4 198.51.100.1 192.0.2.1 40000 1080\r\n";
+    let mut stream = connect_through_balancer(&proxy, header).await;
+    assert_closed(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn proxy_protocol_v2_client_address() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let proxy = start_balanced_proxy("127.0.0.0/8").await;
+    let frontend: SocketAddr = "[2001:db8::1]:1080".parse().unwrap();
+
+    let header = proxy_v2_header("[2001:db8::7]:40000".parse().unwrap(), frontend);
+    let mut stream = connect_through_balancer(&proxy, &header).await;
+    assert_eq!(greet(&mut stream, &[NO_AUTH]).await, NO_AUTH);
+    assert_eq!(
+        request(&mut stream, CONNECT, &ip_target(echo)).await,
+        SUCCEEDED
+    );
+    assert_echo(&mut stream).await;
+
+    let header = proxy_v2_header("[2001:db8::8]:40000".parse().unwrap(), frontend);
+    let mut stream = connect_through_balancer(&proxy, &header).await;
+    assert_closed(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn proxy_protocol_header_required_from_trusted_proxies() {
+    let proxy = start_balanced_proxy("127.0.0.1").await;
+
+    // Clients must not reach the listener through the balancer without a header
+    let mut stream =
+        connect_through_balancer(&proxy, b"GET http://example.com/ HTTP/1.1\r\n\r\n").await;
+    assert_closed(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn proxy_protocol_ignored_from_other_peers() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let mut settings = listener();
+    settings.allowed_ips = vec!["127.0.0.1".to_string()];
+    settings.proxy_protocol = true;
+    settings.trusted_proxies = vec!["10.0.0.0/8".to_string()];
+    let proxy = Proxy::start(Socks5Server::builder().listener(settings)).await;
+
+    // Peers outside the trusted proxies are clients themselves
+    let (mut stream, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut stream).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn max_connections_cap() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .max_connections(1);
+    let proxy = Proxy::start(builder).await;
+
+    // The first session takes the only slot, the second is turned away with a reply
+    let (mut first, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    let (mut second, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, GENERAL_FAILURE);
+    assert_closed(&mut second).await;
+
+    // The slot frees up once the first session is gone
+    assert_echo(&mut first).await;
+    drop(first);
+    let deadline = Instant::now() + WAIT;
+    loop {
+        let (mut stream, reply) = connect(&proxy, &ip_target(echo)).await;
+        if reply == SUCCEEDED {
+            assert_echo(&mut stream).await;
+            break;
+        }
+        assert!(Instant::now() < deadline, "slot was never released");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn max_pending_connections_cap() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .max_connections(1)
+        .max_pending_connections(1);
+    let proxy = Proxy::start(builder).await;
+
+    // One session and one connection still negotiating fill the proxy
+    let (mut first, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    let mut pending = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut pending, &[NO_AUTH]).await, NO_AUTH);
+
+    // A further connection is closed before it could say which protocol it speaks
+    let mut third = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_closed(&mut third).await;
+
+    // The pending connection still gets its reply
+    assert_eq!(
+        request(&mut pending, CONNECT, &ip_target(echo)).await,
+        GENERAL_FAILURE
+    );
+    assert_echo(&mut first).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn admission_queue_waits_for_a_slot() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .max_connections(1)
+        .admission_queue(1, WAIT);
+    let proxy = Proxy::start(builder).await;
+
+    let (mut first, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+
+    // The second session waits in the queue, the third finds it full
+    let mut second = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut second, &[NO_AUTH]).await, NO_AUTH);
+    let mut request_bytes = vec![0x05, CONNECT, 0x00];
+    request_bytes.extend_from_slice(&ip_target(echo));
+    second.write_all(&request_bytes).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (_, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, GENERAL_FAILURE);
+
+    // The waiting session takes the slot the first one leaves
+    assert_echo(&mut first).await;
+    drop(first);
+    let mut header = [0u8; 10];
+    timeout(WAIT, second.read_exact(&mut header))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(header[1], SUCCEEDED);
+    assert_echo(&mut second).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn admission_queue_timeout_reply() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .max_connections(1)
+        .admission_queue(1, Duration::from_millis(200));
+    let proxy = Proxy::start(builder).await;
+
+    let (mut first, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    let started = Instant::now();
+    let (_, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, GENERAL_FAILURE);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_echo(&mut first).await;
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn per_user_session_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let auth_file = dir.path().join("auth.txt");
+    std::fs::write(&auth_file, "alice:secret\tconnections=2\nbob:hunter2\n").unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    let builder = Socks5Server::builder()
+        .listener(settings)
+        .max_connections_per_user(1);
+    let proxy = Proxy::start(builder).await;
+
+    let session = |username: &'static str, password: &'static str| async move {
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        assert_eq!(greet(&mut stream, &[USER_PASS]).await, USER_PASS);
+        assert_eq!(login(&mut stream, username, password).await, 0x00);
+        let reply = request(&mut stream, CONNECT, &ip_target(echo)).await;
+        (stream, reply)
+    };
+
+    // bob has the global limit of one session, alice a limit of two of her own
+    let (_bob, reply) = session("bob", "hunter2").await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_eq!(session("bob", "hunter2").await.1, GENERAL_FAILURE);
+    let (_alice, reply) = session("alice", "secret").await;
+    assert_eq!(reply, SUCCEEDED);
+    let (mut alice, reply) = session("alice", "secret").await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut alice).await;
+    assert_eq!(session("alice", "secret").await.1, GENERAL_FAILURE);
+
+    proxy.stop().await;
+}
+
+// Server that answers every connection with the client's address and closes it
+async fn whoami_server() -> SocketAddr {
+    let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            let _ = stream.write_all(peer.ip().to_string().as_bytes()).await;
+        }
+    });
+    SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+}
+
+// Source address the destination saw for a tunnel
+async fn source_address(stream: &mut TcpStream) -> String {
+    let mut source = String::new();
+    timeout(WAIT, stream.read_to_string(&mut source))
+        .await
+        .unwrap()
+        .unwrap();
+    source
+}
+
+#[tokio::test]
+async fn egress_address_selection() {
+    let dir = tempfile::tempdir().unwrap();
+    let whoami = whoami_server().await;
+    let other = whoami_server().await;
+
+    let auth_file = dir.path().join("auth.txt");
+    std::fs::write(&auth_file, "alice:secret\tegress=127.0.0.3\nbob:secret\n").unwrap();
+    let rules_file = dir.path().join("rules.txt");
+    std::fs::write(
+        &rules_file,
+        format!("port:{} direct:127.0.0.4\n", other.port()),
+    )
+    .unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    let builder = Socks5Server::builder()
+        .listener(settings)
+        .egress("127.0.0.2".parse().unwrap())
+        .rules_file(&rules_file);
+    let proxy = Proxy::start(builder).await;
+
+    // Rule over user over global
+    for (user, target, expected) in [
+        ("bob", whoami, "127.0.0.2"),
+        ("alice", whoami, "127.0.0.3"),
+        ("alice", other, "127.0.0.4"),
+    ] {
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        assert_eq!(greet(&mut stream, &[USER_PASS]).await, USER_PASS);
+        assert_eq!(login(&mut stream, user, "secret").await, 0x00);
+        assert_eq!(
+            request(&mut stream, CONNECT, &ip_target(target)).await,
+            SUCCEEDED
+        );
+        assert_eq!(source_address(&mut stream).await, expected);
+    }
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn missing_egress_address_reply() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let builder = Socks5Server::builder()
+        .listener(listener())
+        .egress("192.0.2.1,2001:db8::1".parse().unwrap());
+    let proxy = Proxy::start(builder).await;
+
+    let (_stream, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, GENERAL_FAILURE);
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn tls_listener() {
+    let dir = tempfile::tempdir().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = dir.path().join("cert.pem");
+    let key = dir.path().join("key.pem");
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let mut settings = listener();
+    settings.tls = Some(TlsSettings {
+        cert,
+        key,
+        client_ca: None,
+        client_crl: None,
+        client_user: rust_socks5_proxy::config::ClientUser::Cn,
+    });
+    let proxy = Proxy::start(Socks5Server::builder().listener(settings)).await;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let tcp = TcpStream::connect(proxy.addr).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut stream = connector.connect(server_name, tcp).await.unwrap();
+
+    assert_eq!(greet(&mut stream, &[NO_AUTH]).await, NO_AUTH);
+    assert_eq!(
+        request(&mut stream, CONNECT, &ip_target(echo)).await,
+        SUCCEEDED
+    );
+    assert_echo(&mut stream).await;
+
+    // Plain SOCKS5 is not spoken on a TLS listener
+    let mut plain = TcpStream::connect(proxy.addr).await.unwrap();
+    plain.write_all(&[0x05, 0x01, NO_AUTH]).await.unwrap();
+    let mut response = Vec::new();
+    let _ = timeout(WAIT, plain.read_to_end(&mut response))
+        .await
+        .unwrap();
+    assert!(!response.starts_with(&[0x05, NO_AUTH]));
+
+    proxy.stop().await;
+}
+
+#[tokio::test]
+async fn graceful_shutdown_closes_tunnels_and_listener() {
+    let echo = echo_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+    let proxy = start_proxy().await;
+    let addr = proxy.addr;
+
+    let (mut stream, reply) = connect(&proxy, &ip_target(echo)).await;
+    assert_eq!(reply, SUCCEEDED);
+    assert_echo(&mut stream).await;
+
+    // run() returns once the open tunnel has been closed
+    proxy.stop().await;
+    assert_closed(&mut stream).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+// UDP echo server on a free loopback port
+async fn udp_echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..len], from).await;
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn udp_traffic_counts_against_quota() {
+    let dir = tempfile::tempdir().unwrap();
+    let echo = udp_echo_server().await;
+    let auth_file = dir.path().join("auth.txt");
+    std::fs::write(&auth_file, "alice:secret\tquota=100/day\n").unwrap();
+    let mut settings = listener();
+    settings.auth = Some(AuthSettings {
+        backend: AuthBackend::File,
+        file: auth_file.to_string_lossy().into_owned(),
+        url: None,
+    });
+    let builder = Socks5Server::builder()
+        .listener(settings)
+        .quota_state_file(dir.path().join("quota_state.txt"));
+    let proxy = Proxy::start(builder).await;
+
+    let mut control = TcpStream::connect(proxy.addr).await.unwrap();
+    assert_eq!(greet(&mut control, &[USER_PASS]).await, USER_PASS);
+    assert_eq!(login(&mut control, "alice", "secret").await, 0x00);
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut request = vec![0x05, UDP_ASSOCIATE, 0x00];
+    request.extend_from_slice(&ip_target(client.local_addr().unwrap()));
+    control.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    timeout(WAIT, control.read_exact(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply[1], SUCCEEDED);
+    let relay = SocketAddr::from(([127, 0, 0, 1], u16::from_be_bytes([reply[8], reply[9]])));
+
+    // 60 bytes each way exceed the quota of 100; the reply that crosses it still arrives
+    let mut datagram = [&[0x00, 0x00, 0x00][..], &ip_target(echo)].concat();
+    datagram.extend_from_slice(&[0x42; 60]);
+    client.send_to(&datagram, relay).await.unwrap();
+    let mut buf = [0u8; 2048];
+    let (len, _) = timeout(WAIT, client.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[10..len], &[0x42; 60]);
+
+    // Then the association closes along with its control connection
+    assert_closed(&mut control).await;
+
+    proxy.stop().await;
+}